@@ -1,4 +1,4 @@
-use std::{fmt::Debug, str::FromStr};
+use std::{collections::HashMap, fmt::Debug, str::FromStr, time::Duration};
 
 pub mod redis;
 
@@ -21,8 +21,25 @@ pub trait Cache {
         V: FromStr + Clone,
         <V as FromStr>::Err: std::fmt::Debug,
         F: FnMut(V) -> ControlFlow<V>;
+    /// Like `subscribe`, but also calls `f` with `None` whenever no message
+    /// came in for `timeout`, so a subscriber can stop while it's quiet.
+    fn subscribe_timeout<V, F>(
+        &self,
+        topic: String,
+        timeout: Duration,
+        f: F,
+    ) -> Result<Option<V>, Self::Err>
+    where
+        V: FromStr + Clone,
+        <V as FromStr>::Err: std::fmt::Debug,
+        F: FnMut(Option<V>) -> ControlFlow<V>;
 
     fn publish<V: ToString>(&self, topic: String, v: V) -> Result<(), Self::Err>;
+
+    /// Sets `field` of the hash at `key`, other fields are left as they are.
+    fn hset<V: ToString>(&self, key: String, field: String, value: V) -> Result<(), Self::Err>;
+    fn hdel(&self, key: String, field: String) -> Result<(), Self::Err>;
+    fn hgetall(&self, key: String) -> Result<HashMap<String, String>, Self::Err>;
 }
 
 pub enum ControlFlow<T> {
@@ -72,7 +89,33 @@ impl<C: Cache> CacheStorage<C> {
         self.inner.subscribe(topic, f)
     }
 
+    pub fn subscribe_timeout<V, F>(
+        &self,
+        topic: String,
+        timeout: Duration,
+        f: F,
+    ) -> Result<Option<V>, C::Err>
+    where
+        V: FromStr + Clone,
+        <V as FromStr>::Err: std::fmt::Debug,
+        F: FnMut(Option<V>) -> ControlFlow<V>,
+    {
+        self.inner.subscribe_timeout(topic, timeout, f)
+    }
+
     pub fn publish<V: ToString>(&self, topic: String, v: V) -> Result<(), C::Err> {
         self.inner.publish(topic, v)
     }
+
+    pub fn hset<V: ToString>(&self, key: String, field: String, value: V) -> Result<(), C::Err> {
+        self.inner.hset(key, field, value)
+    }
+
+    pub fn hdel(&self, key: String, field: String) -> Result<(), C::Err> {
+        self.inner.hdel(key, field)
+    }
+
+    pub fn hgetall(&self, key: String) -> Result<HashMap<String, String>, C::Err> {
+        self.inner.hgetall(key)
+    }
 }
@@ -1,6 +1,6 @@
 use super::Cache;
 use redis::{Client, Commands, PubSubCommands};
-use std::{fmt::Debug, str::FromStr};
+use std::{collections::HashMap, fmt::Debug, str::FromStr, time::Duration};
 
 #[derive(Clone)]
 pub struct RedisCache {
@@ -8,12 +8,11 @@ pub struct RedisCache {
 }
 
 impl RedisCache {
-    pub fn new(address: String) -> Self {
-        let client = match redis::Client::open(address) {
-            Ok(c) => c,
-            Err(e) => panic!("Failed to initailize cache:redis, err={}", e.to_string()),
-        };
-        RedisCache { inner: client }
+    pub fn new(address: String) -> Result<Self, String> {
+        match redis::Client::open(address) {
+            Ok(client) => Ok(RedisCache { inner: client }),
+            Err(e) => Err(format!("Failed to initailize cache:redis, err={}", e)),
+        }
     }
 }
 
@@ -129,6 +128,38 @@ impl Cache for RedisCache {
         }
     }
 
+    fn subscribe_timeout<V, F>(
+        &self,
+        topic: String,
+        timeout: Duration,
+        mut f: F,
+    ) -> Result<Option<V>, Self::Err>
+    where
+        V: FromStr + Clone,
+        <V as FromStr>::Err: std::fmt::Debug,
+        F: FnMut(Option<V>) -> super::ControlFlow<V>,
+    {
+        let mut conn = self.inner.get_connection().map_err(|e| e.to_string())?;
+        let mut pubsub = conn.as_pubsub();
+        pubsub.subscribe(&topic).map_err(|e| e.to_string())?;
+        pubsub
+            .set_read_timeout(Some(timeout))
+            .map_err(|e| e.to_string())?;
+        loop {
+            let msg = match pubsub.get_message() {
+                Ok(msg) => {
+                    let payload = msg.get_payload::<String>().map_err(|e| e.to_string())?;
+                    Some(V::from_str(&payload).map_err(|e| format!("{:?}", e))?)
+                }
+                Err(e) if e.is_timeout() => None,
+                Err(e) => return Err(e.to_string()),
+            };
+            if let super::ControlFlow::Break(v) = f(msg) {
+                return Ok(Some(v));
+            }
+        }
+    }
+
     fn publish<V: ToString>(&self, topic: String, v: V) -> Result<(), Self::Err> {
         let mut conn = match self.inner.get_connection().map_err(|e| e.to_string()) {
             Ok(c) => c,
@@ -155,6 +186,22 @@ impl Cache for RedisCache {
             Err("Something went wrong".to_string())
         }
     }
+
+    fn hset<V: ToString>(&self, key: String, field: String, value: V) -> Result<(), Self::Err> {
+        let mut conn = self.inner.get_connection().map_err(|e| e.to_string())?;
+        conn.hset::<_, _, _, ()>(key, field, value.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    fn hdel(&self, key: String, field: String) -> Result<(), Self::Err> {
+        let mut conn = self.inner.get_connection().map_err(|e| e.to_string())?;
+        conn.hdel::<_, _, ()>(key, field).map_err(|e| e.to_string())
+    }
+
+    fn hgetall(&self, key: String) -> Result<HashMap<String, String>, Self::Err> {
+        let mut conn = self.inner.get_connection().map_err(|e| e.to_string())?;
+        conn.hgetall(key).map_err(|e| e.to_string())
+    }
 }
 
 #[cfg(test)]
@@ -166,12 +213,13 @@ mod tests {
 
     #[tokio::test]
     async fn test_new_redis_cache() {
-        let _ = RedisCache::new("redis://127.0.0.1:6379".to_string());
+        let _ = RedisCache::new("redis://127.0.0.1:6379".to_string()).unwrap();
+        assert!(RedisCache::new("not a url".to_string()).is_err());
     }
 
     #[tokio::test]
     async fn test_set_get() {
-        let cache = RedisCache::new("redis://127.0.0.1:6379".to_string());
+        let cache = RedisCache::new("redis://127.0.0.1:6379".to_string()).unwrap();
 
         // test int
         cache.set("key.name".to_string(), 1).unwrap();
@@ -213,7 +261,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_publish() {
-        let cache = RedisCache::new("redis://127.0.0.1:6379".to_string());
+        let cache = RedisCache::new("redis://127.0.0.1:6379".to_string()).unwrap();
         cache
             .publish("channel1".to_string(), "heelo")
             .unwrap_or_else(|_| {});
@@ -221,7 +269,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_subscribe() {
-        let cache = RedisCache::new("redis://127.0.0.1:6379".to_string());
+        let cache = RedisCache::new("redis://127.0.0.1:6379".to_string()).unwrap();
 
         let cache_clone = cache.clone();
         let handler = std::thread::spawn(move || {
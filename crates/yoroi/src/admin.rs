@@ -0,0 +1,671 @@
+use std::{collections::BTreeMap, time::Instant};
+
+use salvo::{
+    affix_state,
+    conn::{Acceptor, TcpListener},
+    http::StatusCode,
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, warn};
+
+use crate::{
+    breaker::BreakerState,
+    config,
+    endpoint::{AdminState, Endpoint},
+    mirror::Mirror,
+    protocol::Protocol,
+    registry::ServiceRegistry,
+    split::TrafficSplit,
+    subset::SubsetRule,
+    MicroService,
+};
+
+#[derive(Debug, Serialize)]
+struct ServiceView {
+    id: String,
+    name: String,
+    endpoints: Vec<EndpointView>,
+    split: Option<TrafficSplit>,
+    subsets: Vec<SubsetRule>,
+    mirror: Option<Mirror>,
+    mirrored: MirroredView,
+    retries: RetriesView,
+    hedged: HedgedView,
+    protocol: Protocol,
+}
+
+#[derive(Debug, Serialize)]
+struct HedgedView {
+    hedges: u64,
+    wins: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct RetriesView {
+    retries: u64,
+    refused: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct MirroredView {
+    requests: u64,
+    failures: u64,
+    dropped: u64,
+    in_flight: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct EndpointView {
+    address: String,
+    weight: u32,
+    zone: Option<String>,
+    version: Option<String>,
+    labels: BTreeMap<String, String>,
+    state: AdminState,
+    healthy: bool,
+    ejected: bool,
+    breaker: BreakerState,
+    in_flight: usize,
+    requests: u64,
+    latency_ms: Option<f64>,
+}
+
+impl EndpointView {
+    fn new(service: &MicroService, endpoint: &Endpoint, now: Instant) -> Self {
+        let stats = service.stats().get(&endpoint.address);
+        EndpointView {
+            address: endpoint.address.clone(),
+            weight: endpoint.weight,
+            zone: endpoint.zone.clone(),
+            version: endpoint.version.clone(),
+            labels: endpoint.labels.clone(),
+            state: endpoint.state,
+            healthy: stats.is_healthy(),
+            ejected: stats.is_ejected(now),
+            breaker: stats.breaker_state(),
+            in_flight: stats.in_flight(),
+            requests: stats.requests(),
+            latency_ms: stats.latency().map(|rtt| rtt.as_secs_f64() * 1000.0),
+        }
+    }
+}
+
+impl ServiceView {
+    fn new(id: &str, service: &MicroService) -> Self {
+        let now = Instant::now();
+        let mut endpoints: Vec<_> = service
+            .endpoints()
+            .values()
+            .map(|endpoint| EndpointView::new(service, endpoint, now))
+            .collect();
+        endpoints.sort_by(|a, b| a.address.cmp(&b.address));
+        ServiceView {
+            id: id.to_string(),
+            name: service.name().to_string(),
+            endpoints,
+            split: service.traffic_split().cloned(),
+            subsets: service.subset_rules().to_vec(),
+            mirror: service.mirror().cloned(),
+            mirrored: MirroredView {
+                requests: service.mirror_stats().requests(),
+                failures: service.mirror_stats().failures(),
+                dropped: service.mirror_stats().dropped(),
+                in_flight: service.mirror_stats().in_flight(),
+            },
+            retries: RetriesView {
+                retries: service.retry_budget().retries(),
+                refused: service.retry_budget().refused(),
+            },
+            hedged: HedgedView {
+                hedges: service.hedge_stats().hedges(),
+                wins: service.hedge_stats().wins(),
+            },
+            protocol: service.protocol(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct RegisterService {
+    id: String,
+    name: String,
+    #[serde(default)]
+    endpoints: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AddEndpoint {
+    endpoint: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SubsetWeight {
+    weight: u32,
+}
+
+fn registry(depot: &Depot) -> &ServiceRegistry {
+    depot
+        .obtain::<ServiceRegistry>()
+        .expect("registry is injected into every admin route")
+}
+
+fn render_error(res: &mut Response, code: StatusCode, message: String) {
+    res.status_code(code);
+    res.render(Json(serde_json::json!({ "error": message })));
+}
+
+fn param(req: &Request, key: &str) -> String {
+    req.param::<String>(key).unwrap_or_default()
+}
+
+#[handler]
+async fn list_services(depot: &mut Depot, res: &mut Response) {
+    let mut services: Vec<_> = registry(depot)
+        .get_all_services()
+        .iter()
+        .map(|(id, service)| ServiceView::new(id, service))
+        .collect();
+    services.sort_by(|a, b| a.id.cmp(&b.id));
+    res.render(Json(services));
+}
+
+#[handler]
+async fn get_service(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    match registry(depot).get_service(&id) {
+        Some(service) => res.render(Json(ServiceView::new(&id, &service))),
+        None => render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        ),
+    }
+}
+
+#[handler]
+async fn register_service(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let body: RegisterService = match req.parse_json().await {
+        Ok(body) => body,
+        Err(err) => return render_error(res, StatusCode::BAD_REQUEST, err.to_string()),
+    };
+    if body.id.is_empty() {
+        return render_error(res, StatusCode::BAD_REQUEST, "id must not be empty".into());
+    }
+    for endpoint in &body.endpoints {
+        if let Err(msg) = config::validate_endpoint(endpoint) {
+            let msg = format!("endpoint `{}` {}", endpoint, msg);
+            return render_error(res, StatusCode::BAD_REQUEST, msg);
+        }
+    }
+    let registry = registry(depot);
+    if registry.get_service(&body.id).is_some() {
+        let msg = format!("service already registered: {}", body.id);
+        return render_error(res, StatusCode::CONFLICT, msg);
+    }
+    info!("admin: registering service {}", body.id);
+    registry.register_service(body.id.clone(), body.name, body.endpoints);
+    res.status_code(StatusCode::CREATED);
+    if let Some(service) = registry.get_service(&body.id) {
+        res.render(Json(ServiceView::new(&body.id, &service)));
+    }
+}
+
+#[handler]
+async fn deregister_service(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let registry = registry(depot);
+    if registry.get_service(&id).is_none() {
+        return render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        );
+    }
+    info!("admin: deregistering service {}", id);
+    registry.deregister_service(&id);
+    res.status_code(StatusCode::NO_CONTENT);
+}
+
+#[handler]
+async fn add_endpoint(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let body: AddEndpoint = match req.parse_json().await {
+        Ok(body) => body,
+        Err(err) => return render_error(res, StatusCode::BAD_REQUEST, err.to_string()),
+    };
+    if let Err(msg) = config::validate_endpoint(&body.endpoint) {
+        let msg = format!("endpoint `{}` {}", body.endpoint, msg);
+        return render_error(res, StatusCode::BAD_REQUEST, msg);
+    }
+    let registry = registry(depot);
+    if registry.get_service(&id).is_none() {
+        return render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        );
+    }
+    info!("admin: adding endpoint {} to {}", body.endpoint, id);
+    registry.add_endpoint(&id, body.endpoint);
+    res.status_code(StatusCode::CREATED);
+    if let Some(service) = registry.get_service(&id) {
+        res.render(Json(ServiceView::new(&id, &service)));
+    }
+}
+
+#[handler]
+async fn get_endpoint(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let address = param(req, "endpoint");
+    let service = match registry(depot).get_service(&id) {
+        Some(service) => service,
+        None => {
+            let msg = format!("unknown service: {}", id);
+            return render_error(res, StatusCode::NOT_FOUND, msg);
+        }
+    };
+    match service.endpoint(&address) {
+        Some(endpoint) => res.render(Json(EndpointView::new(&service, endpoint, Instant::now()))),
+        None => render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown endpoint of {}: {}", id, address),
+        ),
+    }
+}
+
+#[handler]
+async fn set_endpoint(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let address = param(req, "endpoint");
+    let mut endpoint: Endpoint = match req.parse_json().await {
+        Ok(body) => body,
+        Err(err) => return render_error(res, StatusCode::BAD_REQUEST, err.to_string()),
+    };
+    if endpoint.address.is_empty() {
+        endpoint.address = address.clone();
+    } else if endpoint.address != address {
+        let msg = format!("address `{}` does not match the path", endpoint.address);
+        return render_error(res, StatusCode::BAD_REQUEST, msg);
+    }
+    if let Err(msg) = config::validate_endpoint(&address) {
+        let msg = format!("endpoint `{}` {}", address, msg);
+        return render_error(res, StatusCode::BAD_REQUEST, msg);
+    }
+    let registry = registry(depot);
+    if registry.get_service(&id).is_none() {
+        return render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        );
+    }
+    info!(
+        "admin: setting endpoint {} of {} ({:?})",
+        address, id, endpoint.state
+    );
+    registry.set_endpoint(&id, endpoint);
+    if let Some(service) = registry.get_service(&id) {
+        if let Some(endpoint) = service.endpoint(&address) {
+            res.render(Json(EndpointView::new(&service, endpoint, Instant::now())));
+        }
+    }
+}
+
+#[handler]
+async fn remove_endpoint(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let endpoint = param(req, "endpoint");
+    let registry = registry(depot);
+    match registry.get_service(&id) {
+        Some(service) if service.endpoints().contains_key(&endpoint) => {
+            info!("admin: removing endpoint {} from {}", endpoint, id);
+            registry.remove_endpoint(&id, &endpoint);
+            res.status_code(StatusCode::NO_CONTENT);
+        }
+        Some(_) => render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown endpoint of {}: {}", id, endpoint),
+        ),
+        None => render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        ),
+    }
+}
+
+#[handler]
+async fn set_split(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let split: TrafficSplit = match req.parse_json().await {
+        Ok(body) => body,
+        Err(err) => return render_error(res, StatusCode::BAD_REQUEST, err.to_string()),
+    };
+    let registry = registry(depot);
+    if registry.get_service(&id).is_none() {
+        return render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        );
+    }
+    info!("admin: splitting {} by {:?}", id, split.weights);
+    registry.set_traffic_split(&id, Some(split));
+    if let Some(service) = registry.get_service(&id) {
+        res.render(Json(ServiceView::new(&id, &service)));
+    }
+}
+
+#[handler]
+async fn set_subset_weight(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let version = param(req, "version");
+    let body: SubsetWeight = match req.parse_json().await {
+        Ok(body) => body,
+        Err(err) => return render_error(res, StatusCode::BAD_REQUEST, err.to_string()),
+    };
+    let registry = registry(depot);
+    if registry.get_service(&id).is_none() {
+        return render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        );
+    }
+    info!("admin: weighting {} of {} at {}", version, id, body.weight);
+    registry.set_subset_weight(&id, &version, body.weight);
+    if let Some(service) = registry.get_service(&id) {
+        res.render(Json(ServiceView::new(&id, &service)));
+    }
+}
+
+#[handler]
+async fn remove_split(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let registry = registry(depot);
+    if registry.get_service(&id).is_none() {
+        return render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        );
+    }
+    info!("admin: no longer splitting {}", id);
+    registry.set_traffic_split(&id, None);
+    res.status_code(StatusCode::NO_CONTENT);
+}
+
+#[handler]
+async fn set_subsets(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let rules: Vec<SubsetRule> = match req.parse_json().await {
+        Ok(body) => body,
+        Err(err) => return render_error(res, StatusCode::BAD_REQUEST, err.to_string()),
+    };
+    let registry = registry(depot);
+    if registry.get_service(&id).is_none() {
+        return render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        );
+    }
+    info!("admin: setting {} subset rules of {}", rules.len(), id);
+    registry.set_subset_rules(&id, rules);
+    if let Some(service) = registry.get_service(&id) {
+        res.render(Json(ServiceView::new(&id, &service)));
+    }
+}
+
+#[handler]
+async fn set_mirror(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let mirror: Mirror = match req.parse_json().await {
+        Ok(body) => body,
+        Err(err) => return render_error(res, StatusCode::BAD_REQUEST, err.to_string()),
+    };
+    if mirror.service == id || !(0.0..=100.0).contains(&mirror.percent) {
+        let msg = "mirror needs another service and a percent in [0, 100]".to_string();
+        return render_error(res, StatusCode::BAD_REQUEST, msg);
+    }
+    let registry = registry(depot);
+    if registry.get_service(&id).is_none() {
+        return render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        );
+    }
+    info!(
+        "admin: mirroring {}% of {} to {}",
+        mirror.percent, id, mirror.service
+    );
+    registry.set_mirror(&id, Some(mirror));
+    if let Some(service) = registry.get_service(&id) {
+        res.render(Json(ServiceView::new(&id, &service)));
+    }
+}
+
+#[handler]
+async fn remove_mirror(req: &mut Request, depot: &mut Depot, res: &mut Response) {
+    let id = param(req, "id");
+    let registry = registry(depot);
+    if registry.get_service(&id).is_none() {
+        return render_error(
+            res,
+            StatusCode::NOT_FOUND,
+            format!("unknown service: {}", id),
+        );
+    }
+    info!("admin: no longer mirroring {}", id);
+    registry.set_mirror(&id, None);
+    res.status_code(StatusCode::NO_CONTENT);
+}
+
+/// REST routes of the admin API over `registry`. They are not authenticated,
+/// anyone reaching them can re-route every service: serve them on a loopback
+/// or private interface only.
+///
+/// - `GET /services` lists every service with its endpoints' health and stats
+/// - `POST /services` registers `{"id", "name", "endpoints"}`
+/// - `GET|DELETE /services/<id>` shows or deregisters a service
+/// - `POST /services/<id>/endpoints` adds `{"endpoint": "host:port"}`
+/// - `GET /services/<id>/endpoints/<endpoint>` shows an endpoint
+/// - `PUT /services/<id>/endpoints/<endpoint>` adds an endpoint or replaces
+///   its `{"weight", "zone", "version", "labels", "state"}`, a `draining`
+///   or `disabled` endpoint gets no new requests
+/// - `PUT|DELETE /services/<id>/split` sets `{"weights", "sticky"}` of the
+///   service's traffic split (see `TrafficSplit`) or stops splitting
+/// - `PUT /services/<id>/split/<version>` sets `{"weight"}` of one version,
+///   like endpoint changes split changes reach every replica sharing the
+///   registry's backend
+/// - `PUT /services/<id>/subsets` replaces the header-based subset rules
+///   (see `SubsetRule`) with the given list, `[]` removes them
+/// - `PUT|DELETE /services/<id>/mirror` copies `{"service", "percent"}` of
+///   the requests to a shadow service (see `Mirror`) or stops copying
+/// - `DELETE /services/<id>/endpoints/<endpoint>` takes an endpoint out of
+///   rotation, requests already in flight to it complete
+pub fn router(registry: ServiceRegistry) -> Router {
+    Router::new().hoop(affix_state::inject(registry)).push(
+        Router::with_path("services")
+            .get(list_services)
+            .post(register_service)
+            .push(
+                Router::with_path("<id>")
+                    .get(get_service)
+                    .delete(deregister_service)
+                    .push(
+                        Router::with_path("endpoints").post(add_endpoint).push(
+                            Router::with_path("<endpoint>")
+                                .get(get_endpoint)
+                                .put(set_endpoint)
+                                .delete(remove_endpoint),
+                        ),
+                    )
+                    .push(
+                        Router::with_path("split")
+                            .put(set_split)
+                            .delete(remove_split)
+                            .push(Router::with_path("<version>").put(set_subset_weight)),
+                    )
+                    .push(Router::with_path("subsets").put(set_subsets))
+                    .push(
+                        Router::with_path("mirror")
+                            .put(set_mirror)
+                            .delete(remove_mirror),
+                    ),
+            ),
+    )
+}
+
+/// Serves the admin API on `address` until the task is aborted.
+pub(crate) async fn serve(address: String, registry: ServiceRegistry) {
+    let acceptor = match TcpListener::new(address.clone()).try_bind().await {
+        Ok(acceptor) => acceptor,
+        Err(err) => {
+            error!("admin: failed to listen on {}: {}", address, err);
+            return;
+        }
+    };
+    let local = acceptor.holdings()[0].local_addr.clone().into_std();
+    if local.is_some_and(|local| !local.ip().is_loopback()) {
+        warn!(
+            "admin: the unauthenticated admin API listens on {}, which is not a loopback address",
+            address
+        );
+    }
+    info!("Yoroi admin API started on: {}", address);
+    if let Err(err) = Server::new(acceptor).try_serve(router(registry)).await {
+        error!("admin: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests_admin {
+    use std::error::Error;
+
+    use salvo::conn::Acceptor;
+
+    use super::*;
+
+    async fn start(registry: ServiceRegistry) -> String {
+        let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+        let addr = acceptor.holdings()[0]
+            .local_addr
+            .clone()
+            .into_std()
+            .unwrap();
+        tokio::spawn(Server::new(acceptor).serve(router(registry)));
+        format!("http://{}/services", addr)
+    }
+
+    #[tokio::test]
+    async fn test_admin_api() -> Result<(), Box<dyn Error>> {
+        let registry = ServiceRegistry::new();
+        let base = start(registry.clone()).await;
+        let client = reqwest::Client::new();
+
+        let resp = client
+            .post(&base)
+            .body(r#"{"id": "kyc.Kyc", "name": "kyc", "endpoints": ["10.0.0.1:50051"]}"#)
+            .header("content-type", "application/json")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+
+        let resp = client
+            .post(format!("{}/kyc.Kyc/endpoints", base))
+            .body(r#"{"endpoint": "10.0.0.2:50051"}"#)
+            .header("content-type", "application/json")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+
+        let resp = client
+            .delete(format!("{}/kyc.Kyc/endpoints/10.0.0.1:50051", base))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 204);
+        assert_eq!(
+            registry.resolve_endpoint("kyc.Kyc".to_string()).as_deref(),
+            Some("10.0.0.2:50051")
+        );
+
+        let resp = client
+            .put(format!("{}/kyc.Kyc/endpoints/10.0.0.3:50051", base))
+            .body(r#"{"weight": 3, "version": "v2", "state": "draining"}"#)
+            .header("content-type", "application/json")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 200);
+        let endpoint = registry.get_endpoint("kyc.Kyc", "10.0.0.3:50051").unwrap();
+        assert_eq!(endpoint.weight, 3);
+        assert_eq!(endpoint.state, AdminState::Draining);
+        assert_eq!(
+            registry.resolve_endpoint("kyc.Kyc".to_string()).as_deref(),
+            Some("10.0.0.2:50051")
+        );
+
+        let services: serde_json::Value =
+            serde_json::from_str(&client.get(&base).send().await?.text().await?)?;
+        assert_eq!(services[0]["id"], "kyc.Kyc");
+        assert_eq!(services[0]["endpoints"][0]["address"], "10.0.0.2:50051");
+        assert_eq!(services[0]["endpoints"][0]["healthy"], true);
+        assert_eq!(services[0]["endpoints"][0]["breaker"], "closed");
+        assert_eq!(services[0]["endpoints"][1]["version"], "v2");
+        assert_eq!(services[0]["endpoints"][1]["state"], "draining");
+
+        let resp = client
+            .put(format!("{}/kyc.Kyc/split", base))
+            .body(r#"{"weights": {"v1": 95, "v2": 5}, "sticky": "x-user-id"}"#)
+            .header("content-type", "application/json")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 200);
+        let resp = client
+            .put(format!("{}/kyc.Kyc/split/v2", base))
+            .body(r#"{"weight": 20}"#)
+            .header("content-type", "application/json")
+            .send()
+            .await?;
+        let service: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+        assert_eq!(service["split"]["weights"]["v2"], 20);
+        assert_eq!(service["split"]["sticky"], "x-user-id");
+
+        let resp = client
+            .put(format!("{}/kyc.Kyc/subsets", base))
+            .body(r#"[{"header": "x-version", "select": {"kind": "version"}}]"#)
+            .header("content-type", "application/json")
+            .send()
+            .await?;
+        let service: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+        assert_eq!(service["subsets"][0]["select"]["kind"], "version");
+
+        let resp = client
+            .put(format!("{}/kyc.Kyc/mirror", base))
+            .body(r#"{"service": "kyc.Kyc.shadow", "percent": 10}"#)
+            .header("content-type", "application/json")
+            .send()
+            .await?;
+        let service: serde_json::Value = serde_json::from_str(&resp.text().await?)?;
+        assert_eq!(service["mirror"]["percent"], 10.0);
+        assert_eq!(service["mirrored"]["requests"], 0);
+
+        let resp = client
+            .post(format!("{}/kyc.Kyc/endpoints", base))
+            .body(r#"{"endpoint": "nope"}"#)
+            .header("content-type", "application/json")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 400);
+
+        let resp = client.delete(format!("{}/kyc.Kyc", base)).send().await?;
+        assert_eq!(resp.status(), 204);
+        let resp = client.get(format!("{}/kyc.Kyc", base)).send().await?;
+        assert_eq!(resp.status(), 404);
+        Ok(())
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    sync::Mutex,
+    time::Duration,
+};
+
+use cache::{Cache, ControlFlow};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::mpsc, time::interval};
+use tracing::warn;
+
+use crate::{endpoint::Endpoint, registry::ServiceRegistry, split::TrafficSplit};
+
+/// How often replicas re-read every registration from a shared backend, so
+/// they converge even when a change notification got lost.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Registration of a service as kept by a backend. Only endpoints, their
+/// metadata and the traffic split are stored, strategies and health settings
+/// stay local to each replica.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct ServiceRecord {
+    pub id: String,
+    pub name: String,
+    pub endpoints: BTreeMap<String, Endpoint>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub traffic_split: Option<TrafficSplit>,
+}
+
+/// Change notification published to the other replicas.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Change {
+    Put {
+        origin: String,
+        record: ServiceRecord,
+    },
+    Delete {
+        origin: String,
+        id: String,
+    },
+}
+
+impl Change {
+    fn origin(&self) -> &str {
+        match self {
+            Change::Put { origin, .. } | Change::Delete { origin, .. } => origin,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct BackendError(pub String);
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "registry backend: {}", self.0)
+    }
+}
+
+impl std::error::Error for BackendError {}
+
+/// Storage of service registrations behind a `ServiceRegistry`. Calls may
+/// block, a registry writes to a shared backend from a thread of its own and
+/// reads from it on blocking tasks.
+pub trait RegistryBackend: Send + Sync {
+    /// Stores `change` and tells the other replicas about it.
+    fn apply(&self, change: &Change) -> Result<(), BackendError>;
+
+    fn list(&self) -> Result<Vec<ServiceRecord>, BackendError>;
+
+    /// Whether other replicas share the registrations, a registry only syncs
+    /// from shared backends.
+    fn is_shared(&self) -> bool {
+        false
+    }
+
+    /// Blocks handing the changes of other replicas to `f` until it returns
+    /// `false`. `f` gets `None` at least every `SYNC_INTERVAL` while no
+    /// changes come in.
+    fn subscribe(&self, _f: &mut dyn FnMut(Option<Change>) -> bool) -> Result<(), BackendError> {
+        Ok(())
+    }
+}
+
+/// Registrations kept in the gateway's own memory.
+#[derive(Debug, Default)]
+pub struct InMemoryBackend {
+    records: Mutex<HashMap<String, ServiceRecord>>,
+}
+
+impl RegistryBackend for InMemoryBackend {
+    fn apply(&self, change: &Change) -> Result<(), BackendError> {
+        let mut records = self.records.lock().unwrap();
+        match change {
+            Change::Put { record, .. } => records.insert(record.id.clone(), record.clone()),
+            Change::Delete { id, .. } => records.remove(id),
+        };
+        Ok(())
+    }
+
+    fn list(&self) -> Result<Vec<ServiceRecord>, BackendError> {
+        Ok(self.records.lock().unwrap().values().cloned().collect())
+    }
+}
+
+/// Registrations shared by every replica through a `cache::Cache`, e.g.
+/// `cache::redis::RedisCache`. Each record is a field of one hash, so
+/// replicas changing different services don't overwrite each other.
+pub struct CacheBackend<C> {
+    cache: C,
+    key: String,
+    topic: String,
+}
+
+impl<C: Cache> CacheBackend<C> {
+    /// Stores the registrations in the `prefix:services` hash and publishes
+    /// changes on `prefix:changes`.
+    pub fn new(cache: C, prefix: &str) -> Self {
+        CacheBackend {
+            cache,
+            key: format!("{}:services", prefix),
+            topic: format!("{}:changes", prefix),
+        }
+    }
+}
+
+impl<C> RegistryBackend for CacheBackend<C>
+where
+    C: Cache + Send + Sync,
+{
+    fn apply(&self, change: &Change) -> Result<(), BackendError> {
+        match change {
+            Change::Put { record, .. } => {
+                let raw =
+                    serde_json::to_string(record).map_err(|err| BackendError(err.to_string()))?;
+                self.cache.hset(self.key.clone(), record.id.clone(), raw)
+            }
+            Change::Delete { id, .. } => self.cache.hdel(self.key.clone(), id.clone()),
+        }
+        .map_err(|err| BackendError(format!("{:?}", err)))?;
+        let change = serde_json::to_string(change).map_err(|err| BackendError(err.to_string()))?;
+        self.cache
+            .publish(self.topic.clone(), change)
+            .map_err(|err| BackendError(format!("{:?}", err)))
+    }
+
+    fn list(&self) -> Result<Vec<ServiceRecord>, BackendError> {
+        let records = self
+            .cache
+            .hgetall(self.key.clone())
+            .map_err(|err| BackendError(format!("{:?}", err)))?;
+        records
+            .values()
+            .map(|raw| serde_json::from_str(raw).map_err(|err| BackendError(err.to_string())))
+            .collect()
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+
+    fn subscribe(&self, f: &mut dyn FnMut(Option<Change>) -> bool) -> Result<(), BackendError> {
+        self.cache
+            .subscribe_timeout(self.topic.clone(), SYNC_INTERVAL, |raw: Option<String>| {
+                let change = match raw.as_deref().map(serde_json::from_str) {
+                    Some(Ok(change)) => Some(change),
+                    Some(Err(err)) => {
+                        warn!("ignoring malformed registry change: {}", err);
+                        None
+                    }
+                    None => None,
+                };
+                if f(change) {
+                    ControlFlow::Continue
+                } else {
+                    ControlFlow::Break(raw.unwrap_or_default())
+                }
+            })
+            .map(|_| ())
+            .map_err(|err| BackendError(format!("{:?}", err)))
+    }
+}
+
+/// Keeps `registry` in line with the changes other replicas make to its
+/// shared backend, until the task is aborted.
+pub(crate) async fn sync(registry: ServiceRegistry) {
+    let backend = registry.backend();
+    let origin = registry.origin().to_string();
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let subscriber = backend.clone();
+    // a thread of its own, a blocked subscription would hold up a runtime
+    // shutdown as a blocking task. It stops once the task is gone.
+    std::thread::spawn(move || loop {
+        let result = subscriber.subscribe(&mut |change| match change {
+            Some(change) if change.origin() != origin => tx.send(change).is_ok(),
+            _ => !tx.is_closed(),
+        });
+        if tx.is_closed() {
+            return;
+        }
+        if let Err(err) = result {
+            warn!("{}, subscribing again", err);
+        }
+        std::thread::sleep(SYNC_INTERVAL);
+    });
+
+    let mut ticker = interval(SYNC_INTERVAL);
+    loop {
+        tokio::select! {
+            Some(change) = rx.recv() => registry.apply_remote(change),
+            _ = ticker.tick() => {
+                let backend = backend.clone();
+                match tokio::task::spawn_blocking(move || backend.list()).await {
+                    Ok(Ok(records)) => registry.reconcile(records),
+                    Ok(Err(err)) => warn!("{}", err),
+                    Err(err) => warn!("registry sync failed: {}", err),
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_backend {
+    use std::{
+        fmt::Debug,
+        str::FromStr,
+        sync::{mpsc as std_mpsc, Arc},
+    };
+
+    use super::*;
+
+    /// `Cache` over a map shared by every clone, standing in for Redis.
+    #[derive(Clone, Default)]
+    struct MemoryCache {
+        values: Arc<Mutex<HashMap<String, String>>>,
+        hashes: Arc<Mutex<HashMap<String, HashMap<String, String>>>>,
+        subscribers: Arc<Mutex<Vec<std_mpsc::Sender<String>>>>,
+    }
+
+    impl Cache for MemoryCache {
+        type Err = String;
+
+        fn set<V: ToString>(&self, key: String, value: V) -> Result<(), Self::Err> {
+            self.values.lock().unwrap().insert(key, value.to_string());
+            Ok(())
+        }
+
+        fn get<V>(&self, key: String) -> Result<Option<V>, Self::Err>
+        where
+            V: FromStr + Debug,
+            <V as FromStr>::Err: std::fmt::Display,
+        {
+            let values = self.values.lock().unwrap();
+            values
+                .get(&key)
+                .map(|v| V::from_str(v).map_err(|err| err.to_string()))
+                .transpose()
+        }
+
+        fn forget<V>(&self, key: String) -> Result<Option<V>, Self::Err>
+        where
+            V: FromStr,
+            <V as FromStr>::Err: std::fmt::Display,
+        {
+            let mut values = self.values.lock().unwrap();
+            values
+                .remove(&key)
+                .map(|v| V::from_str(&v).map_err(|err| err.to_string()))
+                .transpose()
+        }
+
+        fn subscribe<V, F>(&self, _topic: String, mut f: F) -> Result<Option<V>, Self::Err>
+        where
+            V: FromStr + Clone,
+            <V as FromStr>::Err: std::fmt::Debug,
+            F: FnMut(V) -> ControlFlow<V>,
+        {
+            let (tx, rx) = std_mpsc::channel();
+            self.subscribers.lock().unwrap().push(tx);
+            for msg in rx {
+                if let ControlFlow::Break(v) = f(V::from_str(&msg).unwrap()) {
+                    return Ok(Some(v));
+                }
+            }
+            Ok(None)
+        }
+
+        fn subscribe_timeout<V, F>(
+            &self,
+            _topic: String,
+            timeout: Duration,
+            mut f: F,
+        ) -> Result<Option<V>, Self::Err>
+        where
+            V: FromStr + Clone,
+            <V as FromStr>::Err: std::fmt::Debug,
+            F: FnMut(Option<V>) -> ControlFlow<V>,
+        {
+            let (tx, rx) = std_mpsc::channel();
+            self.subscribers.lock().unwrap().push(tx);
+            loop {
+                let msg = match rx.recv_timeout(timeout) {
+                    Ok(msg) => Some(V::from_str(&msg).unwrap()),
+                    Err(std_mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(std_mpsc::RecvTimeoutError::Disconnected) => return Ok(None),
+                };
+                if let ControlFlow::Break(v) = f(msg) {
+                    return Ok(Some(v));
+                }
+            }
+        }
+
+        fn publish<V: ToString>(&self, _topic: String, v: V) -> Result<(), Self::Err> {
+            let mut subscribers = self.subscribers.lock().unwrap();
+            subscribers.retain(|tx| tx.send(v.to_string()).is_ok());
+            Ok(())
+        }
+
+        fn hset<V: ToString>(&self, key: String, field: String, value: V) -> Result<(), Self::Err> {
+            let mut hashes = self.hashes.lock().unwrap();
+            hashes
+                .entry(key)
+                .or_default()
+                .insert(field, value.to_string());
+            Ok(())
+        }
+
+        fn hdel(&self, key: String, field: String) -> Result<(), Self::Err> {
+            if let Some(hash) = self.hashes.lock().unwrap().get_mut(&key) {
+                hash.remove(&field);
+            }
+            Ok(())
+        }
+
+        fn hgetall(&self, key: String) -> Result<HashMap<String, String>, Self::Err> {
+            Ok(self
+                .hashes
+                .lock()
+                .unwrap()
+                .get(&key)
+                .cloned()
+                .unwrap_or_default())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replicas_converge() {
+        let cache = MemoryCache::default();
+        let a = ServiceRegistry::with_backend(Arc::new(CacheBackend::new(cache.clone(), "yoroi")));
+        let b = ServiceRegistry::with_backend(Arc::new(CacheBackend::new(cache.clone(), "yoroi")));
+        let sync_b = tokio::spawn(sync(b.clone()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        a.register_service(
+            "kyc.Kyc".to_string(),
+            "kyc".to_string(),
+            vec!["10.0.0.1:50051".to_string()],
+        );
+        a.add_endpoint("kyc.Kyc", "10.0.0.2:50051".to_string());
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let service = b.get_service("kyc.Kyc").unwrap();
+        assert_eq!(service.name(), "kyc");
+        assert_eq!(service.endpoints().len(), 2);
+
+        a.remove_endpoint("kyc.Kyc", "10.0.0.1:50051");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(
+            b.resolve_endpoint("kyc.Kyc".to_string()).as_deref(),
+            Some("10.0.0.2:50051")
+        );
+
+        a.set_subset_weight("kyc.Kyc", "v2", 5);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        let service = b.get_service("kyc.Kyc").unwrap();
+        assert_eq!(service.traffic_split().unwrap().weights["v2"], 5);
+
+        a.deregister_service("kyc.Kyc");
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(b.get_service("kyc.Kyc").is_none());
+        sync_b.abort();
+
+        // the subscriber thread notices the task is gone while it's quiet,
+        // publishing only reaches subscriptions still open
+        tokio::time::sleep(SYNC_INTERVAL * 2).await;
+        cache.publish(String::new(), "").unwrap();
+        assert!(cache.subscribers.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_concurrent_writers() {
+        let cache = MemoryCache::default();
+        let writers: Vec<_> = ["a", "b"]
+            .into_iter()
+            .map(|replica| {
+                let backend = CacheBackend::new(cache.clone(), "yoroi");
+                std::thread::spawn(move || {
+                    for i in 0..50 {
+                        let record = ServiceRecord {
+                            id: format!("{}.{}", replica, i),
+                            name: replica.to_string(),
+                            ..Default::default()
+                        };
+                        let origin = replica.to_string();
+                        backend.apply(&Change::Put { origin, record }).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        let records = CacheBackend::new(cache, "yoroi").list().unwrap();
+        assert_eq!(records.len(), 100);
+    }
+}
@@ -0,0 +1,456 @@
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+};
+
+use hyper::HeaderMap;
+use rand::Rng;
+
+use crate::stats::StatsTable;
+
+/// Picks the endpoint that should serve the next request of a service.
+///
+/// `endpoints` is never empty and is always given in the same (sorted) order,
+/// so implementations are free to keep positional state between calls.
+/// `headers` are the headers (gRPC metadata) of the request being routed.
+pub trait LoadBalancer: Send + Sync + Debug {
+    fn pick(&self, endpoints: &[String], headers: &HeaderMap) -> Option<String>;
+
+    /// Told all of the service's endpoints (sorted) whenever they change,
+    /// those given to `pick` are the ones of them currently available.
+    fn set_endpoints(&self, _endpoints: &[String]) {}
+}
+
+/// Balancing strategy selected per service when it is registered.
+#[derive(Clone, Debug, Default)]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    Random,
+    /// Endpoint weights, endpoints without an entry get the weight they are
+    /// registered with.
+    WeightedRoundRobin(HashMap<String, u32>),
+    /// Endpoint with the fewest requests in flight.
+    LeastRequest,
+    /// Fewest requests in flight out of two endpoints picked at random.
+    PowerOfTwoChoices,
+    /// Lowest peak EWMA latency weighted by requests in flight, out of two
+    /// endpoints picked at random.
+    PeakEwma,
+    /// Consistent hashing of the value of `header`, requests without the
+    /// header are balanced with `fallback`.
+    RingHash {
+        header: String,
+        fallback: Box<Strategy>,
+    },
+    Custom(Arc<dyn LoadBalancer>),
+}
+
+impl Strategy {
+    pub(crate) fn build(&self, stats: &StatsTable) -> Arc<dyn LoadBalancer> {
+        match self {
+            Strategy::RoundRobin => Arc::new(RoundRobin::default()),
+            Strategy::Random => Arc::new(Random),
+            Strategy::WeightedRoundRobin(weights) => Arc::new(WeightedRoundRobin {
+                registered: Some(stats.clone()),
+                ..WeightedRoundRobin::new(weights.clone())
+            }),
+            Strategy::LeastRequest => Arc::new(LeastRequest::new(stats.clone())),
+            Strategy::PowerOfTwoChoices => Arc::new(PowerOfTwoChoices::new(stats.clone())),
+            Strategy::PeakEwma => Arc::new(PeakEwma::new(stats.clone())),
+            Strategy::RingHash { header, fallback } => {
+                Arc::new(RingHash::new(header.clone(), fallback.build(stats)))
+            }
+            Strategy::Custom(lb) => lb.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl LoadBalancer for RoundRobin {
+    fn pick(&self, endpoints: &[String], _: &HeaderMap) -> Option<String> {
+        if endpoints.is_empty() {
+            return None;
+        }
+        let i = self.next.fetch_add(1, Ordering::Relaxed);
+        endpoints.get(i % endpoints.len()).cloned()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Random;
+
+impl LoadBalancer for Random {
+    fn pick(&self, endpoints: &[String], _: &HeaderMap) -> Option<String> {
+        if endpoints.is_empty() {
+            return None;
+        }
+        let i = rand::thread_rng().gen_range(0..endpoints.len());
+        endpoints.get(i).cloned()
+    }
+}
+
+/// Smooth weighted round-robin (the nginx algorithm), spreads picks of heavy
+/// endpoints out instead of sending them in bursts.
+#[derive(Debug, Default)]
+pub struct WeightedRoundRobin {
+    weights: HashMap<String, u32>,
+    // weights endpoints are registered with, used when `weights` has none
+    registered: Option<StatsTable>,
+    current: Mutex<HashMap<String, i64>>,
+}
+
+impl WeightedRoundRobin {
+    pub fn new(weights: HashMap<String, u32>) -> Self {
+        WeightedRoundRobin {
+            weights,
+            registered: None,
+            current: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn weight(&self, endpoint: &str) -> i64 {
+        match (self.weights.get(endpoint), &self.registered) {
+            (Some(weight), _) => *weight as i64,
+            (None, Some(stats)) => stats.get(endpoint).weight() as i64,
+            (None, None) => 1,
+        }
+    }
+}
+
+impl LoadBalancer for WeightedRoundRobin {
+    fn pick(&self, endpoints: &[String], _: &HeaderMap) -> Option<String> {
+        let mut current = self.current.lock().unwrap();
+        current.retain(|endpoint, _| endpoints.contains(endpoint));
+
+        let mut total = 0;
+        let mut best: Option<(&String, i64)> = None;
+        for endpoint in endpoints {
+            let weight = self.weight(endpoint);
+            if weight == 0 {
+                continue;
+            }
+            total += weight;
+            let cw = current.entry(endpoint.clone()).or_insert(0);
+            *cw += weight;
+            if best.is_none_or(|(_, b)| *cw > b) {
+                best = Some((endpoint, *cw));
+            }
+        }
+
+        let (best, _) = best?;
+        if let Some(cw) = current.get_mut(best) {
+            *cw -= total;
+        }
+        Some(best.clone())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LeastRequest {
+    stats: StatsTable,
+    next: AtomicUsize,
+}
+
+impl LeastRequest {
+    pub fn new(stats: StatsTable) -> Self {
+        LeastRequest {
+            stats,
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl LoadBalancer for LeastRequest {
+    fn pick(&self, endpoints: &[String], _: &HeaderMap) -> Option<String> {
+        if endpoints.is_empty() {
+            return None;
+        }
+        // rotate the scan start so ties don't always go to the first endpoint
+        let offset = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..endpoints.len())
+            .map(|i| &endpoints[(offset + i) % endpoints.len()])
+            .min_by_key(|endpoint| self.stats.get(endpoint).in_flight())
+            .cloned()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PowerOfTwoChoices {
+    stats: StatsTable,
+}
+
+impl PowerOfTwoChoices {
+    pub fn new(stats: StatsTable) -> Self {
+        PowerOfTwoChoices { stats }
+    }
+}
+
+impl LoadBalancer for PowerOfTwoChoices {
+    fn pick(&self, endpoints: &[String], _: &HeaderMap) -> Option<String> {
+        pick_two_by(endpoints, |endpoint| {
+            self.stats.get(endpoint).in_flight() as f64
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct PeakEwma {
+    stats: StatsTable,
+}
+
+impl PeakEwma {
+    pub fn new(stats: StatsTable) -> Self {
+        PeakEwma { stats }
+    }
+}
+
+impl LoadBalancer for PeakEwma {
+    fn pick(&self, endpoints: &[String], _: &HeaderMap) -> Option<String> {
+        pick_two_by(endpoints, |endpoint| {
+            let stats = self.stats.get(endpoint);
+            // endpoints without samples cost nothing, so they get measured first
+            let latency = stats.latency().unwrap_or_default().as_nanos() as f64;
+            latency * (stats.in_flight() + 1) as f64
+        })
+    }
+}
+
+/// Picks two distinct endpoints at random and keeps the cheapest one.
+fn pick_two_by<F>(endpoints: &[String], cost: F) -> Option<String>
+where
+    F: Fn(&str) -> f64,
+{
+    if endpoints.len() < 2 {
+        return endpoints.first().cloned();
+    }
+    let mut rng = rand::thread_rng();
+    let a = rng.gen_range(0..endpoints.len());
+    let b = (a + rng.gen_range(1..endpoints.len())) % endpoints.len();
+    let (a, b) = (&endpoints[a], &endpoints[b]);
+    if cost(b) < cost(a) {
+        Some(b.clone())
+    } else {
+        Some(a.clone())
+    }
+}
+
+/// Virtual nodes per endpoint, enough to keep the key spread even for a
+/// handful of endpoints.
+const RING_VNODES: usize = 160;
+
+/// Ketama style hash ring: adding or removing an endpoint only remaps the keys
+/// that land on (or next to) that endpoint's points. The ring holds all of the
+/// service's endpoints, keys of one that is unavailable go to the next point
+/// clockwise and come back once it is.
+#[derive(Debug)]
+pub struct RingHash {
+    header: String,
+    fallback: Arc<dyn LoadBalancer>,
+    ring: RwLock<Ring>,
+}
+
+#[derive(Debug, Default)]
+struct Ring {
+    endpoints: Vec<String>,
+    points: Vec<(u64, usize)>,
+}
+
+impl Ring {
+    fn new(endpoints: &[String]) -> Self {
+        let mut points = Vec::with_capacity(endpoints.len() * RING_VNODES);
+        for (i, endpoint) in endpoints.iter().enumerate() {
+            for vnode in 0..RING_VNODES {
+                points.push((hash(format!("{}-{}", endpoint, vnode).as_bytes()), i));
+            }
+        }
+        points.sort_unstable();
+        Ring {
+            endpoints: endpoints.to_vec(),
+            points,
+        }
+    }
+
+    /// First endpoint clockwise from `key` that is one of `available`.
+    fn lookup(&self, key: &[u8], available: &[String]) -> Option<&String> {
+        let h = hash(key);
+        let i = self.points.partition_point(|(point, _)| *point < h);
+        self.points[i..]
+            .iter()
+            .chain(&self.points[..i])
+            .map(|(_, endpoint)| &self.endpoints[*endpoint])
+            .find(|endpoint| available.binary_search(endpoint).is_ok())
+    }
+}
+
+impl RingHash {
+    pub fn new(header: String, fallback: Arc<dyn LoadBalancer>) -> Self {
+        RingHash {
+            header,
+            fallback,
+            ring: RwLock::new(Ring::default()),
+        }
+    }
+}
+
+impl LoadBalancer for RingHash {
+    fn pick(&self, endpoints: &[String], headers: &HeaderMap) -> Option<String> {
+        let key = match headers.get(self.header.as_str()) {
+            Some(value) => value.as_bytes(),
+            None => return self.fallback.pick(endpoints, headers),
+        };
+        if let Some(endpoint) = self.ring.read().unwrap().lookup(key, endpoints) {
+            return Some(endpoint.clone());
+        }
+        self.fallback.pick(endpoints, headers)
+    }
+
+    fn set_endpoints(&self, endpoints: &[String]) {
+        self.fallback.set_endpoints(endpoints);
+        if self.ring.read().unwrap().endpoints != endpoints {
+            *self.ring.write().unwrap() = Ring::new(endpoints);
+        }
+    }
+}
+
+/// FNV-1a with a murmur3 finalizer, stable across builds and processes so
+/// every gateway instance maps a key to the same endpoint.
+pub(crate) fn hash(bytes: &[u8]) -> u64 {
+    let mut h: u64 = 0xcbf29ce484222325;
+    for b in bytes {
+        h ^= *b as u64;
+        h = h.wrapping_mul(0x100000001b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests_balancer {
+    use std::{collections::HashMap, time::Duration};
+
+    use super::*;
+
+    fn endpoints() -> Vec<String> {
+        vec!["a:1".to_string(), "b:1".to_string(), "c:1".to_string()]
+    }
+
+    #[test]
+    fn test_round_robin() {
+        let lb = RoundRobin::default();
+        let picks: Vec<String> = (0..6)
+            .filter_map(|_| lb.pick(&endpoints(), &HeaderMap::new()))
+            .collect();
+        assert_eq!(picks, vec!["a:1", "b:1", "c:1", "a:1", "b:1", "c:1"]);
+    }
+
+    #[test]
+    fn test_weighted_round_robin() {
+        let lb = WeightedRoundRobin::new(HashMap::from([
+            ("a:1".to_string(), 5),
+            ("b:1".to_string(), 1),
+            ("c:1".to_string(), 0),
+        ]));
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..60 {
+            *counts
+                .entry(lb.pick(&endpoints(), &HeaderMap::new()).unwrap())
+                .or_default() += 1;
+        }
+        assert_eq!(counts.get("a:1"), Some(&50));
+        assert_eq!(counts.get("b:1"), Some(&10));
+        assert_eq!(counts.get("c:1"), None);
+    }
+
+    #[test]
+    fn test_least_request() {
+        let stats = StatsTable::default();
+        let _a = stats.get("a:1").start();
+        let _c = stats.get("c:1").start();
+        let lb = LeastRequest::new(stats.clone());
+        for _ in 0..3 {
+            assert_eq!(
+                lb.pick(&endpoints(), &HeaderMap::new()),
+                Some("b:1".to_string())
+            );
+        }
+
+        let p2c = PowerOfTwoChoices::new(stats.clone());
+        let _b = (0..2).map(|_| stats.get("b:1").start()).collect::<Vec<_>>();
+        for _ in 0..10 {
+            assert_ne!(
+                p2c.pick(&endpoints(), &HeaderMap::new()),
+                Some("b:1".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_peak_ewma() {
+        let stats = StatsTable::default();
+        stats.get("a:1").observe_latency(Duration::from_millis(500));
+        stats.get("b:1").observe_latency(Duration::from_millis(5));
+        stats.get("c:1").observe_latency(Duration::from_millis(8));
+        let lb = PeakEwma::new(stats);
+        for _ in 0..20 {
+            assert_ne!(
+                lb.pick(&endpoints(), &HeaderMap::new()),
+                Some("a:1".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_ring_hash() {
+        let lb = RingHash::new("x-user-id".to_string(), Arc::new(RoundRobin::default()));
+        let user = |id: usize| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-user-id", id.to_string().parse().unwrap());
+            headers
+        };
+
+        let mut endpoints: Vec<String> = (0..4).map(|i| format!("10.0.0.{}:80", i)).collect();
+        lb.set_endpoints(&endpoints);
+        let before: Vec<String> = (0..1000)
+            .map(|id| lb.pick(&endpoints, &user(id)).unwrap())
+            .collect();
+        assert_eq!(lb.pick(&endpoints, &user(7)), Some(before[7].clone()));
+
+        // only the keys of an unavailable endpoint move, and they come back
+        let available: Vec<String> = endpoints[1..].to_vec();
+        for (id, old) in before.iter().enumerate() {
+            let new = lb.pick(&available, &user(id)).unwrap();
+            assert!(new == *old || *old == endpoints[0], "key {} moved", id);
+        }
+        assert_eq!(lb.pick(&endpoints, &user(7)), Some(before[7].clone()));
+
+        endpoints.push("10.0.0.4:80".to_string());
+        lb.set_endpoints(&endpoints);
+        let mut moved = 0;
+        for (id, old) in before.iter().enumerate() {
+            let new = lb.pick(&endpoints, &user(id)).unwrap();
+            if new != *old {
+                assert_eq!(new, "10.0.0.4:80");
+                moved += 1;
+            }
+        }
+        assert!(moved > 100 && moved < 300, "moved {} keys", moved);
+
+        // no header, falls back to round-robin
+        assert_eq!(
+            lb.pick(&endpoints, &HeaderMap::new()),
+            Some("10.0.0.0:80".to_string())
+        );
+    }
+}
@@ -0,0 +1,107 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use http_body_util::combinators::BoxBody;
+use hyper::body::{Body, Bytes, Frame, SizeHint};
+
+use crate::{
+    stats::InFlightGuard,
+    upstream::{self, Upstream},
+};
+
+/// Upstream response body that keeps its request counted as in flight until
+/// the last frame has been streamed to the client (or the client goes away).
+///
+/// When the request outcome was not known from the response headers, the
+/// `upstream` is reported to once the trailers (or the end of the body) come.
+pub(crate) struct TrackedBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    guard: Option<InFlightGuard>,
+    upstream: Option<Upstream>,
+}
+
+impl TrackedBody {
+    pub(crate) fn new(
+        inner: BoxBody<Bytes, hyper::Error>,
+        guard: InFlightGuard,
+        upstream: Option<Upstream>,
+    ) -> Self {
+        TrackedBody {
+            inner,
+            guard: Some(guard),
+            upstream,
+        }
+    }
+
+    fn report(&mut self, success: bool) {
+        if let Some(upstream) = self.upstream.take() {
+            upstream.report(success);
+        }
+    }
+}
+
+impl Body for TrackedBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.inner).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(trailers) = frame.trailers_ref() {
+                    let success = upstream::trailers_outcome(trailers);
+                    this.report(success);
+                }
+            }
+            Poll::Ready(Some(Err(_))) => {
+                this.report(false);
+                this.guard.take();
+            }
+            Poll::Ready(None) => {
+                this.report(true);
+                this.guard.take();
+            }
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests_body {
+    use std::sync::Arc;
+
+    use http_body_util::{BodyExt, Full};
+
+    use super::*;
+    use crate::stats::EndpointStats;
+
+    #[tokio::test]
+    async fn test_guard_released_after_last_frame() {
+        let stats = Arc::new(EndpointStats::default());
+        let inner = Full::new(Bytes::from("pong"))
+            .map_err(|never| match never {})
+            .boxed();
+        let mut body = TrackedBody::new(inner, stats.start(), None);
+        assert_eq!(stats.in_flight(), 1);
+
+        while let Some(frame) = body.frame().await {
+            frame.unwrap();
+        }
+        assert_eq!(stats.in_flight(), 0);
+    }
+}
@@ -0,0 +1,251 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+/// What trips a circuit breaker open.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case", deny_unknown_fields)]
+pub enum Trip {
+    ConsecutiveFailures(u32),
+    /// Share of failed requests (0.0 - 1.0) within the breaker window, once
+    /// the window has seen at least `min_requests`.
+    ErrorRate {
+        rate: f64,
+        min_requests: u32,
+    },
+}
+
+/// Per endpoint circuit breaker of a service.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CircuitBreaker {
+    pub trip: Trip,
+    /// Window the error rate is counted over.
+    #[serde(with = "crate::config::duration")]
+    pub window: Duration,
+    /// How long a tripped breaker stays open before probing the endpoint.
+    #[serde(with = "crate::config::duration")]
+    pub open_for: Duration,
+    /// Requests let through while half-open, all of them have to succeed to
+    /// close the breaker again.
+    pub half_open_probes: u32,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker {
+            trip: Trip::ConsecutiveFailures(5),
+            window: Duration::from_secs(10),
+            open_for: Duration::from_secs(30),
+            half_open_probes: 1,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BreakerState {
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub(crate) struct Breaker {
+    state: BreakerState,
+    window_start: Instant,
+    requests: u32,
+    failures: u32,
+    consecutive_failures: u32,
+    opened_at: Instant,
+    probes: u32,
+    probe_successes: u32,
+}
+
+impl Default for Breaker {
+    fn default() -> Self {
+        let now = Instant::now();
+        Breaker {
+            state: BreakerState::Closed,
+            window_start: now,
+            requests: 0,
+            failures: 0,
+            consecutive_failures: 0,
+            opened_at: now,
+            probes: 0,
+            probe_successes: 0,
+        }
+    }
+}
+
+impl Breaker {
+    pub(crate) fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    /// Whether a request may be sent, an open breaker whose open time ran
+    /// out lets probes through. The state is left as is, see `acquire`.
+    pub(crate) fn allows(&self, config: &CircuitBreaker, now: Instant) -> bool {
+        match self.state {
+            BreakerState::Closed => true,
+            BreakerState::Open => now >= self.opened_at + config.open_for,
+            BreakerState::HalfOpen => self.probes < config.half_open_probes,
+        }
+    }
+
+    /// Admits a request to the endpoint, moving an open breaker whose open
+    /// time ran out to half-open. Takes a probe slot when half-open, returns
+    /// whether one was taken.
+    pub(crate) fn acquire(&mut self, config: &CircuitBreaker, now: Instant) -> bool {
+        if self.state == BreakerState::Open && now >= self.opened_at + config.open_for {
+            self.state = BreakerState::HalfOpen;
+            self.probes = 0;
+            self.probe_successes = 0;
+        }
+        if self.state == BreakerState::HalfOpen && self.probes < config.half_open_probes {
+            self.probes += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Gives back a probe slot of a request that ended without an outcome.
+    pub(crate) fn release(&mut self) {
+        if self.state == BreakerState::HalfOpen {
+            self.probes = self.probes.saturating_sub(1);
+        }
+    }
+
+    /// Records the outcome of a request, a `probe` when it took a probe
+    /// slot, returns the new state when it changed.
+    pub(crate) fn record(
+        &mut self,
+        success: bool,
+        probe: bool,
+        config: &CircuitBreaker,
+        now: Instant,
+    ) -> Option<BreakerState> {
+        match self.state {
+            // only probes decide, other requests were sent while closed
+            BreakerState::HalfOpen if !probe => None,
+            BreakerState::HalfOpen if success => {
+                self.probe_successes += 1;
+                if self.probe_successes >= config.half_open_probes {
+                    self.close(now);
+                    return Some(BreakerState::Closed);
+                }
+                None
+            }
+            BreakerState::HalfOpen => {
+                self.open(now);
+                Some(BreakerState::Open)
+            }
+            // late results of requests sent before the breaker opened
+            BreakerState::Open => None,
+            BreakerState::Closed => {
+                if now >= self.window_start + config.window {
+                    self.window_start = now;
+                    self.requests = 0;
+                    self.failures = 0;
+                }
+                self.requests += 1;
+                if success {
+                    self.consecutive_failures = 0;
+                    return None;
+                }
+                self.failures += 1;
+                self.consecutive_failures += 1;
+                let trip = match config.trip {
+                    Trip::ConsecutiveFailures(n) => self.consecutive_failures >= n,
+                    Trip::ErrorRate { rate, min_requests } => {
+                        self.requests >= min_requests
+                            && self.failures as f64 / self.requests as f64 >= rate
+                    }
+                };
+                if trip {
+                    self.open(now);
+                    return Some(BreakerState::Open);
+                }
+                None
+            }
+        }
+    }
+
+    fn open(&mut self, now: Instant) {
+        self.state = BreakerState::Open;
+        self.opened_at = now;
+    }
+
+    fn close(&mut self, now: Instant) {
+        *self = Breaker {
+            window_start: now,
+            ..Default::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests_breaker {
+    use super::*;
+
+    #[test]
+    fn test_consecutive_failures() {
+        let config = CircuitBreaker {
+            trip: Trip::ConsecutiveFailures(2),
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut breaker = Breaker::default();
+        assert_eq!(breaker.record(false, false, &config, now), None);
+        assert_eq!(
+            breaker.record(false, false, &config, now),
+            Some(BreakerState::Open)
+        );
+        assert!(!breaker.allows(&config, now));
+
+        // one probe once the open time is over
+        let later = now + config.open_for;
+        assert!(breaker.allows(&config, later));
+        assert_eq!(breaker.state(), BreakerState::Open);
+        assert!(breaker.acquire(&config, later));
+        assert!(!breaker.allows(&config, later));
+        assert!(!breaker.acquire(&config, later));
+        // a request sent before the breaker opened is no probe
+        assert_eq!(breaker.record(false, false, &config, later), None);
+        assert_eq!(
+            breaker.record(true, true, &config, later),
+            Some(BreakerState::Closed)
+        );
+        assert!(breaker.allows(&config, later));
+    }
+
+    #[test]
+    fn test_error_rate() {
+        let config = CircuitBreaker {
+            trip: Trip::ErrorRate {
+                rate: 0.5,
+                min_requests: 4,
+            },
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut breaker = Breaker::default();
+        for success in [false, true, false] {
+            assert_eq!(breaker.record(success, false, &config, now), None);
+        }
+        assert_eq!(
+            breaker.record(false, false, &config, now),
+            Some(BreakerState::Open)
+        );
+
+        // a failed probe opens the breaker again
+        let later = now + config.open_for;
+        assert!(breaker.allows(&config, later));
+        assert!(breaker.acquire(&config, later));
+        assert_eq!(
+            breaker.record(false, true, &config, later),
+            Some(BreakerState::Open)
+        );
+        assert_eq!(breaker.state(), BreakerState::Open);
+    }
+}
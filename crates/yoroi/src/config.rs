@@ -0,0 +1,900 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
+
+use hyper::header::HeaderName;
+use serde::Deserialize;
+use tokio::time::interval;
+use tracing::{error, info, warn};
+
+use crate::{
+    balancer::Strategy,
+    breaker::{CircuitBreaker, Trip},
+    deadline::DeadlinePolicy,
+    endpoint::Endpoint,
+    health::HealthCheck,
+    hedge::HedgePolicy,
+    mirror::Mirror,
+    outlier::OutlierDetection,
+    pool::PoolConfig,
+    protocol::Protocol,
+    registry::ServiceRegistry,
+    retry::RetryPolicy,
+    router::{Route, Router},
+    split::TrafficSplit,
+    subset::{Selector, SubsetRule},
+    tls::{TlsConfig, UpstreamTlsConfig},
+};
+
+/// How often the config file is checked for changes.
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Gateway configuration as loaded from a TOML or YAML file.
+///
+/// ```toml
+/// listen = "[::1]:8080"
+///
+/// [[services]]
+/// id = "kyc.Kyc"
+/// name = "kyc"
+/// endpoints = ["localhost:50051"]
+/// strategy = { kind = "peak_ewma" }
+/// health_check = { interval = "5s", timeout = "500ms" }
+///
+/// [[routes]]
+/// exact = "/kyc.Kyc/register"
+/// service = "kyc.Kyc"
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub listen: String,
+    /// TLS on the listener, plaintext HTTP/1.1 or HTTP/2 when unset.
+    pub tls: Option<TlsConfig>,
+    /// Address of the admin API, it is disabled when unset. The API is not
+    /// authenticated, keep it on a loopback or private interface.
+    pub admin: Option<String>,
+    /// Address of the self-registration gRPC API, it is disabled when unset.
+    pub registration: Option<String>,
+    #[serde(default)]
+    pub pool: PoolConfig,
+    /// Deadline of the requests of routes without their own, see
+    /// `DeadlinePolicy`.
+    #[serde(default)]
+    pub deadline: DeadlinePolicy,
+    #[serde(default)]
+    pub services: Vec<ServiceConfig>,
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Registrations shared with other replicas, local when unset.
+    pub shared_registry: Option<SharedRegistryConfig>,
+}
+
+/// Redis the replicas keep their registrations in.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SharedRegistryConfig {
+    /// e.g. `redis://127.0.0.1:6379`
+    pub redis: String,
+    /// Prefix of the keys and channel used, replicas sharing one prefix
+    /// share their services.
+    #[serde(default = "default_prefix")]
+    pub prefix: String,
+}
+
+fn default_prefix() -> String {
+    "yoroi".to_string()
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub id: String,
+    pub name: String,
+    pub endpoints: Vec<EndpointConfig>,
+    #[serde(default)]
+    pub strategy: StrategyConfig,
+    pub health_check: Option<HealthCheck>,
+    pub outlier_detection: Option<OutlierDetection>,
+    pub circuit_breaker: Option<CircuitBreaker>,
+    /// Canary split across endpoint versions, e.g.
+    /// `split = { weights = { v1 = 95, v2 = 5 }, sticky = "x-user-id" }`.
+    pub split: Option<TrafficSplit>,
+    /// Header-based routing to endpoint subsets, see `SubsetRule`.
+    #[serde(default)]
+    pub subsets: Vec<SubsetRule>,
+    /// Shadow service a share of the requests is copied to, e.g.
+    /// `mirror = { service = "kyc.Kyc.v2", percent = 10 }`.
+    pub mirror: Option<Mirror>,
+    /// e.g. `retry = { max_attempts = 3, retry_on = ["unavailable"] }`, see
+    /// `RetryPolicy` for the defaults.
+    pub retry: Option<RetryPolicy>,
+    /// e.g. `hedge = { methods = ["/kyc.Kyc/get"], delay = "40ms" }`, see
+    /// `HedgePolicy`.
+    pub hedge: Option<HedgePolicy>,
+    /// TLS to the service's endpoints, plaintext (see `protocol`) when unset,
+    /// see `UpstreamTlsConfig`.
+    pub tls: Option<UpstreamTlsConfig>,
+    /// `http1` for plain HTTP services, HTTP/2 (gRPC) by default.
+    #[serde(default)]
+    pub protocol: Protocol,
+}
+
+/// File form of an `Endpoint`, either its address alone or a table with
+/// its metadata:
+///
+/// ```toml
+/// endpoints = [
+///     "10.0.0.1:50051",
+///     { address = "10.0.0.2:50051", weight = 3, version = "v2", state = "draining" },
+/// ]
+/// ```
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum EndpointConfig {
+    Address(String),
+    Endpoint(Endpoint),
+}
+
+impl EndpointConfig {
+    pub fn address(&self) -> &str {
+        match self {
+            EndpointConfig::Address(address) => address,
+            EndpointConfig::Endpoint(endpoint) => &endpoint.address,
+        }
+    }
+
+    pub fn to_endpoint(&self) -> Endpoint {
+        match self {
+            EndpointConfig::Address(address) => Endpoint::new(address.clone()),
+            EndpointConfig::Endpoint(endpoint) => endpoint.clone(),
+        }
+    }
+}
+
+/// File form of a balancing `Strategy`.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum StrategyConfig {
+    #[default]
+    RoundRobin,
+    Random,
+    WeightedRoundRobin {
+        weights: HashMap<String, u32>,
+    },
+    LeastRequest,
+    PowerOfTwoChoices,
+    PeakEwma,
+    RingHash {
+        header: String,
+        fallback: Option<Box<StrategyConfig>>,
+    },
+}
+
+impl From<&StrategyConfig> for Strategy {
+    fn from(config: &StrategyConfig) -> Self {
+        match config {
+            StrategyConfig::RoundRobin => Strategy::RoundRobin,
+            StrategyConfig::Random => Strategy::Random,
+            StrategyConfig::WeightedRoundRobin { weights } => {
+                Strategy::WeightedRoundRobin(weights.clone())
+            }
+            StrategyConfig::LeastRequest => Strategy::LeastRequest,
+            StrategyConfig::PowerOfTwoChoices => Strategy::PowerOfTwoChoices,
+            StrategyConfig::PeakEwma => Strategy::PeakEwma,
+            StrategyConfig::RingHash { header, fallback } => Strategy::RingHash {
+                header: header.clone(),
+                fallback: Box::new(fallback.as_deref().map(Strategy::from).unwrap_or_default()),
+            },
+        }
+    }
+}
+
+/// A routing rule, exactly one of `exact`, `prefix` and `regex` is set.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub exact: Option<String>,
+    pub prefix: Option<String>,
+    pub regex: Option<String>,
+    pub service: String,
+    /// Overrides the top-level `deadline` for the route's requests.
+    pub deadline: Option<DeadlinePolicy>,
+}
+
+impl RouteConfig {
+    fn build(&self) -> Result<Route, String> {
+        let route = match (&self.exact, &self.prefix, &self.regex) {
+            (Some(path), None, None) => Route::exact(path, &self.service),
+            (None, Some(path), None) => Route::prefix(path, &self.service),
+            (None, None, Some(pattern)) => {
+                Route::regex(pattern, &self.service).map_err(|err| err.to_string())?
+            }
+            _ => return Err("exactly one of `exact`, `prefix` or `regex` must be set".to_string()),
+        };
+        match self.deadline {
+            Some(deadline) => Ok(route.with_deadline(deadline)),
+            None => Ok(route),
+        }
+    }
+}
+
+fn validate_deadline(deadline: &DeadlinePolicy) -> Result<(), String> {
+    if deadline.default.is_some_and(|d| d.is_zero()) || deadline.max.is_some_and(|d| d.is_zero()) {
+        return Err("timeouts must not be zero".to_string());
+    }
+    if let (Some(default), Some(max)) = (deadline.default, deadline.max) {
+        if default > max {
+            return Err("default must not exceed max".to_string());
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, std::io::Error),
+    Parse(PathBuf, String),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            ConfigError::Parse(path, err) => write!(f, "{}: {}", path.display(), err),
+            ConfigError::Invalid(msg) => write!(f, "invalid config: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Config::parse(s, Path::new("<config>"))
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let raw = std::fs::read_to_string(path)
+            .map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+        Config::parse(&raw, path)
+    }
+
+    /// Parses YAML for `.yaml` and `.yml` paths and TOML otherwise.
+    fn parse(raw: &str, path: &Path) -> Result<Self, ConfigError> {
+        let yaml = matches!(
+            path.extension().and_then(|ext| ext.to_str()),
+            Some("yaml" | "yml")
+        );
+        let config: Config = if yaml {
+            serde_yaml::from_str(raw).map_err(|err| err.to_string())
+        } else {
+            toml::from_str(raw).map_err(|err| err.to_string())
+        }
+        .map_err(|err| ConfigError::Parse(path.to_path_buf(), err))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: String| Err(ConfigError::Invalid(msg));
+
+        if let Err(err) = SocketAddr::from_str(&self.listen) {
+            return invalid(format!(
+                "listen: `{}` is not a socket address: {}",
+                self.listen, err
+            ));
+        }
+        for (key, address) in [("admin", &self.admin), ("registration", &self.registration)] {
+            if let Some(address) = address {
+                if let Err(err) = SocketAddr::from_str(address) {
+                    return invalid(format!(
+                        "{}: `{}` is not a socket address: {}",
+                        key, address, err
+                    ));
+                }
+            }
+        }
+        if let Some(shared) = &self.shared_registry {
+            if !shared.redis.starts_with("redis://") && !shared.redis.starts_with("rediss://") {
+                return invalid(format!(
+                    "shared_registry.redis: `{}` is not a redis:// url",
+                    shared.redis
+                ));
+            }
+        }
+        if let Some(tls) = &self.tls {
+            if tls.certificates.is_empty() {
+                return invalid("tls.certificates must not be empty".to_string());
+            }
+        }
+        if self.pool.max_connections == 0 {
+            return invalid("pool.max_connections must be at least 1".to_string());
+        }
+        if self.pool.connect_timeout.is_zero() {
+            return invalid("pool.connect_timeout must not be zero".to_string());
+        }
+        if let Err(msg) = validate_deadline(&self.deadline) {
+            return invalid(format!("deadline: {}", msg));
+        }
+
+        let mut ids = HashSet::new();
+        for (i, service) in self.services.iter().enumerate() {
+            let at = format!("services[{}] (`{}`)", i, service.id);
+            if service.id.is_empty() {
+                return invalid(format!("services[{}]: id must not be empty", i));
+            }
+            if !ids.insert(service.id.as_str()) {
+                return invalid(format!("{}: duplicate service id", at));
+            }
+            let mut addresses = HashSet::new();
+            for endpoint in &service.endpoints {
+                let address = endpoint.address();
+                if let Err(msg) = validate_endpoint(address) {
+                    return invalid(format!("{}: endpoint `{}` {}", at, address, msg));
+                }
+                if !addresses.insert(address) {
+                    return invalid(format!("{}: duplicate endpoint `{}`", at, address));
+                }
+            }
+            if let Err(msg) = validate_strategy(&service.strategy) {
+                return invalid(format!("{}: strategy: {}", at, msg));
+            }
+            if let Some(check) = &service.health_check {
+                if check.interval.is_zero() || check.timeout.is_zero() {
+                    return invalid(format!(
+                        "{}: health_check interval and timeout must be set",
+                        at
+                    ));
+                }
+                if check.rise == 0 || check.fall == 0 {
+                    return invalid(format!(
+                        "{}: health_check rise and fall must be at least 1",
+                        at
+                    ));
+                }
+            }
+            if let Some(detection) = &service.outlier_detection {
+                if detection.consecutive_failures == 0 {
+                    return invalid(format!(
+                        "{}: outlier_detection.consecutive_failures must be at least 1",
+                        at
+                    ));
+                }
+                if detection.max_ejection_percent > 100 {
+                    return invalid(format!(
+                        "{}: outlier_detection.max_ejection_percent must be at most 100",
+                        at
+                    ));
+                }
+            }
+            if let Some(split) = &service.split {
+                if split.weights.values().all(|weight| *weight == 0) {
+                    return invalid(format!("{}: split needs a version with weight", at));
+                }
+                if let Some(sticky) = &split.sticky {
+                    if HeaderName::from_str(sticky).is_err() {
+                        return invalid(format!(
+                            "{}: split.sticky `{}` is not a valid header name",
+                            at, sticky
+                        ));
+                    }
+                }
+            }
+            for (j, rule) in service.subsets.iter().enumerate() {
+                if HeaderName::from_str(&rule.header).is_err() {
+                    return invalid(format!(
+                        "{}: subsets[{}]: `{}` is not a valid header name",
+                        at, j, rule.header
+                    ));
+                }
+                if matches!(&rule.select, Selector::Label { key, .. } if key.is_empty()) {
+                    return invalid(format!("{}: subsets[{}]: label key must be set", at, j));
+                }
+            }
+            if service.health_check.is_some() && service.protocol != Protocol::Http2 {
+                return invalid(format!(
+                    "{}: health_check needs protocol http2, checks are gRPC",
+                    at
+                ));
+            }
+            if let Some(tls) = &service.tls {
+                match tls.validate() {
+                    Ok(()) => {}
+                    Err(ConfigError::Invalid(msg)) => {
+                        return invalid(format!("{}: tls: {}", at, msg))
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            if let Some(mirror) = &service.mirror {
+                if mirror.service == service.id {
+                    return invalid(format!("{}: mirror.service must be another service", at));
+                }
+                if !(0.0..=100.0).contains(&mirror.percent) {
+                    return invalid(format!("{}: mirror.percent must be in [0, 100]", at));
+                }
+            }
+            if let Some(retry) = &service.retry {
+                if retry.max_attempts == 0 {
+                    return invalid(format!("{}: retry.max_attempts must be at least 1", at));
+                }
+                if retry.budget_percent < 0.0 {
+                    return invalid(format!("{}: retry.budget_percent must not be negative", at));
+                }
+                if retry.base_backoff > retry.max_backoff {
+                    return invalid(format!(
+                        "{}: retry.base_backoff must not exceed retry.max_backoff",
+                        at
+                    ));
+                }
+            }
+            if let Some(hedge) = &service.hedge {
+                if let Some(method) = hedge.methods.iter().find(|m| !m.starts_with('/')) {
+                    return invalid(format!(
+                        "{}: hedge.methods: `{}` must be a path starting with /",
+                        at, method
+                    ));
+                }
+                if hedge.delay.is_zero() {
+                    return invalid(format!("{}: hedge.delay must not be zero", at));
+                }
+                if hedge.max_attempts < 2 {
+                    return invalid(format!("{}: hedge.max_attempts must be at least 2", at));
+                }
+            }
+            if let Some(breaker) = &service.circuit_breaker {
+                let trip_ok = match breaker.trip {
+                    Trip::ConsecutiveFailures(n) => n > 0,
+                    Trip::ErrorRate { rate, .. } => rate > 0.0 && rate <= 1.0,
+                };
+                if !trip_ok {
+                    return invalid(format!(
+                        "{}: circuit_breaker.trip needs at least 1 failure or a rate in (0, 1]",
+                        at
+                    ));
+                }
+                if breaker.half_open_probes == 0 {
+                    return invalid(format!(
+                        "{}: circuit_breaker.half_open_probes must be at least 1",
+                        at
+                    ));
+                }
+            }
+        }
+
+        for (i, route) in self.routes.iter().enumerate() {
+            if let Err(msg) = route.build() {
+                return invalid(format!("routes[{}]: {}", i, msg));
+            }
+            if let Err(msg) = route.deadline.as_ref().map_or(Ok(()), validate_deadline) {
+                return invalid(format!("routes[{}]: deadline: {}", i, msg));
+            }
+            if !ids.contains(route.service.as_str()) {
+                return invalid(format!(
+                    "routes[{}]: service `{}` is not configured",
+                    i, route.service
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings the registry and the router in line with this config.
+    /// Services of `previous` that are gone from this config are deregistered,
+    /// services registered by other means are left alone.
+    pub fn apply(&self, registry: &ServiceRegistry, router: &Router, previous: Option<&Config>) {
+        let routes = self
+            .routes
+            .iter()
+            .filter_map(|route| route.build().ok())
+            .collect();
+
+        // the services are changed aside then swapped in at once, requests
+        // never see a service halfway through its update
+        let staged = registry.stage();
+        let mut removed = vec![];
+        if let Some(previous) = previous {
+            for old in &previous.services {
+                if !self.services.iter().any(|service| service.id == old.id) {
+                    info!("config: removing service {}", old.id);
+                    staged.deregister_service(&old.id);
+                    removed.push(old.id.as_str());
+                }
+            }
+        }
+
+        for service in &self.services {
+            let old = previous
+                .and_then(|previous| previous.services.iter().find(|old| old.id == service.id));
+            let live = staged.get_service(&service.id);
+            if live.is_none() || old.is_none_or(|old| old.strategy != service.strategy) {
+                staged.register_service_with_strategy(
+                    service.id.clone(),
+                    service.name.clone(),
+                    vec![],
+                    Strategy::from(&service.strategy),
+                );
+            }
+            // only endpoints the previous config listed go, those registered
+            // otherwise (self-registration, admin API) stay
+            if let Some(old) = old {
+                for endpoint in &old.endpoints {
+                    let address = endpoint.address();
+                    if !service.endpoints.iter().any(|e| e.address() == address) {
+                        staged.remove_endpoint(&service.id, address);
+                    }
+                }
+            }
+            for endpoint in &service.endpoints {
+                // metadata left as it was keeps what was changed at runtime,
+                // e.g. an endpoint drained through the admin API
+                let unchanged = old.is_some_and(|old| old.endpoints.contains(endpoint));
+                let registered = live
+                    .as_ref()
+                    .is_some_and(|live| live.endpoint(endpoint.address()).is_some());
+                if !(unchanged && registered) {
+                    staged.set_endpoint(&service.id, endpoint.to_endpoint());
+                }
+            }
+            staged.set_health_check(&service.id, service.health_check.clone());
+            staged.set_outlier_detection(&service.id, service.outlier_detection.clone());
+            staged.set_circuit_breaker(&service.id, service.circuit_breaker.clone());
+            // like endpoint metadata, a split left as it was keeps the
+            // weights shifted at runtime
+            if live.is_none() || old.is_none_or(|old| old.split != service.split) {
+                staged.set_traffic_split(&service.id, service.split.clone());
+            }
+            if live.is_none() || old.is_none_or(|old| old.subsets != service.subsets) {
+                staged.set_subset_rules(&service.id, service.subsets.clone());
+            }
+            if live.is_none() || old.is_none_or(|old| old.mirror != service.mirror) {
+                staged.set_mirror(&service.id, service.mirror.clone());
+            }
+            if live.is_none() || old.is_none_or(|old| old.tls != service.tls) {
+                if let Err(err) = staged.set_upstream_tls(&service.id, service.tls.as_ref()) {
+                    // a new service is left out rather than sent plaintext
+                    if live.is_none() {
+                        error!(
+                            "config: {}: not serving it without its tls: {}",
+                            service.id, err
+                        );
+                        staged.deregister_service(&service.id);
+                        continue;
+                    }
+                    error!("config: {}: keeping the current tls: {}", service.id, err);
+                }
+            }
+            staged.set_protocol(&service.id, service.protocol);
+            staged.set_retry_policy(&service.id, service.retry.clone());
+            staged.set_hedge_policy(&service.id, service.hedge.clone());
+        }
+
+        let configured: Vec<&str> = self.services.iter().map(|s| s.id.as_str()).collect();
+        registry.commit(&staged, &configured);
+        router.set_deadline(self.deadline);
+        router.set_routes(routes);
+        // only once no route leads to them anymore
+        registry.commit(&staged, &removed);
+    }
+}
+
+pub(crate) fn validate_endpoint(endpoint: &str) -> Result<(), &'static str> {
+    let (host, port) = endpoint.rsplit_once(':').ok_or("must be host:port")?;
+    if host.is_empty() {
+        return Err("has no host");
+    }
+    port.parse::<u16>().map_err(|_| "has an invalid port")?;
+    Ok(())
+}
+
+fn validate_strategy(strategy: &StrategyConfig) -> Result<(), String> {
+    match strategy {
+        StrategyConfig::RingHash { header, fallback } => {
+            if HeaderName::from_str(header).is_err() {
+                return Err(format!("`{}` is not a valid header name", header));
+            }
+            match fallback {
+                Some(fallback) => validate_strategy(fallback),
+                None => Ok(()),
+            }
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Reloads the config at `path` on SIGHUP or when the file changes, until the
+/// task is aborted. Invalid configs are logged and the live one is kept.
+pub(crate) async fn watch(
+    path: PathBuf,
+    mut current: Config,
+    registry: ServiceRegistry,
+    router: Router,
+) {
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(err) => {
+            warn!("config: SIGHUP reload disabled: {}", err);
+            None
+        }
+    };
+    let mut modified = modified_at(&path);
+    let mut ticker = interval(WATCH_INTERVAL);
+    loop {
+        #[cfg(unix)]
+        let hangup_received = async {
+            match hangup.as_mut() {
+                Some(signal) => signal.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup_received = std::future::pending::<Option<()>>();
+
+        tokio::select! {
+            _ = hangup_received => info!("config: SIGHUP received, reloading {}", path.display()),
+            _ = ticker.tick() => {
+                let now = modified_at(&path);
+                if now == modified {
+                    continue;
+                }
+                modified = now;
+                info!("config: {} changed, reloading", path.display());
+            }
+        }
+
+        match Config::load(&path) {
+            Ok(config) if config == current => {}
+            Ok(config) => {
+                if config.listen != current.listen
+                    || config.admin != current.admin
+                    || config.registration != current.registration
+                    || config.shared_registry != current.shared_registry
+                    || config.pool != current.pool
+                {
+                    warn!("config: listener and pool changes only apply after a restart");
+                }
+                config.apply(&registry, &router, Some(&current));
+                current = config;
+                info!("config: reloaded {}", path.display());
+            }
+            Err(err) => error!("config: keeping the live config: {}", err),
+        }
+    }
+}
+
+pub(crate) fn modified_at(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Serde helper for durations written as `"500ms"`, `"10s"`, `"5m"` or `"1h"`.
+pub(crate) mod duration {
+    use std::time::Duration;
+
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Duration, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let raw = String::deserialize(deserializer)?;
+        parse(&raw).ok_or_else(|| D::Error::custom(format!("invalid duration `{}`", raw)))
+    }
+
+    /// Writes `duration` back the way it is read, in milliseconds.
+    pub fn serialize<S>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{}ms", duration.as_millis()))
+    }
+
+    pub(crate) mod option {
+        use std::time::Duration;
+
+        use serde::{de::Error, Deserialize, Deserializer};
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            match Option::<String>::deserialize(deserializer)? {
+                Some(raw) => super::parse(&raw)
+                    .map(Some)
+                    .ok_or_else(|| D::Error::custom(format!("invalid duration `{}`", raw))),
+                None => Ok(None),
+            }
+        }
+    }
+
+    pub(crate) fn parse(raw: &str) -> Option<Duration> {
+        let raw = raw.trim();
+        let split = raw.find(|c: char| !c.is_ascii_digit())?;
+        let (value, unit) = raw.split_at(split);
+        let value: u64 = value.parse().ok()?;
+        match unit {
+            "ms" => Some(Duration::from_millis(value)),
+            "s" => Some(Duration::from_secs(value)),
+            "m" => Some(Duration::from_secs(value * 60)),
+            "h" => Some(Duration::from_secs(value * 3600)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_config {
+    use super::*;
+    use crate::endpoint::AdminState;
+
+    const CONFIG: &str = r#"
+listen = "127.0.0.1:8080"
+deadline = { default = "10s", max = "1m" }
+
+[pool]
+max_connections = 2
+connect_timeout = "1s"
+
+[[services]]
+id = "kyc.Kyc"
+name = "kyc"
+endpoints = ["10.0.0.1:50051", "10.0.0.2:50051"]
+strategy = { kind = "ring_hash", header = "x-user-id", fallback = { kind = "least_request" } }
+health_check = { service = "kyc.Kyc", interval = "5s", timeout = "500ms" }
+circuit_breaker = { trip = { error_rate = { rate = 0.5, min_requests = 20 } } }
+split = { weights = { v1 = 95, v2 = 5 }, sticky = "x-user-id" }
+subsets = [{ header = "x-debug-backend", select = { kind = "address" } }]
+mirror = { service = "kyc.Kyc.write", percent = 5 }
+retry = { max_attempts = 2, retry_on = ["connect_failure", "resource_exhausted"], base_backoff = "10ms" }
+hedge = { methods = ["/kyc.Kyc/get"], delay = "40ms" }
+
+[[services]]
+id = "kyc.Kyc.write"
+name = "kyc-write"
+protocol = "http1"
+endpoints = [{ address = "10.0.1.1:50051", weight = 2, zone = "eu-west-1a" }]
+
+[[routes]]
+exact = "/kyc.Kyc/register"
+service = "kyc.Kyc.write"
+deadline = { max = "2s" }
+"#;
+
+    #[test]
+    fn test_parse() {
+        let config = Config::from_str(CONFIG).unwrap();
+        assert_eq!(config.pool.max_connections, 2);
+        assert_eq!(config.pool.connect_timeout, Duration::from_secs(1));
+        assert_eq!(config.deadline.max, Some(Duration::from_secs(60)));
+        let deadline = config.routes[0].deadline.unwrap();
+        assert_eq!(
+            (deadline.default, deadline.max),
+            (None, Some(Duration::from_secs(2)))
+        );
+        assert_eq!(config.services.len(), 2);
+        let check = config.services[0].health_check.as_ref().unwrap();
+        assert_eq!(check.timeout, Duration::from_millis(500));
+        assert_eq!(check.fall, HealthCheck::default().fall);
+        let split = config.services[0].split.as_ref().unwrap();
+        assert_eq!(split.weights["v2"], 5);
+        assert_eq!(config.services[0].subsets[0].select, Selector::Address);
+        assert_eq!(config.services[0].mirror.as_ref().unwrap().percent, 5.0);
+        let retry = config.services[0].retry.as_ref().unwrap();
+        assert_eq!(retry.base_backoff, Duration::from_millis(10));
+        assert_eq!(retry.max_backoff, RetryPolicy::default().max_backoff);
+        let hedge = config.services[0].hedge.as_ref().unwrap();
+        assert_eq!(hedge.delay, Duration::from_millis(40));
+        assert_eq!(hedge.max_attempts, 2);
+        assert_eq!(config.services[0].protocol, Protocol::Http2);
+        assert_eq!(config.services[1].protocol, Protocol::Http1);
+        let endpoint = config.services[1].endpoints[0].to_endpoint();
+        assert_eq!(endpoint.weight, 2);
+        assert_eq!(endpoint.zone.as_deref(), Some("eu-west-1a"));
+        assert_eq!(endpoint.state, AdminState::Active);
+    }
+
+    #[test]
+    fn test_parse_yaml() {
+        let yaml = r#"
+listen: "127.0.0.1:8080"
+services:
+  - id: kyc.Kyc
+    name: kyc
+    endpoints: ["10.0.0.1:50051"]
+    strategy: { kind: weighted_round_robin, weights: { "10.0.0.1:50051": 3 } }
+    outlier_detection: { base_ejection: 10s }
+"#;
+        let config = Config::parse(yaml, Path::new("yoroi.yaml")).unwrap();
+        let detection = config.services[0].outlier_detection.as_ref().unwrap();
+        assert_eq!(detection.base_ejection, Duration::from_secs(10));
+        assert!(matches!(
+            config.services[0].strategy,
+            StrategyConfig::WeightedRoundRobin { .. }
+        ));
+    }
+
+    #[test]
+    fn test_validation_errors() {
+        let err = Config::from_str(&CONFIG.replace("10.0.1.1:50051", "10.0.1.1")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid config: services[1] (`kyc.Kyc.write`): endpoint `10.0.1.1` must be host:port"
+        );
+
+        let err =
+            Config::from_str(&CONFIG.replace("service = \"kyc.Kyc.write\"", "service = \"nope\""))
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid config: routes[0]: service `nope` is not configured"
+        );
+
+        let err =
+            Config::from_str(&CONFIG.replace("\"/kyc.Kyc/get\"", "\"kyc.Kyc/get\"")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid config: services[0] (`kyc.Kyc`): hedge.methods: `kyc.Kyc/get` must be a path starting with /"
+        );
+
+        let err = Config::from_str(
+            &CONFIG.replace("name = \"kyc\"\n", "name = \"kyc\"\nprotocol = \"http1\"\n"),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid config: services[0] (`kyc.Kyc`): health_check needs protocol http2, checks are gRPC"
+        );
+
+        let err = Config::from_str(&CONFIG.replace("max = \"2s\"", "max = \"0s\"")).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid config: routes[0]: deadline: timeouts must not be zero"
+        );
+
+        assert!(matches!(
+            Config::from_str(&CONFIG.replace("interval = \"5s\"", "interval = \"5 parsecs\"")),
+            Err(ConfigError::Parse(..))
+        ));
+    }
+
+    #[test]
+    fn test_apply_diff() {
+        let registry = ServiceRegistry::new();
+        let router = Router::new();
+        registry.register_service("other".to_string(), "other".to_string(), vec![]);
+
+        let first = Config::from_str(CONFIG).unwrap();
+        first.apply(&registry, &router, None);
+        assert_eq!(registry.get_all_services().len(), 3);
+        assert_eq!(router.route("/kyc.Kyc/register"), "kyc.Kyc.write");
+        let write = registry.get_service("kyc.Kyc.write").unwrap();
+        assert_eq!(write.protocol(), Protocol::Http1);
+
+        let second = Config::from_str(
+            CONFIG
+                .replace("\"10.0.0.1:50051\", ", "")
+                .split("[[services]]\nid = \"kyc.Kyc.write\"")
+                .next()
+                .unwrap(),
+        )
+        .unwrap();
+        second.apply(&registry, &router, Some(&first));
+        let services = registry.get_all_services();
+        assert_eq!(services.len(), 2);
+        assert!(services.contains_key("other"));
+        let endpoints: Vec<_> = services["kyc.Kyc"].endpoints().keys().collect();
+        assert_eq!(endpoints, ["10.0.0.2:50051"]);
+        assert_eq!(router.route("/kyc.Kyc/register"), "kyc.Kyc");
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Body, Bytes, Frame, SizeHint},
+    header::HeaderValue,
+    HeaderMap,
+};
+use serde::Deserialize;
+use tokio::time::{sleep_until, Instant, Sleep};
+
+use crate::error::{BoxError, ProxyError};
+
+pub(crate) const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+/// Units of `grpc-timeout`, finest first, with their length in nanoseconds.
+const UNITS: [(char, u128); 6] = [
+    ('n', 1),
+    ('u', 1_000),
+    ('m', 1_000_000),
+    ('S', 1_000_000_000),
+    ('M', 60_000_000_000),
+    ('H', 3_600_000_000_000),
+];
+
+/// Largest value `grpc-timeout` carries, 8 digits.
+const MAX_VALUE: u128 = 99_999_999;
+
+/// Deadline of the requests of a route. Clients set theirs with the
+/// `grpc-timeout` header, the gateway caps it and forwards what is left of
+/// it to gRPC upstreams. A request past its deadline is cancelled upstream
+/// and answered with `DEADLINE_EXCEEDED`, or `504` for plain HTTP clients.
+///
+/// ```toml
+/// deadline = { default = "5s", max = "30s" }
+/// ```
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadlinePolicy {
+    /// Deadline of requests without `grpc-timeout`, none when unset.
+    #[serde(with = "crate::config::duration::option")]
+    pub default: Option<Duration>,
+    /// Longest deadline a request gets, whatever it asks for.
+    #[serde(with = "crate::config::duration::option")]
+    pub max: Option<Duration>,
+}
+
+impl DeadlinePolicy {
+    /// Time a request with `headers` is given, `None` when it has no
+    /// deadline.
+    pub(crate) fn timeout(&self, headers: &HeaderMap) -> Option<Duration> {
+        let requested = headers.get(GRPC_TIMEOUT).and_then(parse);
+        let timeout = requested.or(self.default);
+        match (timeout, self.max) {
+            (Some(timeout), Some(max)) => Some(timeout.min(max)),
+            (timeout, max) => timeout.or(max),
+        }
+    }
+}
+
+/// Parses a `grpc-timeout` value, e.g. `100m` for 100 milliseconds.
+pub(crate) fn parse(value: &HeaderValue) -> Option<Duration> {
+    let value = value.to_str().ok()?;
+    let unit = value.chars().last()?;
+    let digits = &value[..value.len() - unit.len_utf8()];
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let (_, nanos) = UNITS.iter().find(|(u, _)| *u == unit)?;
+    let value: u64 = digits.parse().ok()?;
+    Some(Duration::from_nanos(value.saturating_mul(*nanos as u64)))
+}
+
+/// `grpc-timeout` value of `timeout`, in the finest unit that fits.
+pub(crate) fn encode(timeout: Duration) -> HeaderValue {
+    let nanos = timeout.as_nanos();
+    let (unit, value) = UNITS
+        .iter()
+        .map(|(unit, length)| (unit, nanos / length))
+        .find(|(_, value)| *value <= MAX_VALUE)
+        .unwrap_or((&'H', MAX_VALUE));
+    // a deadline that is all but gone still has to read as one
+    let value = value.max(1);
+    HeaderValue::from_str(&format!("{}{}", value, unit)).expect("digits and a unit are valid")
+}
+
+/// Response body cut short at the request's deadline. The upstream stream
+/// is dropped, which cancels it. A gRPC client gets `DEADLINE_EXCEEDED` in
+/// trailers, any other client an error ending the response, which aborts
+/// it rather than passing it off as complete.
+pub(crate) struct DeadlineBody {
+    inner: Option<BoxBody<Bytes, hyper::Error>>,
+    sleep: Pin<Box<Sleep>>,
+    grpc: bool,
+}
+
+impl DeadlineBody {
+    pub(crate) fn new(inner: BoxBody<Bytes, hyper::Error>, deadline: Instant, grpc: bool) -> Self {
+        DeadlineBody {
+            inner: Some(inner),
+            sleep: Box::pin(sleep_until(deadline)),
+            grpc,
+        }
+    }
+}
+
+impl Body for DeadlineBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let inner = match &mut this.inner {
+            Some(inner) => inner,
+            None => return Poll::Ready(None),
+        };
+        if let Poll::Ready(frame) = Pin::new(inner).poll_frame(cx) {
+            return Poll::Ready(frame.map(|frame| frame.map_err(BoxError::from)));
+        }
+        if this.sleep.as_mut().poll(cx).is_pending() {
+            return Poll::Pending;
+        }
+        this.inner = None;
+        if !this.grpc {
+            return Poll::Ready(Some(Err(ProxyError::DeadlineExceeded.into())));
+        }
+        let mut trailers = HeaderMap::new();
+        let resp = ProxyError::DeadlineExceeded.into_response();
+        for name in ["grpc-status", "grpc-message"] {
+            if let Some(value) = resp.headers().get(name) {
+                trailers.insert(name, value.clone());
+            }
+        }
+        Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner
+            .as_ref()
+            .is_none_or(|inner| inner.is_end_stream())
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner
+            .as_ref()
+            .map_or_else(|| SizeHint::with_exact(0), |inner| inner.size_hint())
+    }
+}
+
+#[cfg(test)]
+mod tests_deadline {
+    use http_body_util::{BodyExt, StreamBody};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_and_encode() {
+        let parsed = |value| parse(&HeaderValue::from_static(value));
+        assert_eq!(parsed("100m"), Some(Duration::from_millis(100)));
+        assert_eq!(parsed("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parsed("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parsed("100"), None);
+        assert_eq!(parsed("m"), None);
+        assert_eq!(parsed("123456789n"), None);
+        assert_eq!(parsed("-1S"), None);
+
+        assert_eq!(encode(Duration::from_millis(100)), "100000u");
+        assert_eq!(encode(Duration::from_secs(5)), "5000000u");
+        assert_eq!(encode(Duration::from_secs(86_400)), "86400000m");
+        assert_eq!(encode(Duration::ZERO), "1n");
+    }
+
+    #[test]
+    fn test_timeout() {
+        let policy = DeadlinePolicy {
+            default: Some(Duration::from_secs(1)),
+            max: Some(Duration::from_secs(5)),
+        };
+        let mut headers = HeaderMap::new();
+        assert_eq!(policy.timeout(&headers), Some(Duration::from_secs(1)));
+        headers.insert(GRPC_TIMEOUT, HeaderValue::from_static("2S"));
+        assert_eq!(policy.timeout(&headers), Some(Duration::from_secs(2)));
+        headers.insert(GRPC_TIMEOUT, HeaderValue::from_static("1M"));
+        assert_eq!(policy.timeout(&headers), Some(Duration::from_secs(5)));
+        assert_eq!(DeadlinePolicy::default().timeout(&HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_deadline_body() {
+        let (tx, rx) = mpsc::channel::<Result<Frame<Bytes>, hyper::Error>>(1);
+        let inner = StreamBody::new(ReceiverStream::new(rx)).boxed();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let mut body = DeadlineBody::new(inner, deadline, true);
+        tx.send(Ok(Frame::data(Bytes::from("pong")))).await.unwrap();
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.into_data().unwrap(), "pong");
+        let frame = body.frame().await.unwrap().unwrap();
+        assert_eq!(frame.trailers_ref().unwrap()["grpc-status"], "4");
+        assert!(body.frame().await.is_none());
+        assert!(tx.is_closed());
+
+        // other clients get no trailers they'd take for a complete response
+        let (tx, rx) = mpsc::channel::<Result<Frame<Bytes>, hyper::Error>>(1);
+        let inner = StreamBody::new(ReceiverStream::new(rx)).boxed();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let mut body = DeadlineBody::new(inner, deadline, false);
+        assert!(body.frame().await.unwrap().is_err());
+        assert!(body.frame().await.is_none());
+        assert!(tx.is_closed());
+    }
+}
@@ -0,0 +1,59 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Whether an endpoint takes traffic, set by operators.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AdminState {
+    #[default]
+    Active,
+    /// Gets no new requests, the ones in flight complete. Still health
+    /// checked so it can be put back into rotation.
+    Draining,
+    /// Gets no requests and is not health checked.
+    Disabled,
+}
+
+/// Upstream address of a service along with what routing knows about it.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Endpoint {
+    /// `host:port` requests are forwarded to.
+    pub address: String,
+    /// Relative share of traffic under weighted balancing, 0 takes none.
+    pub weight: u32,
+    /// Availability zone the endpoint runs in.
+    pub zone: Option<String>,
+    /// Version of the service the endpoint runs.
+    pub version: Option<String>,
+    pub labels: BTreeMap<String, String>,
+    pub state: AdminState,
+}
+
+impl Default for Endpoint {
+    fn default() -> Self {
+        Endpoint {
+            address: String::new(),
+            weight: 1,
+            zone: None,
+            version: None,
+            labels: BTreeMap::new(),
+            state: AdminState::Active,
+        }
+    }
+}
+
+impl Endpoint {
+    pub fn new(address: impl Into<String>) -> Self {
+        Endpoint {
+            address: address.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Whether new requests may be sent to the endpoint.
+    pub fn is_active(&self) -> bool {
+        self.state == AdminState::Active
+    }
+}
@@ -0,0 +1,145 @@
+use std::{error::Error, fmt};
+
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use hyper::{
+    body::Bytes,
+    header::{HeaderValue, CONTENT_TYPE},
+    Response, StatusCode,
+};
+use tonic::{Code, Status};
+
+pub(crate) type BoxError = Box<dyn Error + Send + Sync>;
+
+/// Errors the gateway answers a request with instead of proxying it.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// No service is registered under the id the request path routes to.
+    UnknownService(String),
+    /// The service has no endpoint that is healthy, not ejected and whose
+    /// circuit breaker is closed.
+    NoEndpoint(String),
+    Connect {
+        endpoint: String,
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// The upstream connection failed while the request was in flight.
+    Upstream(hyper::Error),
+    DeadlineExceeded,
+}
+
+impl ProxyError {
+    pub fn code(&self) -> Code {
+        match self {
+            ProxyError::UnknownService(_) => Code::Unimplemented,
+            ProxyError::NoEndpoint(_) => Code::Unavailable,
+            ProxyError::Connect { .. } => Code::Unavailable,
+            ProxyError::Upstream(_) => Code::Unavailable,
+            ProxyError::DeadlineExceeded => Code::DeadlineExceeded,
+        }
+    }
+
+    /// HTTP status plain HTTP clients are answered with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ProxyError::UnknownService(_) => StatusCode::NOT_FOUND,
+            ProxyError::NoEndpoint(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ProxyError::Connect { .. } => StatusCode::BAD_GATEWAY,
+            ProxyError::Upstream(_) => StatusCode::BAD_GATEWAY,
+            ProxyError::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        }
+    }
+
+    /// Plain text response with the error's HTTP status, for clients that
+    /// don't speak gRPC.
+    pub fn into_http_response(self) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let status = self.status();
+        let mut resp = Response::new(
+            Full::new(Bytes::from(self.to_string()))
+                .map_err(|never| match never {})
+                .boxed(),
+        );
+        *resp.status_mut() = status;
+        resp.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        resp
+    }
+
+    /// Trailers-only gRPC response carrying the error's status code and
+    /// message.
+    pub fn into_response(self) -> Response<BoxBody<Bytes, hyper::Error>> {
+        let status = Status::new(self.code(), self.to_string());
+        let mut resp = Response::new(
+            Empty::<Bytes>::new()
+                .map_err(|never| match never {})
+                .boxed(),
+        );
+        let headers = resp.headers_mut();
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/grpc"));
+        if status.add_header(headers).is_err() {
+            // only the message can fail to encode, the status is enough
+            headers.insert("grpc-status", HeaderValue::from(status.code() as i32));
+        }
+        resp
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProxyError::UnknownService(id) => write!(f, "unknown service: {}", id),
+            ProxyError::NoEndpoint(id) => write!(f, "no endpoint available for: {}", id),
+            ProxyError::Connect { endpoint, source } => {
+                write!(f, "failed to connect to {}: {}", endpoint, source)
+            }
+            ProxyError::Upstream(err) => write!(f, "upstream error: {}", err),
+            ProxyError::DeadlineExceeded => write!(f, "deadline exceeded"),
+        }
+    }
+}
+
+impl Error for ProxyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ProxyError::Connect { source, .. } => Some(source.as_ref()),
+            ProxyError::Upstream(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<hyper::Error> for ProxyError {
+    fn from(err: hyper::Error) -> Self {
+        ProxyError::Upstream(err)
+    }
+}
+
+#[cfg(test)]
+mod tests_error {
+    use super::*;
+
+    #[test]
+    fn test_into_response() {
+        let resp = ProxyError::UnknownService("kyc.Kyc".to_string()).into_response();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["content-type"], "application/grpc");
+        assert_eq!(resp.headers()["grpc-status"], "12");
+        assert_eq!(
+            resp.headers()["grpc-message"],
+            "unknown%20service:%20kyc.Kyc"
+        );
+
+        let resp = ProxyError::DeadlineExceeded.into_response();
+        assert_eq!(resp.headers()["grpc-status"], "4");
+    }
+
+    #[test]
+    fn test_into_http_response() {
+        let resp = ProxyError::NoEndpoint("users".to_string()).into_http_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers()["content-type"], "text/plain");
+        assert_eq!(
+            ProxyError::DeadlineExceeded.status(),
+            StatusCode::GATEWAY_TIMEOUT
+        );
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use hyper::Uri;
+use hyper_util::rt::TokioIo;
+use serde::Deserialize;
+use tokio::time::{interval, timeout, Instant, MissedTickBehavior};
+use tonic::{codegen::Service, transport::Endpoint};
+use tonic_health::pb::{
+    health_check_response::ServingStatus, health_client::HealthClient, HealthCheckRequest,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    endpoint::AdminState,
+    protocol::Protocol,
+    registry::ServiceRegistry,
+    tls::{self, ClientTls, Io},
+};
+
+/// How often the checker looks for endpoints that are due for a check.
+const TICK: Duration = Duration::from_millis(250);
+
+/// Active `grpc.health.v1.Health/Check` probing of a service's endpoints.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthCheck {
+    /// Service name sent in the check request, empty checks the whole server.
+    pub service: String,
+    #[serde(with = "crate::config::duration")]
+    pub interval: Duration,
+    #[serde(with = "crate::config::duration")]
+    pub timeout: Duration,
+    /// Consecutive passing checks to mark an unhealthy endpoint healthy.
+    pub rise: u32,
+    /// Consecutive failing checks to mark a healthy endpoint unhealthy.
+    pub fall: u32,
+}
+
+impl Default for HealthCheck {
+    fn default() -> Self {
+        HealthCheck {
+            service: String::new(),
+            interval: Duration::from_secs(10),
+            timeout: Duration::from_secs(1),
+            rise: 2,
+            fall: 3,
+        }
+    }
+}
+
+/// Health of an endpoint, endpoints start out healthy until checks say
+/// otherwise.
+#[derive(Debug)]
+pub(crate) struct HealthState {
+    healthy: bool,
+    successes: u32,
+    failures: u32,
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        HealthState {
+            healthy: true,
+            successes: 0,
+            failures: 0,
+        }
+    }
+}
+
+impl HealthState {
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.healthy
+    }
+
+    /// Records a check result, returns true when the endpoint flipped state.
+    pub(crate) fn record(&mut self, passed: bool, check: &HealthCheck) -> bool {
+        if passed {
+            self.successes += 1;
+            self.failures = 0;
+            if !self.healthy && self.successes >= check.rise {
+                self.healthy = true;
+                return true;
+            }
+        } else {
+            self.failures += 1;
+            self.successes = 0;
+            if self.healthy && self.failures >= check.fall {
+                self.healthy = false;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Checks the endpoints of every service with a health check configured,
+/// each at its service's interval. Runs until the task is aborted.
+pub(crate) async fn run(registry: ServiceRegistry) {
+    let mut next_check: HashMap<(String, String), Instant> = HashMap::new();
+    let mut ticker = interval(TICK);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let now = Instant::now();
+        let mut checked = HashSet::new();
+        for (id, service) in registry.get_all_services() {
+            let check = match service.health_check() {
+                Some(check) => check.clone(),
+                None => continue,
+            };
+            for (endpoint, metadata) in service.endpoints() {
+                if metadata.state == AdminState::Disabled {
+                    continue;
+                }
+                let key = (id.clone(), endpoint.clone());
+                if next_check.get(&key).is_none_or(|at| *at <= now) {
+                    next_check.insert(key.clone(), now + check.interval);
+                    let stats = service.stats().get(endpoint);
+                    let (id, endpoint, check) = (id.clone(), endpoint.clone(), check.clone());
+                    let tls = service.upstream_tls().cloned();
+                    tokio::spawn(async move {
+                        let passed = probe(&endpoint, &check, tls).await;
+                        if stats.record_health_check(passed, &check) {
+                            if passed {
+                                info!("{}: endpoint {} is healthy", id, endpoint);
+                            } else {
+                                warn!("{}: endpoint {} is unhealthy", id, endpoint);
+                            }
+                        }
+                    });
+                }
+                checked.insert(key);
+            }
+        }
+        next_check.retain(|key, _| checked.contains(key));
+    }
+}
+
+/// Calls `grpc.health.v1.Health/Check` on `endpoint`, passes only when it
+/// answers `SERVING` within the check timeout. The endpoint is connected to
+/// over `tls` like the pool does.
+pub(crate) async fn probe(
+    endpoint: &str,
+    check: &HealthCheck,
+    tls: Option<Arc<ClientTls>>,
+) -> bool {
+    let request = async {
+        let connector = Connector {
+            endpoint: endpoint.to_string(),
+            tls,
+        };
+        let channel = Endpoint::from_shared(format!("http://{}", endpoint))?
+            .connect_timeout(check.timeout)
+            .connect_with_connector(connector)
+            .await?;
+        let response = HealthClient::new(channel)
+            .check(HealthCheckRequest {
+                service: check.service.clone(),
+            })
+            .await?;
+        Ok::<_, Box<dyn std::error::Error + Send + Sync>>(response.into_inner().status())
+    };
+    match timeout(check.timeout, request).await {
+        Ok(Ok(ServingStatus::Serving)) => true,
+        Ok(Ok(status)) => {
+            debug!("health check of {}: {}", endpoint, status.as_str_name());
+            false
+        }
+        Ok(Err(err)) => {
+            debug!("health check of {} failed: {}", endpoint, err);
+            false
+        }
+        Err(_) => {
+            debug!("health check of {} timed out", endpoint);
+            false
+        }
+    }
+}
+
+/// Connector of the health check channels, with the TLS of the pool.
+struct Connector {
+    endpoint: String,
+    tls: Option<Arc<ClientTls>>,
+}
+
+impl Service<Uri> for Connector {
+    type Response = TokioIo<Box<dyn Io>>;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        let (endpoint, tls) = (self.endpoint.clone(), self.tls.clone());
+        Box::pin(async move {
+            let io = tls::connect(&endpoint, tls.as_deref(), Protocol::Http2).await?;
+            Ok(TokioIo::new(io))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests_health {
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    use super::*;
+
+    #[test]
+    fn test_rise_fall() {
+        let check = HealthCheck::default();
+        let mut state = HealthState::default();
+        assert!(!state.record(false, &check));
+        assert!(!state.record(false, &check));
+        assert!(state.record(false, &check));
+        assert!(!state.is_healthy());
+
+        assert!(!state.record(true, &check));
+        assert!(state.record(true, &check));
+        assert!(state.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        let (mut reporter, health) = tonic_health::server::health_reporter();
+        reporter
+            .set_service_status("kyc.Kyc", tonic_health::ServingStatus::NotServing)
+            .await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(health)
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+
+        let endpoint = addr.to_string();
+        let mut check = HealthCheck::default();
+        assert!(probe(&endpoint, &check, None).await);
+
+        check.service = "kyc.Kyc".to_string();
+        assert!(!probe(&endpoint, &check, None).await);
+
+        assert!(!probe("127.0.0.1:1", &check, None).await);
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+/// Hedging of idempotent methods of a service: when an attempt hasn't been
+/// answered after `delay`, the request is also sent to another endpoint.
+/// The first answer wins and the other attempts are cancelled.
+///
+/// ```toml
+/// hedge = { methods = ["/kyc.Kyc/get", "/kyc.Kyc/list"], delay = "40ms" }
+/// ```
+///
+/// The delay is best set around the methods' p95 latency, so only the slow
+/// tail is sent twice.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HedgePolicy {
+    /// Paths of the methods hedged, e.g. `/kyc.Kyc/get`. Only methods safe
+    /// to run twice belong here.
+    pub methods: Vec<String>,
+    #[serde(with = "crate::config::duration")]
+    pub delay: Duration,
+    /// Attempts in flight at most, the first one included.
+    pub max_attempts: u32,
+    /// Request bodies larger than this are never hedged.
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for HedgePolicy {
+    fn default() -> Self {
+        HedgePolicy {
+            methods: vec![],
+            delay: Duration::from_millis(50),
+            max_attempts: 2,
+            max_buffered_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl HedgePolicy {
+    /// Whether requests to `path` are hedged.
+    pub(crate) fn applies(&self, path: &str) -> bool {
+        self.methods.iter().any(|method| method == path)
+    }
+}
+
+/// Counters of the hedged attempts of a service.
+#[derive(Debug, Default)]
+pub struct HedgeStats {
+    hedges: AtomicU64,
+    wins: AtomicU64,
+}
+
+impl HedgeStats {
+    /// Hedged attempts sent.
+    pub fn hedges(&self) -> u64 {
+        self.hedges.load(Ordering::Relaxed)
+    }
+
+    /// Requests answered by a hedged attempt rather than the first one.
+    pub fn wins(&self) -> u64 {
+        self.wins.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record_hedge(&self) {
+        self.hedges.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_win(&self) {
+        self.wins.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests_hedge {
+    use super::*;
+
+    #[test]
+    fn test_applies() {
+        let policy = HedgePolicy {
+            methods: vec!["/kyc.Kyc/get".to_string()],
+            ..Default::default()
+        };
+        assert!(policy.applies("/kyc.Kyc/get"));
+        assert!(!policy.applies("/kyc.Kyc/getAll"));
+        assert!(!policy.applies("/kyc.Kyc/register"));
+    }
+}
@@ -1,21 +1,151 @@
-use std::{collections::HashSet, error::Error};
+use std::{collections::HashMap, error::Error, path::PathBuf, sync::Arc};
 
+use backend::{CacheBackend, RegistryBackend};
+use balancer::LoadBalancer;
+use breaker::CircuitBreaker;
+use config::{Config, ConfigError};
+use endpoint::Endpoint;
+use health::HealthCheck;
+use hedge::{HedgePolicy, HedgeStats};
+use mirror::{Mirror, MirrorStats};
+use outlier::OutlierDetection;
+use pool::{ConnectionPool, PoolConfig};
+use protocol::Protocol;
 use registry::ServiceRegistry;
+use retry::{RetryBudget, RetryPolicy};
+use router::Router;
+use split::TrafficSplit;
+use stats::StatsTable;
+use subset::SubsetRule;
+use tls::{CertResolver, ClientTls, TlsConfig};
 
+pub mod admin;
+pub mod backend;
+pub mod balancer;
+mod body;
+pub mod breaker;
+pub mod config;
+pub mod deadline;
+pub mod endpoint;
+pub mod error;
+pub mod health;
+pub mod hedge;
+pub mod mirror;
+pub mod outlier;
+pub mod pool;
+pub mod protocol;
+pub mod registration;
 mod registry;
+pub mod retry;
+pub mod router;
 pub mod server;
+pub mod split;
+pub mod stats;
+pub mod subset;
+pub mod tls;
+mod upstream;
 pub mod utils;
+pub mod watch;
 
 #[derive(Clone, Debug)]
 pub struct MicroService {
     name: String,
-    endpoints: HashSet<String>,
+    endpoints: HashMap<String, Endpoint>,
+    balancer: Arc<dyn LoadBalancer>,
+    stats: StatsTable,
+    health_check: Option<HealthCheck>,
+    outlier_detection: Option<OutlierDetection>,
+    circuit_breaker: Option<CircuitBreaker>,
+    traffic_split: Option<TrafficSplit>,
+    subset_rules: Vec<SubsetRule>,
+    mirror: Option<Mirror>,
+    mirror_stats: Arc<MirrorStats>,
+    retry_policy: Option<RetryPolicy>,
+    retry_budget: Arc<RetryBudget>,
+    hedge_policy: Option<HedgePolicy>,
+    hedge_stats: Arc<HedgeStats>,
+    upstream_tls: Option<Arc<ClientTls>>,
+    protocol: Protocol,
+}
+
+impl MicroService {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Endpoints of the service by address.
+    pub fn endpoints(&self) -> &HashMap<String, Endpoint> {
+        &self.endpoints
+    }
+
+    pub fn endpoint(&self, address: &str) -> Option<&Endpoint> {
+        self.endpoints.get(address)
+    }
+
+    pub fn stats(&self) -> &StatsTable {
+        &self.stats
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
+
+    pub fn outlier_detection(&self) -> Option<&OutlierDetection> {
+        self.outlier_detection.as_ref()
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.circuit_breaker.as_ref()
+    }
+
+    pub fn traffic_split(&self) -> Option<&TrafficSplit> {
+        self.traffic_split.as_ref()
+    }
+
+    pub fn subset_rules(&self) -> &[SubsetRule] {
+        &self.subset_rules
+    }
+
+    pub fn mirror(&self) -> Option<&Mirror> {
+        self.mirror.as_ref()
+    }
+
+    pub fn mirror_stats(&self) -> &MirrorStats {
+        &self.mirror_stats
+    }
+
+    pub fn retry_policy(&self) -> Option<&RetryPolicy> {
+        self.retry_policy.as_ref()
+    }
+
+    pub fn retry_budget(&self) -> &RetryBudget {
+        &self.retry_budget
+    }
+
+    pub fn hedge_policy(&self) -> Option<&HedgePolicy> {
+        self.hedge_policy.as_ref()
+    }
+
+    pub fn hedge_stats(&self) -> &HedgeStats {
+        &self.hedge_stats
+    }
+
+    pub(crate) fn upstream_tls(&self) -> Option<&Arc<ClientTls>> {
+        self.upstream_tls.as_ref()
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
 }
 
 #[derive(Clone, Default)]
 pub struct Yoroi {
     address: String,
     server: server::Server,
+    config: Option<(PathBuf, Config)>,
+    admin_address: Option<String>,
+    registration_address: Option<String>,
 }
 
 impl Yoroi {
@@ -23,19 +153,95 @@ impl Yoroi {
         Yoroi {
             address,
             server: server::Server::default(),
+            config: None,
+            admin_address: None,
+            registration_address: None,
+        }
+    }
+
+    /// Loads listeners, services and routes from the config file at `path`,
+    /// the file is watched and reloaded while the daemon runs.
+    pub fn from_config_file(path: impl Into<PathBuf>) -> Result<Self, ConfigError> {
+        let path = path.into();
+        let config = Config::load(&path)?;
+        let mut yr = Yoroi::new(config.listen.clone());
+        yr.set_pool_config(config.pool.clone());
+        yr.set_tls(config.tls.clone())?;
+        yr.set_admin_address(config.admin.clone());
+        yr.set_registration_address(config.registration.clone());
+        if let Some(shared) = &config.shared_registry {
+            let cache = cache::redis::RedisCache::new(shared.redis.clone())
+                .map_err(|err| ConfigError::Invalid(format!("shared_registry: {}", err)))?;
+            yr.set_registry_backend(Arc::new(CacheBackend::new(cache, &shared.prefix)));
         }
+        config.apply(&yr.server.registry, &yr.server.router, None);
+        yr.config = Some((path, config));
+        Ok(yr)
     }
 
     pub fn registry(&mut self) -> &mut ServiceRegistry {
         &mut self.server.registry
     }
 
+    pub fn router(&mut self) -> &mut Router {
+        &mut self.server.router
+    }
+
+    pub fn set_pool_config(&mut self, config: PoolConfig) {
+        self.server.pool = ConnectionPool::new(config);
+    }
+
+    /// Terminates TLS on the listener with the certificates of `config`,
+    /// `None` serves plaintext HTTP/1.1 or HTTP/2.
+    pub fn set_tls(&mut self, config: Option<TlsConfig>) -> Result<(), ConfigError> {
+        self.server.tls = config.map(CertResolver::new).transpose()?.map(Arc::new);
+        Ok(())
+    }
+
+    /// Keeps the registrations in `backend`, replacing the registry and any
+    /// service registered so far.
+    pub fn set_registry_backend(&mut self, backend: Arc<dyn RegistryBackend>) {
+        self.server.registry = ServiceRegistry::with_backend(backend);
+    }
+
+    /// Serves the REST admin API (see [`admin::router`]) on `address` while
+    /// the daemon runs, `None` disables it. The API is not authenticated,
+    /// `address` should be a loopback or private one.
+    pub fn set_admin_address(&mut self, address: Option<String>) {
+        self.admin_address = address;
+    }
+
+    /// Serves the `yoroi.registry.v1` self-registration gRPC API on `address`
+    /// while the daemon runs, `None` disables it.
+    pub fn set_registration_address(&mut self, address: Option<String>) {
+        self.registration_address = address;
+    }
+
     pub fn handler(&self) -> server::ShutdownHandler {
         self.server.handler()
     }
 
     pub async fn start_daemon(&self) -> Result<(), Box<dyn Error>> {
-        self.server.serve(self.address.as_str()).await
+        let watcher = self.config.clone().map(|(path, config)| {
+            tokio::spawn(config::watch(
+                path,
+                config,
+                self.server.registry.clone(),
+                self.server.router.clone(),
+            ))
+        });
+        let admin = self
+            .admin_address
+            .clone()
+            .map(|address| tokio::spawn(admin::serve(address, self.server.registry.clone())));
+        let registration = self.registration_address.clone().map(|address| {
+            tokio::spawn(registration::serve(address, self.server.registry.clone()))
+        });
+        let result = self.server.serve(self.address.as_str()).await;
+        for task in [watcher, admin, registration].into_iter().flatten() {
+            task.abort();
+        }
+        result
     }
 }
 
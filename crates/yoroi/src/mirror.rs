@@ -0,0 +1,479 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Body, Bytes, Frame, SizeHint},
+    Request,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot, OwnedSemaphorePermit, Semaphore},
+    time::{timeout_at, Instant},
+};
+use tracing::debug;
+
+use crate::{pool::ConnectionPool, protocol, registry::ServiceRegistry, upstream};
+
+/// Body frames a mirrored request may fall behind its original by before
+/// the mirror is given up, so a slow shadow never holds up the original.
+const MIRROR_BUFFER: usize = 64;
+
+/// Copies of a service's requests in flight at once, more are dropped
+/// rather than piling up behind a slow shadow.
+pub const MAX_IN_FLIGHT: usize = 128;
+
+type BoxRequest = Request<BoxBody<Bytes, hyper::Error>>;
+
+/// Copies a share of a service's requests to a shadow service, e.g. to try
+/// a rewritten backend against production traffic. The shadow's responses
+/// are discarded and it never adds latency to the original requests.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mirror {
+    /// Id of the service requests are copied to.
+    pub service: String,
+    /// Percentage of requests copied, from 0 to 100.
+    #[serde(default = "default_percent")]
+    pub percent: f64,
+    /// How long a copy may take, it is given up on sooner when the original
+    /// request's deadline comes first.
+    #[serde(default = "default_timeout", with = "crate::config::duration")]
+    pub timeout: Duration,
+}
+
+fn default_percent() -> f64 {
+    100.0
+}
+
+fn default_timeout() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl Mirror {
+    /// Whether the next request is copied.
+    pub(crate) fn sample(&self) -> bool {
+        rand::random::<f64>() * 100.0 < self.percent
+    }
+}
+
+/// Counters of the requests a service mirrored, kept apart from the stats
+/// of its own endpoints.
+#[derive(Debug)]
+pub struct MirrorStats {
+    requests: AtomicU64,
+    failures: AtomicU64,
+    dropped: AtomicU64,
+    permits: Arc<Semaphore>,
+}
+
+impl Default for MirrorStats {
+    fn default() -> Self {
+        MirrorStats {
+            requests: AtomicU64::default(),
+            failures: AtomicU64::default(),
+            dropped: AtomicU64::default(),
+            permits: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        }
+    }
+}
+
+impl MirrorStats {
+    /// Copies the shadow answered, successfully or not.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Copies the shadow failed, like a failed request would be reported.
+    pub fn failures(&self) -> u64 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    /// Copies given up on, for lack of a shadow endpoint, because the shadow
+    /// fell behind the original request or too many copies were in flight.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Copies sent and not answered yet.
+    pub fn in_flight(&self) -> usize {
+        MAX_IN_FLIGHT - self.permits.available_permits()
+    }
+
+    /// Takes a slot for a copy, held until the copy is done. Without a free
+    /// slot the copy is counted as dropped.
+    pub(crate) fn admit(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = self.permits.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            self.record_dropped();
+        }
+        permit
+    }
+
+    fn record(&self, success: bool) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.failures.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn record_dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Body of a mirrored request that hands a copy of every frame on to the
+/// mirror, without ever waiting for it.
+pub(crate) struct TeeBody {
+    inner: BoxBody<Bytes, hyper::Error>,
+    tx: Option<mpsc::Sender<Frame<Bytes>>>,
+    abort: Option<oneshot::Sender<()>>,
+}
+
+impl TeeBody {
+    /// The copy is complete.
+    fn finish(&mut self) {
+        self.tx.take();
+        self.abort.take();
+    }
+
+    /// The copy can't be completed, the mirror is cancelled rather than
+    /// sent a truncated body.
+    fn give_up(&mut self) {
+        if let Some(abort) = self.abort.take() {
+            let _ = abort.send(());
+        }
+        self.tx.take();
+    }
+}
+
+impl Body for TeeBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let polled = Pin::new(&mut this.inner).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                let copy = match (frame.data_ref(), frame.trailers_ref()) {
+                    (Some(data), _) => Some(Frame::data(data.clone())),
+                    (_, Some(trailers)) => Some(Frame::trailers(trailers.clone())),
+                    _ => None,
+                };
+                let sent = match (&this.tx, copy) {
+                    (Some(tx), Some(copy)) => tx.try_send(copy).is_ok(),
+                    _ => true,
+                };
+                if !sent {
+                    this.give_up();
+                } else if this.inner.is_end_stream() {
+                    this.finish();
+                }
+            }
+            Poll::Ready(Some(Err(_))) => this.give_up(),
+            Poll::Ready(None) => this.finish(),
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for TeeBody {
+    fn drop(&mut self) {
+        // the original may be dropped as soon as it has seen its last frame
+        if self.inner.is_end_stream() {
+            self.finish();
+        } else {
+            self.give_up();
+        }
+    }
+}
+
+/// Body of the copy of a request, fed by its `TeeBody`.
+pub(crate) struct MirrorBody(mpsc::Receiver<Frame<Bytes>>);
+
+impl Body for MirrorBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        self.get_mut().0.poll_recv(cx).map(|frame| frame.map(Ok))
+    }
+}
+
+/// Splits `req` into the original, whose body is teed, and its copy. The
+/// receiver fires when the copy has to be cancelled.
+pub(crate) fn tee(req: BoxRequest) -> (BoxRequest, BoxRequest, oneshot::Receiver<()>) {
+    let (tx, rx) = mpsc::channel(MIRROR_BUFFER);
+    let (abort, aborted) = oneshot::channel();
+    let mut copy = Request::new(MirrorBody(rx).boxed());
+    *copy.method_mut() = req.method().clone();
+    *copy.uri_mut() = req.uri().clone();
+    *copy.version_mut() = req.version();
+    *copy.headers_mut() = req.headers().clone();
+    let req = req.map(|inner| {
+        let mut tee = TeeBody {
+            inner,
+            tx: Some(tx),
+            abort: Some(abort),
+        };
+        if tee.inner.is_end_stream() {
+            tee.finish();
+        }
+        tee.boxed()
+    });
+    (req, copy, aborted)
+}
+
+/// Sends `req`, a copy of a request, to the `mirror`'s service and discards
+/// the response. The shadow endpoint's stats see the copy like any other
+/// request, the outcome is counted in `stats`. The copy is given up on at
+/// the original's `deadline` or after the mirror's timeout.
+pub(crate) async fn send(
+    registry: ServiceRegistry,
+    pool: ConnectionPool,
+    mirror: Mirror,
+    stats: Arc<MirrorStats>,
+    req: BoxRequest,
+    aborted: oneshot::Receiver<()>,
+    deadline: Option<Instant>,
+) {
+    let limit = Instant::now() + mirror.timeout;
+    let deadline = deadline.map_or(limit, |deadline| deadline.min(limit));
+    let upstream = match registry.resolve(&mirror.service, req.headers()) {
+        Some(upstream) => upstream,
+        None => {
+            debug!("mirror: no endpoint of {} available", mirror.service);
+            stats.record_dropped();
+            return;
+        }
+    };
+    let _guard = upstream.stats.start();
+    let mut sender = match pool
+        .get(&upstream.endpoint, upstream.tls.as_ref(), upstream.protocol)
+        .await
+    {
+        Ok(sender) => sender,
+        Err(err) => {
+            debug!(
+                "mirror: failed to connect to {}: {}",
+                upstream.endpoint, err
+            );
+            upstream.report(false);
+            stats.record_dropped();
+            return;
+        }
+    };
+    // the copy is in the form the client sent, the shadow may speak another protocol
+    let (mut parts, body) = req.into_parts();
+    protocol::adapt(&mut parts, upstream.protocol);
+    let req = Request::from_parts(parts, body);
+    let start = Instant::now();
+    let exchange = async {
+        let resp = sender.send_request(req).await?;
+        upstream.stats.observe_latency(start.elapsed());
+        let mut outcome = upstream::response_outcome(resp.status(), resp.headers());
+        let mut body = resp.into_body();
+        while let Some(frame) = body.frame().await {
+            if let Some(trailers) = frame?.trailers_ref() {
+                outcome.get_or_insert(upstream::trailers_outcome(trailers));
+            }
+        }
+        Ok::<_, hyper::Error>(outcome.unwrap_or(true))
+    };
+    tokio::select! {
+        result = timeout_at(deadline, exchange) => match result {
+            Ok(result) => {
+                let success = matches!(result, Ok(true));
+                upstream.report(success);
+                stats.record(success);
+            }
+            // like a request past its deadline, not held against the endpoint
+            Err(_) => {
+                debug!("mirror: copy to {} timed out", upstream.endpoint);
+                stats.record(false);
+            }
+        },
+        Ok(()) = aborted => {
+            debug!("mirror: copy to {} cancelled", upstream.endpoint);
+            stats.record_dropped();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_mirror {
+    use http_body_util::{Full, StreamBody};
+    use hyper::{server::conn::http2, service::service_fn, Response};
+    use hyper_util::rt::TokioIo;
+    use tokio::{net::TcpListener, sync::mpsc::UnboundedSender};
+
+    use super::*;
+    use crate::server::TokioExecutor;
+
+    /// HTTP/2 server handing the body of every request it gets to `bodies`.
+    async fn serve_shadow(bodies: UnboundedSender<Bytes>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let bodies = bodies.clone();
+                let service = service_fn(move |req: Request<hyper::body::Incoming>| {
+                    let bodies = bodies.clone();
+                    async move {
+                        let body = req.into_body().collect().await?.to_bytes();
+                        let _ = bodies.send(body);
+                        Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from("pong"))))
+                    }
+                });
+                tokio::spawn(
+                    http2::Builder::new(TokioExecutor)
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        addr.to_string()
+    }
+
+    fn request(body: BoxBody<Bytes, hyper::Error>) -> BoxRequest {
+        Request::builder()
+            .uri("http://kyc/kyc.Kyc/verify")
+            .body(body)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_mirror() {
+        let (bodies, mut received) = mpsc::unbounded_channel();
+        let registry = ServiceRegistry::new();
+        registry.register_service(
+            "kyc.Kyc.shadow".to_string(),
+            "kyc-v2".to_string(),
+            vec![serve_shadow(bodies).await],
+        );
+        let mirror = Mirror {
+            service: "kyc.Kyc.shadow".to_string(),
+            percent: 100.0,
+            timeout: default_timeout(),
+        };
+        let stats = Arc::new(MirrorStats::default());
+        let body = Full::new(Bytes::from("ping"))
+            .map_err(|never| match never {})
+            .boxed();
+        let (req, copy, aborted) = tee(request(body));
+        let copied = tokio::spawn(send(
+            registry.clone(),
+            ConnectionPool::default(),
+            mirror.clone(),
+            stats.clone(),
+            copy,
+            aborted,
+            None,
+        ));
+        let original = req.into_body().collect().await.unwrap().to_bytes();
+        assert_eq!(original, Bytes::from("ping"));
+        copied.await.unwrap();
+        assert_eq!(received.recv().await.unwrap(), Bytes::from("ping"));
+        assert_eq!((stats.requests(), stats.failures()), (1, 0));
+
+        // an original dropped halfway cancels its copy
+        let (tx, rx) = mpsc::channel::<Result<Frame<Bytes>, hyper::Error>>(1);
+        let body = StreamBody::new(tokio_stream::wrappers::ReceiverStream::new(rx)).boxed();
+        let (req, copy, aborted) = tee(request(body));
+        let copied = tokio::spawn(send(
+            registry,
+            ConnectionPool::default(),
+            mirror,
+            stats.clone(),
+            copy,
+            aborted,
+            None,
+        ));
+        tx.send(Ok(Frame::data(Bytes::from("pi")))).await.unwrap();
+        let mut body = req.into_body();
+        body.frame().await.unwrap().unwrap();
+        drop(body);
+        copied.await.unwrap();
+        assert_eq!((stats.requests(), stats.dropped()), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_mirror_timeout_and_cap() {
+        // a shadow that never answers
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service_fn(|_: Request<hyper::body::Incoming>| {
+                    std::future::pending::<Result<Response<Full<Bytes>>, hyper::Error>>()
+                });
+                tokio::spawn(
+                    http2::Builder::new(TokioExecutor)
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        let registry = ServiceRegistry::new();
+        registry.register_service(
+            "kyc.Kyc.shadow".to_string(),
+            "kyc-v2".to_string(),
+            vec![addr.to_string()],
+        );
+        let mirror = Mirror {
+            service: "kyc.Kyc.shadow".to_string(),
+            percent: 100.0,
+            timeout: Duration::from_millis(100),
+        };
+        let stats = Arc::new(MirrorStats::default());
+        let body = Full::new(Bytes::from("ping"))
+            .map_err(|never| match never {})
+            .boxed();
+        let (req, copy, aborted) = tee(request(body));
+        req.into_body().collect().await.unwrap();
+        let permit = stats.admit().unwrap();
+        assert_eq!(stats.in_flight(), 1);
+        let started = Instant::now();
+        send(
+            registry,
+            ConnectionPool::default(),
+            mirror,
+            stats.clone(),
+            copy,
+            aborted,
+            None,
+        )
+        .await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!((stats.requests(), stats.failures()), (1, 1));
+        drop(permit);
+
+        // copies over the cap are dropped
+        let permits: Vec<_> = (0..MAX_IN_FLIGHT).map(|_| stats.admit().unwrap()).collect();
+        assert!(stats.admit().is_none());
+        assert_eq!(stats.dropped(), 1);
+        drop(permits);
+        assert_eq!(stats.in_flight(), 0);
+    }
+}
@@ -0,0 +1,154 @@
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::stats::{EndpointStats, StatsTable};
+
+/// Passive ejection of endpoints that keep failing real traffic.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutlierDetection {
+    /// Consecutive failed requests that eject an endpoint.
+    pub consecutive_failures: u32,
+    /// Ejection time of a first offense, each repeat offense adds another.
+    #[serde(with = "crate::config::duration")]
+    pub base_ejection: Duration,
+    #[serde(with = "crate::config::duration")]
+    pub max_ejection: Duration,
+    /// Cap on the share of a service's endpoints ejected at the same time,
+    /// one endpoint can always be ejected.
+    pub max_ejection_percent: u8,
+}
+
+impl Default for OutlierDetection {
+    fn default() -> Self {
+        OutlierDetection {
+            consecutive_failures: 5,
+            base_ejection: Duration::from_secs(30),
+            max_ejection: Duration::from_secs(300),
+            max_ejection_percent: 10,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct OutlierState {
+    consecutive_failures: u32,
+    ejections: u32,
+    ejected_until: Option<Instant>,
+}
+
+impl OutlierState {
+    pub(crate) fn is_ejected(&self, now: Instant) -> bool {
+        self.ejected_until.is_some_and(|until| now < until)
+    }
+
+    pub(crate) fn record_success(&mut self, detection: &OutlierDetection, now: Instant) {
+        self.consecutive_failures = 0;
+        // forgive past offenses once the endpoint behaved for a full max
+        // ejection time after coming back
+        if let Some(until) = self.ejected_until {
+            if now >= until + detection.max_ejection {
+                self.ejections = 0;
+                self.ejected_until = None;
+            }
+        }
+    }
+
+    /// Records a failure, returns the ejection time when it ejected the
+    /// endpoint. `can_eject` is only asked once the threshold is reached.
+    pub(crate) fn record_failure<F>(
+        &mut self,
+        detection: &OutlierDetection,
+        now: Instant,
+        can_eject: F,
+    ) -> Option<Duration>
+    where
+        F: FnOnce() -> bool,
+    {
+        self.consecutive_failures += 1;
+        if self.is_ejected(now)
+            || self.consecutive_failures < detection.consecutive_failures
+            || !can_eject()
+        {
+            return None;
+        }
+        self.consecutive_failures = 0;
+        self.ejections += 1;
+        let ejection = detection
+            .base_ejection
+            .saturating_mul(self.ejections)
+            .min(detection.max_ejection);
+        self.ejected_until = Some(now + ejection);
+        Some(ejection)
+    }
+}
+
+/// Whether `candidate`, one of the service's `endpoints`, may be ejected
+/// without going over the ejection cap.
+pub(crate) fn can_eject(
+    detection: &OutlierDetection,
+    stats: &StatsTable,
+    endpoints: usize,
+    candidate: &EndpointStats,
+) -> bool {
+    let now = Instant::now();
+    let ejected = stats
+        .all()
+        .iter()
+        .filter(|s| !std::ptr::eq(s.as_ref(), candidate) && s.is_ejected(now))
+        .count();
+    let allowed = (endpoints * detection.max_ejection_percent as usize / 100).max(1);
+    ejected < allowed
+}
+
+#[cfg(test)]
+mod tests_outlier {
+    use super::*;
+
+    #[test]
+    fn test_ejection_backoff() {
+        let detection = OutlierDetection {
+            consecutive_failures: 2,
+            ..Default::default()
+        };
+        let now = Instant::now();
+        let mut state = OutlierState::default();
+        assert_eq!(state.record_failure(&detection, now, || true), None);
+        assert_eq!(
+            state.record_failure(&detection, now, || true),
+            Some(Duration::from_secs(30))
+        );
+        assert!(state.is_ejected(now));
+
+        let later = now + Duration::from_secs(31);
+        assert!(!state.is_ejected(later));
+        state.record_failure(&detection, later, || true);
+        assert_eq!(
+            state.record_failure(&detection, later, || true),
+            Some(Duration::from_secs(60))
+        );
+
+        // at the ejection cap the endpoint keeps serving
+        let mut capped = OutlierState::default();
+        capped.record_failure(&detection, now, || false);
+        assert_eq!(capped.record_failure(&detection, now, || false), None);
+        assert!(!capped.is_ejected(now));
+    }
+
+    #[test]
+    fn test_can_eject() {
+        let detection = OutlierDetection {
+            consecutive_failures: 1,
+            max_ejection_percent: 50,
+            ..Default::default()
+        };
+        let stats = StatsTable::default();
+        let (a, b, c) = (stats.get("a:1"), stats.get("b:1"), stats.get("c:1"));
+        assert!(can_eject(&detection, &stats, 4, &a));
+        a.record_failure(&detection, || true);
+        assert!(can_eject(&detection, &stats, 4, &b));
+        b.record_failure(&detection, || true);
+        assert!(!can_eject(&detection, &stats, 4, &c));
+    }
+}
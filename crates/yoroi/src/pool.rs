@@ -0,0 +1,582 @@
+use std::{
+    collections::{HashMap, HashSet},
+    error::Error,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use http_body_util::combinators::BoxBody;
+use hyper::{
+    body::{Bytes, Incoming},
+    client::conn::{http1, http2},
+    Request, Response,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use serde::Deserialize;
+use tokio::time::{interval, timeout, Instant};
+use tracing::debug;
+
+use crate::{
+    protocol::Protocol,
+    registry::ServiceRegistry,
+    server::TokioExecutor,
+    tls::{self, ClientTls},
+    watch::RegistryEvent,
+};
+
+type Body = BoxBody<Bytes, hyper::Error>;
+
+/// Sender of requests over a pooled connection.
+pub(crate) enum Sender {
+    /// Shared by every request multiplexed over the connection.
+    Http2(http2::SendRequest<Body>),
+    /// Held by one request at a time, see `Checkout`.
+    Http1(Checkout),
+}
+
+impl Sender {
+    pub(crate) async fn send_request(
+        &mut self,
+        req: Request<Body>,
+    ) -> Result<Response<Incoming>, hyper::Error> {
+        match self {
+            Sender::Http2(sender) => sender.send_request(req).await,
+            Sender::Http1(checkout) => {
+                let sender = checkout.sender.as_mut().expect("checked out until dropped");
+                sender.send_request(req).await
+            }
+        }
+    }
+}
+
+/// HTTP/1.1 connection taken out of the pool, it goes back in when dropped.
+/// It is only handed out again once the response sent over it has been
+/// read to the end.
+pub(crate) struct Checkout {
+    sender: Option<http1::SendRequest<Body>>,
+    pool: ConnectionPool,
+    endpoint: String,
+    tls: Option<Arc<ClientTls>>,
+}
+
+impl Drop for Checkout {
+    fn drop(&mut self) {
+        let sender = match self.sender.take() {
+            Some(sender) if !sender.is_closed() => sender,
+            _ => return,
+        };
+        let mut endpoints = self.pool.endpoints.lock().unwrap();
+        let pooled = endpoints.entry(self.endpoint.clone()).or_default();
+        pooled.idle.push(Idle {
+            sender,
+            last_used: Instant::now(),
+            tls: self.tls.take(),
+        });
+    }
+}
+
+/// Settings of the upstream connection pool, shared by every endpoint.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    /// Long-lived HTTP/2 connections opened per endpoint, requests are
+    /// multiplexed over them round-robin. HTTP/1.1 endpoints get as many
+    /// connections as they have requests in flight.
+    pub max_connections: usize,
+    /// How long opening a connection may take, the TCP connect and the TLS
+    /// and HTTP handshakes together. It is apart from the request's
+    /// deadline so an unreachable endpoint fails fast and can be retried
+    /// elsewhere.
+    #[serde(with = "crate::config::duration")]
+    pub connect_timeout: Duration,
+    /// Connections unused for this long are closed.
+    #[serde(with = "crate::config::duration")]
+    pub idle_timeout: Duration,
+    /// Interval of HTTP/2 keepalive pings, `None` disables them.
+    #[serde(with = "crate::config::duration::option")]
+    pub keepalive_interval: Option<Duration>,
+    /// How long to wait for a keepalive ping to be acknowledged.
+    #[serde(with = "crate::config::duration")]
+    pub keepalive_timeout: Duration,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_connections: 1,
+            connect_timeout: Duration::from_secs(5),
+            idle_timeout: Duration::from_secs(90),
+            keepalive_interval: Some(Duration::from_secs(30)),
+            keepalive_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+struct Connection {
+    sender: http2::SendRequest<Body>,
+    last_used: Instant,
+    /// TLS the connection was opened with.
+    tls: Option<Arc<ClientTls>>,
+}
+
+/// HTTP/1.1 connection waiting in the pool for its next request.
+struct Idle {
+    sender: http1::SendRequest<Body>,
+    last_used: Instant,
+    tls: Option<Arc<ClientTls>>,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    connections: Vec<Connection>,
+    next: usize,
+    idle: Vec<Idle>,
+    /// Held while an HTTP/2 connection is being opened, so a burst of
+    /// requests opens one at a time rather than one each.
+    connecting: Arc<tokio::sync::Mutex<()>>,
+}
+
+fn same_tls(a: Option<&Arc<ClientTls>>, b: Option<&Arc<ClientTls>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => Arc::ptr_eq(a, b),
+        (a, b) => a.is_none() && b.is_none(),
+    }
+}
+
+/// Pool of connections to upstream endpoints, keyed by address.
+#[derive(Clone, Default)]
+pub struct ConnectionPool {
+    config: PoolConfig,
+    endpoints: Arc<Mutex<HashMap<String, Endpoint>>>,
+}
+
+impl ConnectionPool {
+    pub fn new(config: PoolConfig) -> Self {
+        ConnectionPool {
+            config,
+            endpoints: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Hands out a sender of a live connection to `endpoint`, connecting
+    /// first when the endpoint has fewer connections than allowed or all of
+    /// them have gone away. Connections are opened one at a time, requests
+    /// arriving meanwhile share the ones already there. Connections are
+    /// made over `tls` when set, those opened with other TLS settings are
+    /// replaced. HTTP/1.1 connections are reused once idle, see `Checkout`.
+    pub(crate) async fn get(
+        &self,
+        endpoint: &str,
+        tls: Option<&Arc<ClientTls>>,
+        protocol: Protocol,
+    ) -> Result<Sender, Box<dyn Error + Send + Sync>> {
+        if protocol == Protocol::Http1 {
+            let sender = match self.idle(endpoint, tls) {
+                Some(sender) => sender,
+                None => self.connect(self.handshake_http1(endpoint, tls)).await?,
+            };
+            return Ok(Sender::Http1(Checkout {
+                sender: Some(sender),
+                pool: self.clone(),
+                endpoint: endpoint.to_string(),
+                tls: tls.cloned(),
+            }));
+        }
+
+        if let Some(sender) = self.ready(endpoint, tls, true).await {
+            return Ok(Sender::Http2(sender));
+        }
+        let connecting = self.connecting(endpoint);
+        let _connecting = match connecting.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                if let Some(sender) = self.ready(endpoint, tls, false).await {
+                    return Ok(Sender::Http2(sender));
+                }
+                connecting.lock().await
+            }
+        };
+        // the pool may have filled up while waiting
+        if let Some(sender) = self.ready(endpoint, tls, true).await {
+            return Ok(Sender::Http2(sender));
+        }
+
+        let sender = self.connect(self.handshake_http2(endpoint, tls)).await?;
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let pooled = endpoints.entry(endpoint.to_string()).or_default();
+        if pooled.connections.len() < self.config.max_connections {
+            pooled.connections.push(Connection {
+                sender: sender.clone(),
+                last_used: Instant::now(),
+                tls: tls.cloned(),
+            });
+        }
+        Ok(Sender::Http2(sender))
+    }
+
+    /// Number of live connections to `endpoint`.
+    pub fn connections(&self, endpoint: &str) -> usize {
+        let endpoints = self.endpoints.lock().unwrap();
+        endpoints.get(endpoint).map_or(0, |pooled| {
+            let connections = pooled.connections.iter();
+            let idle = pooled.idle.iter();
+            connections.filter(|c| !c.sender.is_closed()).count()
+                + idle.filter(|c| !c.sender.is_closed()).count()
+        })
+    }
+
+    /// Closes connections that have been idle for longer than allowed and
+    /// forgets the ones that broke.
+    pub(crate) fn evict_idle(&self) {
+        let now = Instant::now();
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let expired = |last_used: Instant, endpoint: &str| {
+            let idle = now.saturating_duration_since(last_used) >= self.config.idle_timeout;
+            if idle {
+                debug!("closing idle connection to {}", endpoint);
+            }
+            idle
+        };
+        for (endpoint, pooled) in endpoints.iter_mut() {
+            pooled
+                .connections
+                .retain(|c| !expired(c.last_used, endpoint) && !c.sender.is_closed());
+            pooled
+                .idle
+                .retain(|c| !expired(c.last_used, endpoint) && !c.sender.is_closed());
+        }
+        endpoints.retain(|_, pooled| {
+            !pooled.connections.is_empty()
+                || !pooled.idle.is_empty()
+                || Arc::strong_count(&pooled.connecting) > 1
+        });
+    }
+
+    /// Evicts idle connections until the task is aborted.
+    pub(crate) async fn run_reaper(self) {
+        let period = (self.config.idle_timeout / 2)
+            .clamp(Duration::from_millis(100), Duration::from_secs(30));
+        let mut ticker = interval(period);
+        loop {
+            ticker.tick().await;
+            self.evict_idle();
+        }
+    }
+
+    /// Closes the connections of endpoints once no service routes to them
+    /// anymore, until the task is aborted. Requests already sent over them
+    /// complete.
+    pub(crate) async fn run_evictor(self, registry: ServiceRegistry) {
+        loop {
+            let mut watch = registry.watch();
+            // the removals a watch that fell behind missed
+            let used: HashSet<&String> = watch
+                .snapshot()
+                .iter()
+                .flat_map(|record| record.endpoints.keys())
+                .collect();
+            self.endpoints
+                .lock()
+                .unwrap()
+                .retain(|endpoint, _| used.contains(endpoint));
+            while let Some(event) = watch.next().await {
+                if let RegistryEvent::EndpointRemoved { endpoint, .. } = event {
+                    let used = registry
+                        .get_all_services()
+                        .values()
+                        .any(|service| service.endpoints().contains_key(&endpoint));
+                    if !used {
+                        debug!("closing connections to removed endpoint {}", endpoint);
+                        self.endpoints.lock().unwrap().remove(&endpoint);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Pooled connection ready for a request, only out of a pool that is
+    /// `full` when set. A connection that broke since it was last used is
+    /// dropped.
+    async fn ready(
+        &self,
+        endpoint: &str,
+        tls: Option<&Arc<ClientTls>>,
+        full: bool,
+    ) -> Option<http2::SendRequest<Body>> {
+        let mut sender = self.pooled(endpoint, tls, full)?;
+        if sender.ready().await.is_ok() {
+            return Some(sender);
+        }
+        self.purge(endpoint);
+        None
+    }
+
+    fn connecting(&self, endpoint: &str) -> Arc<tokio::sync::Mutex<()>> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let pooled = endpoints.entry(endpoint.to_string()).or_default();
+        pooled.connecting.clone()
+    }
+
+    fn pooled(
+        &self,
+        endpoint: &str,
+        tls: Option<&Arc<ClientTls>>,
+        full: bool,
+    ) -> Option<http2::SendRequest<Body>> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let pooled = endpoints.get_mut(endpoint)?;
+        // requests already sent over a dropped connection still complete
+        pooled
+            .connections
+            .retain(|c| same_tls(c.tls.as_ref(), tls) && !c.sender.is_closed());
+        if pooled.connections.is_empty()
+            || (full && pooled.connections.len() < self.config.max_connections)
+        {
+            return None;
+        }
+        pooled.next = pooled.next.wrapping_add(1);
+        let i = pooled.next % pooled.connections.len();
+        let connection = &mut pooled.connections[i];
+        connection.last_used = Instant::now();
+        Some(connection.sender.clone())
+    }
+
+    /// Takes an HTTP/1.1 connection to `endpoint` that is done with its
+    /// last request out of the pool.
+    fn idle(
+        &self,
+        endpoint: &str,
+        tls: Option<&Arc<ClientTls>>,
+    ) -> Option<http1::SendRequest<Body>> {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        let pooled = endpoints.get_mut(endpoint)?;
+        pooled
+            .idle
+            .retain(|c| same_tls(c.tls.as_ref(), tls) && !c.sender.is_closed());
+        let i = pooled.idle.iter().rposition(|c| c.sender.is_ready())?;
+        Some(pooled.idle.swap_remove(i).sender)
+    }
+
+    fn purge(&self, endpoint: &str) {
+        let mut endpoints = self.endpoints.lock().unwrap();
+        if let Some(pooled) = endpoints.get_mut(endpoint) {
+            pooled.connections.retain(|c| c.sender.is_ready());
+        }
+    }
+
+    /// Runs `handshake`, failing it past the connect timeout.
+    async fn connect<T>(
+        &self,
+        handshake: impl Future<Output = Result<T, Box<dyn Error + Send + Sync>>>,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        match timeout(self.config.connect_timeout, handshake).await {
+            Ok(result) => result,
+            Err(_) => {
+                Err(format!("connect timed out after {:?}", self.config.connect_timeout).into())
+            }
+        }
+    }
+
+    async fn handshake_http2(
+        &self,
+        endpoint: &str,
+        tls: Option<&Arc<ClientTls>>,
+    ) -> Result<http2::SendRequest<Body>, Box<dyn Error + Send + Sync>> {
+        let stream = tls::connect(endpoint, tls.map(Arc::as_ref), Protocol::Http2).await?;
+        let mut builder = http2::Builder::new(TokioExecutor);
+        builder.timer(TokioTimer::new());
+        if let Some(keepalive) = self.config.keepalive_interval {
+            builder
+                .keep_alive_interval(keepalive)
+                .keep_alive_timeout(self.config.keepalive_timeout)
+                .keep_alive_while_idle(true);
+        }
+        let (sender, conn) = builder.handshake(TokioIo::new(stream)).await?;
+        let endpoint = endpoint.to_string();
+        tokio::task::spawn(async move {
+            if let Err(err) = conn.await {
+                debug!("connection to {} closed: {}", endpoint, err);
+            }
+        });
+        Ok(sender)
+    }
+
+    async fn handshake_http1(
+        &self,
+        endpoint: &str,
+        tls: Option<&Arc<ClientTls>>,
+    ) -> Result<http1::SendRequest<Body>, Box<dyn Error + Send + Sync>> {
+        let stream = tls::connect(endpoint, tls.map(Arc::as_ref), Protocol::Http1).await?;
+        let (sender, conn) = http1::handshake(TokioIo::new(stream)).await?;
+        let endpoint = endpoint.to_string();
+        tokio::task::spawn(async move {
+            if let Err(err) = conn.await {
+                debug!("connection to {} closed: {}", endpoint, err);
+            }
+        });
+        Ok(sender)
+    }
+}
+
+#[cfg(test)]
+mod tests_pool {
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::{
+        server::conn::{http1 as server_http1, http2 as server_http2},
+        service::service_fn,
+    };
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn serve_h2() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(server_http2::Builder::new(TokioExecutor).serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(|_| async {
+                        Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from("ok"))))
+                    }),
+                ));
+            }
+        });
+        addr.to_string()
+    }
+
+    async fn serve_h1() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(server_http1::Builder::new().serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(|_| async {
+                        Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from("ok"))))
+                    }),
+                ));
+            }
+        });
+        addr.to_string()
+    }
+
+    fn request() -> Request<BoxBody<Bytes, hyper::Error>> {
+        Request::builder()
+            .uri("/ping.Ping/ping")
+            .body(Empty::new().map_err(|never| match never {}).boxed())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_reused() {
+        let endpoint = serve_h2().await;
+        let pool = ConnectionPool::new(PoolConfig {
+            max_connections: 2,
+            ..Default::default()
+        });
+        for _ in 0..5 {
+            let mut sender = pool.get(&endpoint, None, Protocol::Http2).await.unwrap();
+            let resp = sender.send_request(request()).await.unwrap();
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, Bytes::from("ok"));
+        }
+        assert_eq!(pool.connections(&endpoint), 2);
+
+        assert!(pool
+            .get("127.0.0.1:1", None, Protocol::Http2)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_burst_is_capped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let endpoint = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                tokio::spawn(server_http2::Builder::new(TokioExecutor).serve_connection(
+                    TokioIo::new(stream),
+                    service_fn(|_| async {
+                        Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from("ok"))))
+                    }),
+                ));
+            }
+        });
+        let pool = ConnectionPool::new(PoolConfig {
+            max_connections: 2,
+            ..Default::default()
+        });
+        let requests: Vec<_> = (0..50)
+            .map(|_| {
+                let (pool, endpoint) = (pool.clone(), endpoint.clone());
+                tokio::spawn(async move {
+                    let mut sender = pool.get(&endpoint, None, Protocol::Http2).await.unwrap();
+                    sender.send_request(request()).await.unwrap();
+                })
+            })
+            .collect();
+        for request in requests {
+            request.await.unwrap();
+        }
+        assert_eq!(pool.connections(&endpoint), 2);
+        assert_eq!(accepted.load(std::sync::atomic::Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn test_http1_checkout() {
+        let endpoint = serve_h1().await;
+        let pool = ConnectionPool::default();
+        for _ in 0..3 {
+            let mut sender = pool.get(&endpoint, None, Protocol::Http1).await.unwrap();
+            let resp = sender.send_request(request()).await.unwrap();
+            drop(sender);
+            let body = resp.into_body().collect().await.unwrap().to_bytes();
+            assert_eq!(body, Bytes::from("ok"));
+            // let the connection see the response was read
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(pool.connections(&endpoint), 1);
+
+        // a checked out connection is not handed out twice
+        let mut first = pool.get(&endpoint, None, Protocol::Http1).await.unwrap();
+        let mut second = pool.get(&endpoint, None, Protocol::Http1).await.unwrap();
+        assert!(first.send_request(request()).await.is_ok());
+        assert!(second.send_request(request()).await.is_ok());
+        drop((first, second));
+        assert_eq!(pool.connections(&endpoint), 2);
+    }
+
+    #[tokio::test]
+    async fn test_evict_removed_endpoints() {
+        let endpoint = serve_h2().await;
+        let registry = ServiceRegistry::new();
+        registry.register_service("ping.Ping".into(), "ping".into(), vec![endpoint.clone()]);
+        let pool = ConnectionPool::default();
+        let evictor = tokio::spawn(pool.clone().run_evictor(registry.clone()));
+        pool.get(&endpoint, None, Protocol::Http2).await.unwrap();
+        assert_eq!(pool.connections(&endpoint), 1);
+
+        registry.remove_endpoint("ping.Ping", &endpoint);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(pool.connections(&endpoint), 0);
+        evictor.abort();
+    }
+
+    #[tokio::test]
+    async fn test_evict_idle() {
+        let endpoint = serve_h2().await;
+        let pool = ConnectionPool::new(PoolConfig {
+            idle_timeout: Duration::ZERO,
+            ..Default::default()
+        });
+        pool.get(&endpoint, None, Protocol::Http2).await.unwrap();
+        assert_eq!(pool.connections(&endpoint), 1);
+        pool.evict_idle();
+        assert_eq!(pool.connections(&endpoint), 0);
+    }
+}
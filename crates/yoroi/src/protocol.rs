@@ -0,0 +1,154 @@
+use hyper::{
+    header::{HeaderName, CONNECTION, CONTENT_TYPE, HOST, TE, TRANSFER_ENCODING, UPGRADE},
+    http::{request::Parts, uri},
+    HeaderMap, Uri, Version,
+};
+use serde::{Deserialize, Serialize};
+
+/// Headers about a single connection, never forwarded.
+const HOP_BY_HOP: [&str; 2] = ["keep-alive", "proxy-connection"];
+
+/// Protocol the endpoints of a service speak. The gateway listener serves
+/// both to clients whatever the services speak, requests are translated on
+/// the way through.
+///
+/// ```toml
+/// protocol = "http1"
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    /// HTTP/2, what gRPC services speak.
+    #[default]
+    Http2,
+    /// HTTP/1.1, for plain HTTP services, over pooled keep-alive
+    /// connections carrying one request at a time.
+    Http1,
+}
+
+/// Whether the request or response with `headers` is gRPC.
+pub(crate) fn is_grpc(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/grpc"))
+}
+
+/// Rewrites the head of a request for an upstream speaking `protocol`:
+/// HTTP/1.1 wants the path alone with a `Host` header, HTTP/2 a full URI
+/// its `:authority` and `:scheme` come from.
+pub(crate) fn adapt(parts: &mut Parts, protocol: Protocol) {
+    strip_hop_by_hop(&mut parts.headers);
+    match protocol {
+        Protocol::Http1 => {
+            if let Some(authority) = parts.uri.authority() {
+                if !parts.headers.contains_key(HOST) {
+                    if let Ok(host) = authority.as_str().parse() {
+                        parts.headers.insert(HOST, host);
+                    }
+                }
+            }
+            parts.uri = parts
+                .uri
+                .path_and_query()
+                .map_or_else(|| Uri::from_static("/"), |path| Uri::from(path.clone()));
+            parts.version = Version::HTTP_11;
+        }
+        Protocol::Http2 => {
+            if parts.uri.authority().is_none() {
+                let host = parts.headers.get(HOST).and_then(|host| host.to_str().ok());
+                let mut uri = uri::Parts::default();
+                uri.scheme = Some(uri::Scheme::HTTP);
+                uri.authority = host.and_then(|host| host.parse().ok());
+                uri.path_and_query = parts.uri.path_and_query().cloned();
+                if uri.authority.is_some() {
+                    if let Ok(absolute) = Uri::from_parts(uri) {
+                        parts.uri = absolute;
+                        parts.headers.remove(HOST);
+                    }
+                }
+            }
+            parts.version = Version::HTTP_2;
+        }
+    }
+}
+
+/// Removes the headers that only concern the connection they came over,
+/// the ones its `Connection` header lists included. `TE: trailers`, which
+/// gRPC requires, is kept.
+pub(crate) fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| name.trim().parse().ok())
+        .collect();
+    for name in listed {
+        headers.remove(name);
+    }
+    for name in [CONNECTION, TRANSFER_ENCODING, UPGRADE] {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+    if headers.get(TE).is_some_and(|te| te != "trailers") {
+        headers.remove(TE);
+    }
+}
+
+#[cfg(test)]
+mod tests_protocol {
+    use hyper::Request;
+
+    use super::*;
+
+    fn parts(req: Request<()>) -> Parts {
+        req.into_parts().0
+    }
+
+    #[test]
+    fn test_adapt() {
+        // HTTP/1.1 client to an HTTP/2 service
+        let mut head = parts(
+            Request::get("/kyc.Kyc/get?id=1")
+                .header(HOST, "api.example.com")
+                .header(CONNECTION, "keep-alive, x-hop")
+                .header("keep-alive", "timeout=5")
+                .header("x-hop", "1")
+                .header(TE, "trailers")
+                .body(())
+                .unwrap(),
+        );
+        adapt(&mut head, Protocol::Http2);
+        assert_eq!(head.uri, "http://api.example.com/kyc.Kyc/get?id=1");
+        assert_eq!(head.version, Version::HTTP_2);
+        assert_eq!(head.headers.len(), 1);
+        assert_eq!(head.headers[TE], "trailers");
+
+        // HTTP/2 client to an HTTP/1.1 service
+        let mut head = parts(
+            Request::get("http://api.example.com/users")
+                .version(Version::HTTP_2)
+                .header(TE, "gzip")
+                .body(())
+                .unwrap(),
+        );
+        adapt(&mut head, Protocol::Http1);
+        assert_eq!(head.uri, "/users");
+        assert_eq!(head.version, Version::HTTP_11);
+        assert_eq!(head.headers[HOST], "api.example.com");
+        assert!(!head.headers.contains_key(TE));
+    }
+
+    #[test]
+    fn test_is_grpc() {
+        let mut headers = HeaderMap::new();
+        assert!(!is_grpc(&headers));
+        headers.insert(CONTENT_TYPE, "application/grpc+proto".parse().unwrap());
+        assert!(is_grpc(&headers));
+        headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
+        assert!(!is_grpc(&headers));
+    }
+}
@@ -0,0 +1,555 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    task::JoinHandle,
+    time::{interval, Instant, MissedTickBehavior},
+};
+use tonic::{transport::Channel, Code, Request, Response, Status};
+use tracing::{error, info, warn};
+
+use crate::{config, endpoint::Endpoint, registry::ServiceRegistry};
+
+use pb::{
+    registry_client::RegistryClient,
+    registry_server::{Registry, RegistryServer},
+    DeregisterRequest, DeregisterResponse, HeartbeatRequest, HeartbeatResponse, RegisterRequest,
+    RegisterResponse,
+};
+
+pub mod pb {
+    tonic::include_proto!("yoroi.registry.v1");
+}
+
+/// Lease lifetime of registrations that don't ask for one.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(10);
+
+/// How often expired leases are looked for.
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Lease {
+    service_id: String,
+    endpoint: String,
+    ttl: Duration,
+    expires_at: Instant,
+    /// Whether the lease added the endpoint. One that was registered
+    /// otherwise, e.g. configured, stays when the lease ends.
+    owned: bool,
+}
+
+/// Endpoints that registered themselves, each held by a lease that expires
+/// unless it is renewed by heartbeats.
+#[derive(Clone)]
+pub(crate) struct Leases {
+    registry: ServiceRegistry,
+    leases: Arc<Mutex<HashMap<String, Lease>>>,
+}
+
+impl Leases {
+    pub(crate) fn new(registry: ServiceRegistry) -> Self {
+        Leases {
+            registry,
+            leases: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Registers `endpoint` under `service_id` and hands out the id of its
+    /// lease, a previous lease on the same endpoint is replaced.
+    fn grant(&self, service_id: &str, name: &str, endpoint: Endpoint, ttl: Duration) -> String {
+        let lease_id = format!("{:032x}", rand::random::<u128>());
+        let mut leases = self.leases.lock().unwrap();
+        let mut replaced = None;
+        leases.retain(|_, lease| {
+            let same = lease.service_id == service_id && lease.endpoint == endpoint.address;
+            if same {
+                replaced = Some(lease.owned);
+            }
+            !same
+        });
+        let current = self.registry.get_endpoint(service_id, &endpoint.address);
+        let owned = replaced.unwrap_or(current.is_none());
+        // the admin state stays with the gateway's operators, and so does the
+        // rest of an endpoint the lease doesn't own
+        let endpoint = match current {
+            Some(current) if !owned => current,
+            Some(current) => Endpoint {
+                state: current.state,
+                ..endpoint
+            },
+            None => endpoint,
+        };
+        leases.insert(
+            lease_id.clone(),
+            Lease {
+                service_id: service_id.to_string(),
+                endpoint: endpoint.address.clone(),
+                ttl,
+                expires_at: Instant::now() + ttl,
+                owned,
+            },
+        );
+        self.registry
+            .register_service(service_id.to_string(), name.to_string(), vec![]);
+        self.registry.set_endpoint(service_id, endpoint);
+        lease_id
+    }
+
+    /// Extends the lease by its TTL, `None` once it has expired.
+    fn renew(&self, lease_id: &str) -> Option<Duration> {
+        let mut leases = self.leases.lock().unwrap();
+        let lease = leases.get_mut(lease_id)?;
+        lease.expires_at = Instant::now() + lease.ttl;
+        Some(lease.ttl)
+    }
+
+    fn revoke(&self, lease_id: &str) -> bool {
+        let lease = self.leases.lock().unwrap().remove(lease_id);
+        match lease {
+            Some(lease) => {
+                if lease.owned {
+                    self.registry
+                        .remove_endpoint(&lease.service_id, &lease.endpoint);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Removes the endpoints whose leases have expired.
+    pub(crate) fn reap(&self) {
+        let now = Instant::now();
+        let mut expired = vec![];
+        self.leases.lock().unwrap().retain(|_, lease| {
+            if lease.expires_at > now {
+                return true;
+            }
+            if lease.owned {
+                expired.push((lease.service_id.clone(), lease.endpoint.clone()));
+            }
+            false
+        });
+        for (service_id, endpoint) in expired {
+            warn!(
+                "lease of {} ({}) expired, removing it",
+                endpoint, service_id
+            );
+            self.registry.remove_endpoint(&service_id, &endpoint);
+        }
+    }
+
+    async fn run_reaper(self) {
+        let mut ticker = interval(REAP_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            ticker.tick().await;
+            self.reap();
+        }
+    }
+}
+
+fn ttl_seconds(ttl: Duration) -> u32 {
+    ttl.as_secs().try_into().unwrap_or(u32::MAX)
+}
+
+#[tonic::async_trait]
+impl Registry for Leases {
+    async fn register(
+        &self,
+        request: Request<RegisterRequest>,
+    ) -> Result<Response<RegisterResponse>, Status> {
+        let RegisterRequest {
+            service_id,
+            name,
+            endpoint,
+            ttl_seconds: ttl,
+            weight,
+            zone,
+            version,
+            labels,
+        } = request.into_inner();
+        if service_id.is_empty() {
+            return Err(Status::invalid_argument("service_id must not be empty"));
+        }
+        if let Err(msg) = config::validate_endpoint(&endpoint) {
+            return Err(Status::invalid_argument(format!(
+                "endpoint `{}` {}",
+                endpoint, msg
+            )));
+        }
+        let ttl = match ttl {
+            0 => DEFAULT_TTL,
+            secs => Duration::from_secs(secs.into()),
+        };
+        let metadata = Endpoint {
+            address: endpoint.clone(),
+            weight: if weight == 0 { 1 } else { weight },
+            zone: Some(zone).filter(|zone| !zone.is_empty()),
+            version: Some(version).filter(|version| !version.is_empty()),
+            labels: labels.into_iter().collect(),
+            ..Default::default()
+        };
+        let lease_id = self.grant(&service_id, &name, metadata, ttl);
+        info!("registered {} for {}", endpoint, service_id);
+        Ok(Response::new(RegisterResponse {
+            lease_id,
+            ttl_seconds: ttl_seconds(ttl),
+        }))
+    }
+
+    async fn heartbeat(
+        &self,
+        request: Request<HeartbeatRequest>,
+    ) -> Result<Response<HeartbeatResponse>, Status> {
+        match self.renew(&request.get_ref().lease_id) {
+            Some(ttl) => Ok(Response::new(HeartbeatResponse {
+                ttl_seconds: ttl_seconds(ttl),
+            })),
+            None => Err(Status::not_found("lease expired or unknown")),
+        }
+    }
+
+    async fn deregister(
+        &self,
+        request: Request<DeregisterRequest>,
+    ) -> Result<Response<DeregisterResponse>, Status> {
+        if !self.revoke(&request.get_ref().lease_id) {
+            return Err(Status::not_found("lease expired or unknown"));
+        }
+        Ok(Response::new(DeregisterResponse {}))
+    }
+}
+
+/// Serves the `yoroi.registry.v1.Registry` gRPC service on `address` and
+/// evicts endpoints whose leases expire, until the task is aborted.
+pub(crate) async fn serve(address: String, registry: ServiceRegistry) {
+    let addr = match SocketAddr::from_str(&address) {
+        Ok(addr) => addr,
+        Err(err) => {
+            error!("registration: invalid address {}: {}", address, err);
+            return;
+        }
+    };
+    let leases = Leases::new(registry);
+    let reaper = leases.clone().run_reaper();
+    let server = tonic::transport::Server::builder()
+        .add_service(RegistryServer::new(leases))
+        .serve(addr);
+    info!("Yoroi registration API started on: {}", address);
+    tokio::select! {
+        result = server => {
+            if let Err(err) = result {
+                error!("registration: {}", err);
+            }
+        }
+        _ = reaper => {}
+    }
+}
+
+/// Registration of a backend with the gateway, kept alive by heartbeats
+/// until it is deregistered or dropped.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+/// let registration = yoroi::registration::Registration::register(
+///     "http://localhost:9091".to_string(),
+///     "kyc.Kyc",
+///     "kyc",
+///     "localhost:50051",
+///     std::time::Duration::from_secs(10),
+/// )
+/// .await?;
+/// // serve until shutdown, then leave the rotation
+/// registration.deregister().await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Registration {
+    client: RegistryClient<Channel>,
+    lease_id: Arc<Mutex<String>>,
+    heartbeat: JoinHandle<()>,
+}
+
+impl Registration {
+    /// Registers `endpoint` for `service_id` with the gateway's registration
+    /// API at `gateway` and keeps heartbeating every third of `ttl`. A lease
+    /// the gateway has let expire is registered again.
+    pub async fn register(
+        gateway: String,
+        service_id: &str,
+        name: &str,
+        endpoint: &str,
+        ttl: Duration,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::register_endpoint(gateway, service_id, name, Endpoint::new(endpoint), ttl).await
+    }
+
+    /// Like `register`, with the weight, zone, version and labels of
+    /// `endpoint`. Its admin state is left to the gateway's operators.
+    pub async fn register_endpoint(
+        gateway: String,
+        service_id: &str,
+        name: &str,
+        endpoint: Endpoint,
+        ttl: Duration,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let mut client = RegistryClient::connect(gateway).await?;
+        let request = RegisterRequest {
+            service_id: service_id.to_string(),
+            name: name.to_string(),
+            endpoint: endpoint.address,
+            ttl_seconds: ttl_seconds(ttl),
+            weight: endpoint.weight,
+            zone: endpoint.zone.unwrap_or_default(),
+            version: endpoint.version.unwrap_or_default(),
+            labels: endpoint.labels.into_iter().collect(),
+        };
+        let lease = client.register(request.clone()).await?.into_inner();
+        let lease_id = Arc::new(Mutex::new(lease.lease_id));
+        let period = Duration::from_secs(lease.ttl_seconds.into()) / 3;
+        let heartbeat = tokio::spawn(heartbeat(
+            client.clone(),
+            request,
+            lease_id.clone(),
+            period.max(Duration::from_millis(100)),
+        ));
+        Ok(Registration {
+            client,
+            lease_id,
+            heartbeat,
+        })
+    }
+
+    pub fn lease_id(&self) -> String {
+        self.lease_id.lock().unwrap().clone()
+    }
+
+    /// Stops heartbeating and removes the endpoint from the gateway.
+    pub async fn deregister(mut self) -> Result<(), Status> {
+        self.heartbeat.abort();
+        let lease_id = self.lease_id();
+        self.client
+            .deregister(DeregisterRequest { lease_id })
+            .await?;
+        Ok(())
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.heartbeat.abort();
+    }
+}
+
+async fn heartbeat(
+    mut client: RegistryClient<Channel>,
+    request: RegisterRequest,
+    lease_id: Arc<Mutex<String>>,
+    period: Duration,
+) {
+    let mut ticker = interval(period);
+    ticker.tick().await;
+    loop {
+        ticker.tick().await;
+        let current = lease_id.lock().unwrap().clone();
+        let result = client
+            .heartbeat(HeartbeatRequest { lease_id: current })
+            .await;
+        match result {
+            Ok(_) => {}
+            Err(status) if status.code() == Code::NotFound => {
+                warn!("lease of {} expired, registering again", request.endpoint);
+                match client.register(request.clone()).await {
+                    Ok(lease) => *lease_id.lock().unwrap() = lease.into_inner().lease_id,
+                    Err(status) => error!("failed to register again: {}", status),
+                }
+            }
+            Err(status) => warn!("heartbeat failed: {}", status),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests_registration {
+    use tokio::net::TcpListener;
+    use tokio_stream::wrappers::TcpListenerStream;
+
+    use super::*;
+    use crate::{config::Config, endpoint::AdminState, router::Router};
+
+    async fn start(leases: Leases) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(RegistryServer::new(leases))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        );
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_register_and_deregister() {
+        let registry = ServiceRegistry::new();
+        let gateway = start(Leases::new(registry.clone())).await;
+
+        let registration = Registration::register_endpoint(
+            gateway,
+            "kyc.Kyc",
+            "kyc",
+            Endpoint {
+                weight: 2,
+                version: Some("v2".to_string()),
+                ..Endpoint::new("10.0.0.1:50051")
+            },
+            Duration::from_secs(30),
+        )
+        .await
+        .unwrap();
+        let endpoint = registry.get_endpoint("kyc.Kyc", "10.0.0.1:50051").unwrap();
+        assert_eq!(endpoint.weight, 2);
+        assert_eq!(endpoint.version.as_deref(), Some("v2"));
+        assert_eq!(
+            registry.resolve_endpoint("kyc.Kyc".to_string()).as_deref(),
+            Some("10.0.0.1:50051")
+        );
+
+        registration.deregister().await.unwrap();
+        assert!(registry.resolve_endpoint("kyc.Kyc".to_string()).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_expired_leases_are_reaped() {
+        let registry = ServiceRegistry::new();
+        let leases = Leases::new(registry.clone());
+        let expiring = Endpoint::new("10.0.0.1:50051");
+        let lease_id = leases.grant("kyc.Kyc", "kyc", expiring, Duration::ZERO);
+        leases.grant(
+            "kyc.Kyc",
+            "kyc",
+            Endpoint::new("10.0.0.2:50051"),
+            Duration::from_secs(30),
+        );
+
+        leases.reap();
+        assert!(leases.renew(&lease_id).is_none());
+        let service = registry.get_service("kyc.Kyc").unwrap();
+        assert_eq!(service.endpoints().len(), 1);
+        assert!(service.endpoints().contains_key("10.0.0.2:50051"));
+    }
+
+    #[test]
+    fn test_configured_endpoints_outlive_leases() {
+        let registry = ServiceRegistry::new();
+        registry.register_service(
+            "kyc.Kyc".to_string(),
+            "kyc".to_string(),
+            vec!["[::1]:50051".to_string()],
+        );
+        let leases = Leases::new(registry.clone());
+        let endpoint = Endpoint::new("[::1]:50051");
+        leases.grant("kyc.Kyc", "kyc", endpoint.clone(), Duration::ZERO);
+        // registering again does not take the endpoint over
+        leases.grant("kyc.Kyc", "kyc", endpoint.clone(), Duration::ZERO);
+        leases.reap();
+        assert!(registry.get_endpoint("kyc.Kyc", "[::1]:50051").is_some());
+
+        let lease_id = leases.grant("kyc.Kyc", "kyc", endpoint, Duration::from_secs(30));
+        assert!(leases.revoke(&lease_id));
+        assert!(registry.get_endpoint("kyc.Kyc", "[::1]:50051").is_some());
+    }
+
+    #[test]
+    fn test_register_again_keeps_admin_state() {
+        let registry = ServiceRegistry::new();
+        let leases = Leases::new(registry.clone());
+        let endpoint = Endpoint {
+            weight: 2,
+            ..Endpoint::new("10.0.0.1:50051")
+        };
+        leases.grant("kyc.Kyc", "kyc", endpoint.clone(), Duration::from_secs(30));
+        registry.set_endpoint(
+            "kyc.Kyc",
+            Endpoint {
+                state: AdminState::Draining,
+                ..endpoint.clone()
+            },
+        );
+
+        // e.g. the heartbeat loop registering again after a NotFound
+        leases.grant(
+            "kyc.Kyc",
+            "kyc",
+            Endpoint {
+                weight: 3,
+                ..endpoint
+            },
+            Duration::from_secs(30),
+        );
+        let endpoint = registry.get_endpoint("kyc.Kyc", "10.0.0.1:50051").unwrap();
+        assert_eq!(endpoint.state, AdminState::Draining);
+        assert_eq!(endpoint.weight, 3);
+
+        // an endpoint registered otherwise keeps its metadata too
+        registry.register_service(
+            "kyc.Kyc".to_string(),
+            "kyc".to_string(),
+            vec!["10.0.0.2:50051".to_string()],
+        );
+        leases.grant(
+            "kyc.Kyc",
+            "kyc",
+            Endpoint {
+                weight: 5,
+                version: Some("v2".to_string()),
+                ..Endpoint::new("10.0.0.2:50051")
+            },
+            Duration::from_secs(30),
+        );
+        let endpoint = registry.get_endpoint("kyc.Kyc", "10.0.0.2:50051").unwrap();
+        assert_eq!(endpoint.weight, 1);
+        assert_eq!(endpoint.version, None);
+    }
+
+    #[test]
+    fn test_config_reload_keeps_leased_endpoints() {
+        let registry = ServiceRegistry::new();
+        let router = Router::new();
+        let config = r#"
+listen = "127.0.0.1:8080"
+
+[[services]]
+id = "kyc.Kyc"
+name = "kyc"
+endpoints = ["10.0.0.1:50051", "10.0.0.3:50051"]
+"#;
+        let first = Config::from_str(config).unwrap();
+        first.apply(&registry, &router, None);
+        let leases = Leases::new(registry.clone());
+        let lease_id = leases.grant(
+            "kyc.Kyc",
+            "kyc",
+            Endpoint::new("10.0.0.2:50051"),
+            Duration::from_secs(30),
+        );
+
+        let second = Config::from_str(&config.replace(", \"10.0.0.3:50051\"", "")).unwrap();
+        second.apply(&registry, &router, Some(&first));
+        let service = registry.get_service("kyc.Kyc").unwrap();
+        let mut endpoints: Vec<_> = service.endpoints().keys().cloned().collect();
+        endpoints.sort();
+        assert_eq!(endpoints, ["10.0.0.1:50051", "10.0.0.2:50051"]);
+
+        // the lease still owns its endpoint
+        assert!(leases.renew(&lease_id).is_some());
+        assert!(leases.revoke(&lease_id));
+        assert!(registry.get_endpoint("kyc.Kyc", "10.0.0.2:50051").is_none());
+    }
+}
@@ -1,45 +1,535 @@
 use std::{
     collections::{HashMap, HashSet},
-    sync::{Arc, Mutex},
+    sync::{atomic::AtomicBool, mpsc, Arc, Mutex},
+    time::Instant,
 };
 
-use crate::MicroService;
+use hyper::HeaderMap;
+use tracing::{error, info, warn};
 
-#[derive(Clone, Default)]
+use crate::{
+    backend::{Change, InMemoryBackend, RegistryBackend, ServiceRecord},
+    balancer::Strategy,
+    breaker::CircuitBreaker,
+    config::ConfigError,
+    endpoint::{AdminState, Endpoint},
+    health::HealthCheck,
+    hedge::{HedgePolicy, HedgeStats},
+    mirror::{Mirror, MirrorStats},
+    outlier::OutlierDetection,
+    protocol::Protocol,
+    retry::{RetryBudget, RetryPolicy},
+    split::TrafficSplit,
+    stats::StatsTable,
+    subset::{self, SubsetRule},
+    tls::{ClientTls, UpstreamTlsConfig},
+    upstream::Upstream,
+    watch::{RegistryEvent, Watch, Watchers},
+    MicroService,
+};
+
+/// Services the gateway routes to. Membership changes and traffic splits are
+/// written through to the registry's backend, everything else stays local.
+#[derive(Clone)]
 pub struct ServiceRegistry {
     services: Arc<Mutex<HashMap<String, MicroService>>>,
+    backend: Arc<dyn RegistryBackend>,
+    // hands changes to the thread writing them to a shared backend
+    writer: Option<mpsc::Sender<Change>>,
+    watchers: Watchers,
+    // tells this registry's change notifications apart from other replicas'
+    origin: Arc<str>,
+}
+
+impl Default for ServiceRegistry {
+    fn default() -> Self {
+        ServiceRegistry::new()
+    }
 }
 
 impl ServiceRegistry {
     pub fn new() -> Self {
+        ServiceRegistry::with_backend(Arc::new(InMemoryBackend::default()))
+    }
+
+    /// Registry whose registrations are kept by `backend`, a shared backend
+    /// makes replicas converge on the same services.
+    pub fn with_backend(backend: Arc<dyn RegistryBackend>) -> Self {
+        // shared backends are remote, their writes would block the runtime
+        // workers membership changes are made on
+        let writer = backend.is_shared().then(|| {
+            let (tx, rx) = mpsc::channel::<Change>();
+            let backend = backend.clone();
+            std::thread::spawn(move || {
+                for change in rx {
+                    if let Err(err) = backend.apply(&change) {
+                        warn!("{}", err);
+                    }
+                }
+            });
+            tx
+        });
         ServiceRegistry {
             services: Arc::new(Mutex::new(HashMap::new())),
+            backend,
+            writer,
+            watchers: Watchers::default(),
+            origin: format!("{:016x}", rand::random::<u64>()).into(),
         }
     }
 
+    pub(crate) fn backend(&self) -> Arc<dyn RegistryBackend> {
+        self.backend.clone()
+    }
+
+    pub(crate) fn origin(&self) -> &str {
+        &self.origin
+    }
+
     pub fn register_service(&self, id: String, name: String, endpoints: Vec<String>) {
-        let mut services = self.services.lock().unwrap();
-        let service = services.entry(id.clone()).or_insert(MicroService {
-            name,
-            endpoints: HashSet::new(),
+        self.register(id, name, endpoints, None);
+    }
+
+    /// Same as `register_service` but balances the service with `strategy`,
+    /// replacing the strategy of an already registered service.
+    pub fn register_service_with_strategy(
+        &self,
+        id: String,
+        name: String,
+        endpoints: Vec<String>,
+        strategy: Strategy,
+    ) {
+        self.register(id, name, endpoints, Some(strategy));
+    }
+
+    fn register(
+        &self,
+        id: String,
+        name: String,
+        endpoints: Vec<String>,
+        strategy: Option<Strategy>,
+    ) {
+        self.update(&id, true, |services| {
+            let service = services
+                .entry(id.clone())
+                .or_insert_with(|| new_service(name));
+            for address in endpoints {
+                service
+                    .endpoints
+                    .entry(address.clone())
+                    .or_insert_with(|| Endpoint::new(address));
+            }
+            if let Some(strategy) = strategy {
+                service.balancer = strategy.build(&service.stats);
+                set_balancer_endpoints(service);
+            }
         });
-        for endpoint in endpoints {
-            service.endpoints.insert(endpoint);
+    }
+
+    pub fn set_strategy(&self, id: &str, strategy: Strategy) {
+        let mut services = self.services.lock().unwrap();
+        if let Some(service) = services.get_mut(id) {
+            service.balancer = strategy.build(&service.stats);
+            set_balancer_endpoints(service);
         }
     }
 
     pub fn deregister_service(&self, id: &str) {
-        let mut services = self.services.lock().unwrap();
-        services.remove(id);
+        self.update(id, true, |services| {
+            services.remove(id);
+        });
     }
 
+    /// Adds `endpoint` with default metadata, an endpoint already registered
+    /// keeps its metadata.
     pub fn add_endpoint(&self, id: &str, endpoint: String) {
+        self.update(id, true, |services| {
+            if let Some(service) = services.get_mut(id) {
+                service
+                    .endpoints
+                    .entry(endpoint.clone())
+                    .or_insert_with(|| Endpoint::new(endpoint));
+            }
+        });
+    }
+
+    /// Adds `endpoint` or replaces the metadata of the endpoint registered
+    /// under the same address.
+    pub fn set_endpoint(&self, id: &str, endpoint: Endpoint) {
+        self.update(id, true, |services| {
+            if let Some(service) = services.get_mut(id) {
+                service.endpoints.insert(endpoint.address.clone(), endpoint);
+            }
+        });
+    }
+
+    /// Takes the endpoint in or out of rotation, see `AdminState`.
+    pub fn set_endpoint_state(&self, id: &str, address: &str, state: AdminState) {
+        self.update(id, true, |services| {
+            let endpoint = services
+                .get_mut(id)
+                .and_then(|service| service.endpoints.get_mut(address));
+            if let Some(endpoint) = endpoint {
+                endpoint.state = state;
+            }
+        });
+    }
+
+    pub fn get_endpoint(&self, id: &str, address: &str) -> Option<Endpoint> {
+        let services = self.services.lock().unwrap();
+        services.get(id)?.endpoints.get(address).cloned()
+    }
+
+    pub fn remove_endpoint(&self, id: &str, endpoint: &str) {
+        self.update(id, true, |services| {
+            if let Some(service) = services.get_mut(id) {
+                service.endpoints.remove(endpoint);
+                service.stats.remove(endpoint);
+            }
+        });
+    }
+
+    /// Subscribes to membership changes, starting from a snapshot of every
+    /// service.
+    pub fn watch(&self) -> Watch {
+        let services = self.services.lock().unwrap();
+        let snapshot = services
+            .iter()
+            .map(|(id, service)| record(id, service))
+            .collect();
+        self.watchers.subscribe(snapshot)
+    }
+
+    /// Registry holding a copy of the services to make changes to aside,
+    /// they are only seen here once `commit`ted. Endpoint stats stay shared.
+    pub(crate) fn stage(&self) -> ServiceRegistry {
+        let services = self.services.lock().unwrap().clone();
+        ServiceRegistry {
+            services: Arc::new(Mutex::new(services)),
+            backend: Arc::new(InMemoryBackend::default()),
+            writer: None,
+            watchers: Watchers::default(),
+            origin: self.origin.clone(),
+        }
+    }
+
+    /// Swaps in the services `ids` as they are in `staged` under a single
+    /// lock, those `staged` doesn't have are removed. Requests see either
+    /// all of the services before or all of them after.
+    pub(crate) fn commit(&self, staged: &ServiceRegistry, ids: &[&str]) {
+        let staged = staged.services.lock().unwrap();
+        self.update_all(ids, true, |services| {
+            for id in ids {
+                match staged.get(*id) {
+                    Some(service) => services.insert(id.to_string(), service.clone()),
+                    None => services.remove(*id),
+                };
+            }
+        });
+    }
+
+    /// Runs `f` on the services, when it changed the record of service `id`
+    /// (see `ServiceRecord`) the watchers are told and, with `write`, the
+    /// backend too.
+    fn update<F>(&self, id: &str, write: bool, f: F)
+    where
+        F: FnOnce(&mut HashMap<String, MicroService>),
+    {
+        self.update_all(&[id], write, f);
+    }
+
+    /// Same as `update` for the records of several services.
+    fn update_all<F>(&self, ids: &[&str], write: bool, f: F)
+    where
+        F: FnOnce(&mut HashMap<String, MicroService>),
+    {
+        let changes: Vec<Change> = {
+            let mut services = self.services.lock().unwrap();
+            let before: Vec<Option<ServiceRecord>> = ids
+                .iter()
+                .map(|id| services.get(*id).map(|service| record(id, service)))
+                .collect();
+            f(&mut services);
+            ids.iter()
+                .zip(before)
+                .filter_map(|(id, before)| {
+                    let after = services.get(*id).map(|service| record(id, service));
+                    if before == after {
+                        return None;
+                    }
+                    if let (Some(service), Some(after)) = (services.get(*id), &after) {
+                        for endpoint in after.endpoints.values() {
+                            service
+                                .stats
+                                .get(&endpoint.address)
+                                .set_weight(endpoint.weight);
+                        }
+                        set_balancer_endpoints(service);
+                    }
+                    self.watchers
+                        .notify(RegistryEvent::diff(before.as_ref(), after.as_ref()));
+                    let origin = self.origin.to_string();
+                    Some(match after {
+                        Some(record) => Change::Put { origin, record },
+                        None => Change::Delete {
+                            origin,
+                            id: id.to_string(),
+                        },
+                    })
+                })
+                .collect()
+        };
+        if !write {
+            return;
+        }
+        for change in changes {
+            match &self.writer {
+                Some(writer) => {
+                    if writer.send(change).is_err() {
+                        warn!("registry backend writer is gone");
+                    }
+                }
+                None => {
+                    if let Err(err) = self.backend.apply(&change) {
+                        warn!("{}", err);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies a change another replica made, without writing it back.
+    pub(crate) fn apply_remote(&self, change: Change) {
+        match change {
+            Change::Put { record, .. } => self.update(&record.id.clone(), false, |services| {
+                let service = services
+                    .entry(record.id.clone())
+                    .or_insert_with(|| new_service(record.name.clone()));
+                service.name = record.name;
+                for gone in service.endpoints.keys() {
+                    if !record.endpoints.contains_key(gone) {
+                        service.stats.remove(gone);
+                    }
+                }
+                service.endpoints = record.endpoints.into_iter().collect();
+                service.traffic_split = record.traffic_split;
+            }),
+            Change::Delete { id, .. } => self.update(&id, false, |services| {
+                services.remove(&id);
+            }),
+        }
+    }
+
+    /// Makes the local services match every record of the backend.
+    pub(crate) fn reconcile(&self, records: Vec<ServiceRecord>) {
+        let ids: HashSet<String> = records.iter().map(|record| record.id.clone()).collect();
+        let stale: Vec<String> = {
+            let services = self.services.lock().unwrap();
+            services
+                .keys()
+                .filter(|id| !ids.contains(*id))
+                .cloned()
+                .collect()
+        };
+        for record in records {
+            self.apply_remote(Change::Put {
+                origin: String::new(),
+                record,
+            });
+        }
+        for id in stale {
+            self.apply_remote(Change::Delete {
+                origin: String::new(),
+                id,
+            });
+        }
+    }
+
+    /// Enables (or with `None` disables) active health checking of the
+    /// service's endpoints, only healthy endpoints are resolved.
+    pub fn set_health_check(&self, id: &str, check: Option<HealthCheck>) {
+        let mut services = self.services.lock().unwrap();
+        if let Some(service) = services.get_mut(id) {
+            if check.is_none() {
+                for stats in service.stats.all() {
+                    stats.reset_health();
+                }
+            }
+            service.health_check = check;
+        }
+    }
+
+    /// Enables (or with `None` disables) ejection of endpoints that keep
+    /// failing requests.
+    pub fn set_outlier_detection(&self, id: &str, detection: Option<OutlierDetection>) {
+        let mut services = self.services.lock().unwrap();
+        if let Some(service) = services.get_mut(id) {
+            service.outlier_detection = detection;
+        }
+    }
+
+    /// Enables (or with `None` disables) circuit breaking of each of the
+    /// service's endpoints.
+    pub fn set_circuit_breaker(&self, id: &str, breaker: Option<CircuitBreaker>) {
+        let mut services = self.services.lock().unwrap();
+        if let Some(service) = services.get_mut(id) {
+            service.circuit_breaker = breaker;
+        }
+    }
+
+    /// Splits (or with `None` stops splitting) the service's traffic across
+    /// its endpoint versions, see `TrafficSplit`. Like endpoints, the split
+    /// is written to the backend.
+    pub fn set_traffic_split(&self, id: &str, split: Option<TrafficSplit>) {
+        self.update(id, true, |services| {
+            if let Some(service) = services.get_mut(id) {
+                service.traffic_split = split;
+            }
+        });
+    }
+
+    /// Changes the weight of one version of the service's traffic split,
+    /// starting a split if the service has none.
+    pub fn set_subset_weight(&self, id: &str, version: &str, weight: u32) {
+        self.update(id, true, |services| {
+            if let Some(service) = services.get_mut(id) {
+                service
+                    .traffic_split
+                    .get_or_insert_with(TrafficSplit::default)
+                    .weights
+                    .insert(version.to_string(), weight);
+            }
+        });
+    }
+
+    /// Replaces the rules routing requests to subsets of the service's
+    /// endpoints by their headers, see `SubsetRule`. The first rule that
+    /// applies to a request wins.
+    pub fn set_subset_rules(&self, id: &str, rules: Vec<SubsetRule>) {
         let mut services = self.services.lock().unwrap();
         if let Some(service) = services.get_mut(id) {
-            service.endpoints.insert(endpoint);
+            service.subset_rules = rules;
         }
     }
 
+    /// Copies (or with `None` stops copying) a share of the service's
+    /// requests to another service, see `Mirror`.
+    pub fn set_mirror(&self, id: &str, mirror: Option<Mirror>) {
+        let mut services = self.services.lock().unwrap();
+        if let Some(service) = services.get_mut(id) {
+            service.mirror = mirror;
+        }
+    }
+
+    /// Mirror of the service and its counters, `None` when the next request
+    /// should not be copied.
+    pub(crate) fn sample_mirror(&self, id: &str) -> Option<(Mirror, Arc<MirrorStats>)> {
+        let services = self.services.lock().unwrap();
+        let service = services.get(id)?;
+        let mirror = service.mirror.as_ref().filter(|mirror| mirror.sample())?;
+        Some((mirror.clone(), service.mirror_stats.clone()))
+    }
+
+    /// Retries (or with `None` stops retrying) failed requests of the
+    /// service, see `RetryPolicy`.
+    pub fn set_retry_policy(&self, id: &str, policy: Option<RetryPolicy>) {
+        let mut services = self.services.lock().unwrap();
+        if let Some(service) = services.get_mut(id) {
+            service.retry_policy = policy;
+        }
+    }
+
+    pub(crate) fn retry_policy(&self, id: &str) -> Option<(RetryPolicy, Arc<RetryBudget>)> {
+        let services = self.services.lock().unwrap();
+        let service = services.get(id)?;
+        let policy = service.retry_policy.clone()?;
+        Some((policy, service.retry_budget.clone()))
+    }
+
+    /// Hedges (or with `None` stops hedging) requests to some methods of the
+    /// service, see `HedgePolicy`.
+    pub fn set_hedge_policy(&self, id: &str, policy: Option<HedgePolicy>) {
+        let mut services = self.services.lock().unwrap();
+        if let Some(service) = services.get_mut(id) {
+            service.hedge_policy = policy;
+        }
+    }
+
+    /// Connects to the endpoints of the service over TLS (or with `None` in
+    /// plaintext), see `UpstreamTlsConfig`. New connections use it, pooled
+    /// ones made without it are replaced.
+    pub fn set_upstream_tls(
+        &self,
+        id: &str,
+        config: Option<&UpstreamTlsConfig>,
+    ) -> Result<(), ConfigError> {
+        let tls = config.map(UpstreamTlsConfig::build).transpose()?;
+        let mut services = self.services.lock().unwrap();
+        if let Some(service) = services.get_mut(id) {
+            service.upstream_tls = tls.map(Arc::new);
+        }
+        Ok(())
+    }
+
+    /// Builds the upstream TLS of every service again whose certificate
+    /// files changed, new connections use it and pooled ones are replaced.
+    pub(crate) fn reload_upstream_tls(&self) {
+        let current: Vec<(String, Arc<ClientTls>)> = {
+            let services = self.services.lock().unwrap();
+            services
+                .iter()
+                .filter_map(|(id, service)| Some((id.clone(), service.upstream_tls.clone()?)))
+                .collect()
+        };
+        // the files are read without holding the services
+        for (id, tls) in current {
+            let reloaded = match tls.reload() {
+                Ok(Some(reloaded)) => reloaded,
+                Ok(None) => continue,
+                Err(err) => {
+                    error!("{}: keeping the current upstream tls: {}", id, err);
+                    continue;
+                }
+            };
+            let mut services = self.services.lock().unwrap();
+            let service = services.get_mut(&id).filter(|service| {
+                // unless a config reload replaced it meanwhile
+                service
+                    .upstream_tls
+                    .as_ref()
+                    .is_some_and(|current| Arc::ptr_eq(current, &tls))
+            });
+            if let Some(service) = service {
+                info!("{}: upstream tls reloaded", id);
+                service.upstream_tls = Some(Arc::new(reloaded));
+            }
+        }
+    }
+
+    /// Speaks `protocol` to the endpoints of the service. New connections
+    /// use it, pooled ones stay with the protocol they were opened for.
+    pub fn set_protocol(&self, id: &str, protocol: Protocol) {
+        let mut services = self.services.lock().unwrap();
+        if let Some(service) = services.get_mut(id) {
+            service.protocol = protocol;
+        }
+    }
+
+    /// Hedge policy of the service when it covers `path`, and its counters.
+    pub(crate) fn hedge_policy(
+        &self,
+        id: &str,
+        path: &str,
+    ) -> Option<(HedgePolicy, Arc<HedgeStats>)> {
+        let services = self.services.lock().unwrap();
+        let service = services.get(id)?;
+        let policy = service
+            .hedge_policy
+            .as_ref()
+            .filter(|policy| policy.applies(path))?;
+        Some((policy.clone(), service.hedge_stats.clone()))
+    }
+
     pub fn get_service(&self, id: &str) -> Option<MicroService> {
         let services = self.services.lock().unwrap();
         services.get(id).cloned()
@@ -51,18 +541,149 @@ impl ServiceRegistry {
     }
 
     pub fn resolve_endpoint(&self, id: String) -> Option<String> {
+        self.resolve(&id, &HeaderMap::new())
+            .map(|upstream| upstream.endpoint.clone())
+    }
+
+    /// Picks an endpoint of the service for a request with `headers`, out of
+    /// the endpoints that are healthy, not ejected and whose circuit breaker
+    /// lets requests through. A subset rule applying to the request narrows
+    /// them down to its subset, otherwise with a traffic split only the
+    /// endpoints of the version picked for the request are balanced between.
+    pub(crate) fn resolve(&self, id: &str, headers: &HeaderMap) -> Option<Upstream> {
+        self.resolve_excluding(id, headers, &[])
+    }
+
+    /// Same as `resolve` but avoids the `excluded` endpoints, unless there
+    /// is no other one to pick. Retries use it to go to another endpoint.
+    pub(crate) fn resolve_excluding(
+        &self,
+        id: &str,
+        headers: &HeaderMap,
+        excluded: &[String],
+    ) -> Option<Upstream> {
         let services = self.services.lock().unwrap();
-        if let Some(service) = services.get(id.as_str()) {
-            service.endpoints.clone().into_iter().next()
-        } else {
-            None
+        let service = services.get(id)?;
+        let now = Instant::now();
+        let mut available: Vec<&Endpoint> = service
+            .endpoints
+            .values()
+            .filter(|endpoint| endpoint.is_active())
+            .filter(|endpoint| {
+                let stats = service.stats.get(&endpoint.address);
+                stats.is_available(now)
+                    && service
+                        .circuit_breaker
+                        .as_ref()
+                        .is_none_or(|config| stats.breaker_allows(config, now))
+            })
+            .collect();
+        match subset::select(&service.subset_rules, &available, headers) {
+            Some(selected) => available = selected,
+            None => {
+                if let Some(split) = &service.traffic_split {
+                    let version = split.pick(&available, headers)?.to_string();
+                    available.retain(|endpoint| endpoint.version.as_ref() == Some(&version));
+                }
+            }
+        }
+        let mut endpoints: Vec<String> = available
+            .into_iter()
+            .map(|endpoint| endpoint.address.clone())
+            .collect();
+        if endpoints.is_empty() {
+            return None;
+        }
+        if endpoints
+            .iter()
+            .any(|endpoint| !excluded.contains(endpoint))
+        {
+            endpoints.retain(|endpoint| !excluded.contains(endpoint));
         }
+        endpoints.sort();
+        let endpoint = service.balancer.pick(&endpoints, headers)?;
+        let stats = service.stats.get(&endpoint);
+        // only the picked endpoint moves to half-open and gets probed
+        let probe = service
+            .circuit_breaker
+            .as_ref()
+            .is_some_and(|config| stats.acquire_probe(config, now));
+        Some(Upstream {
+            id: id.to_string(),
+            endpoint,
+            stats,
+            service_stats: service.stats.clone(),
+            endpoints: service.endpoints.len(),
+            outlier: service.outlier_detection.clone(),
+            breaker: service.circuit_breaker.clone(),
+            probe,
+            reported: AtomicBool::new(false),
+            tls: service.upstream_tls.clone(),
+            protocol: service.protocol,
+        })
+    }
+}
+
+/// Tells the service's balancer all of its endpoints, see
+/// `LoadBalancer::set_endpoints`.
+fn set_balancer_endpoints(service: &MicroService) {
+    let mut endpoints: Vec<String> = service.endpoints.keys().cloned().collect();
+    endpoints.sort();
+    service.balancer.set_endpoints(&endpoints);
+}
+
+fn new_service(name: String) -> MicroService {
+    let stats = StatsTable::default();
+    MicroService {
+        name,
+        endpoints: HashMap::new(),
+        balancer: Strategy::default().build(&stats),
+        stats,
+        health_check: None,
+        outlier_detection: None,
+        circuit_breaker: None,
+        traffic_split: None,
+        subset_rules: vec![],
+        mirror: None,
+        mirror_stats: Arc::default(),
+        retry_policy: None,
+        retry_budget: Arc::default(),
+        hedge_policy: None,
+        hedge_stats: Arc::default(),
+        upstream_tls: None,
+        protocol: Protocol::default(),
+    }
+}
+
+fn record(id: &str, service: &MicroService) -> ServiceRecord {
+    ServiceRecord {
+        id: id.to_string(),
+        name: service.name.clone(),
+        endpoints: service
+            .endpoints
+            .iter()
+            .map(|(address, endpoint)| (address.clone(), endpoint.clone()))
+            .collect(),
+        traffic_split: service.traffic_split.clone(),
     }
 }
 
 #[cfg(test)]
 mod tests_registry {
-    use crate::ServiceRegistry;
+    use std::{collections::HashMap, time::Duration};
+
+    use hyper::HeaderMap;
+
+    use crate::{
+        balancer::Strategy,
+        breaker::{BreakerState, CircuitBreaker, Trip},
+        endpoint::{AdminState, Endpoint},
+        health::HealthCheck,
+        outlier::OutlierDetection,
+        subset::{Selector, SubsetRule},
+        watch::{RegistryEvent, WATCH_BUFFER},
+        ServiceRegistry,
+    };
 
     #[test]
     fn test_new() {
@@ -79,4 +700,404 @@ mod tests_registry {
         );
         assert_eq!(srg.get_all_services().len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let srg = ServiceRegistry::default();
+        srg.register_service(
+            "kyc.Kyc".to_string(),
+            "kyc".to_string(),
+            vec!["10.0.0.1:50051".to_string()],
+        );
+        let mut watch = srg.watch();
+        assert_eq!(watch.snapshot().len(), 1);
+        assert!(watch.snapshot()[0].endpoints.contains_key("10.0.0.1:50051"));
+
+        srg.add_endpoint("kyc.Kyc", "10.0.0.1:50051".to_string());
+        srg.add_endpoint("kyc.Kyc", "10.0.0.2:50051".to_string());
+        srg.deregister_service("kyc.Kyc");
+        srg.register_service("ping.Ping".to_string(), "ping".to_string(), vec![]);
+        drop(srg);
+
+        let mut events = vec![];
+        while let Some(event) = watch.next().await {
+            events.push(event);
+        }
+        let endpoint = |endpoint: &str| endpoint.to_string();
+        assert_eq!(
+            events,
+            vec![
+                RegistryEvent::EndpointAdded {
+                    id: "kyc.Kyc".to_string(),
+                    endpoint: endpoint("10.0.0.2:50051"),
+                },
+                RegistryEvent::EndpointRemoved {
+                    id: "kyc.Kyc".to_string(),
+                    endpoint: endpoint("10.0.0.1:50051"),
+                },
+                RegistryEvent::EndpointRemoved {
+                    id: "kyc.Kyc".to_string(),
+                    endpoint: endpoint("10.0.0.2:50051"),
+                },
+                RegistryEvent::ServiceRemoved {
+                    id: "kyc.Kyc".to_string(),
+                },
+                RegistryEvent::ServiceAdded {
+                    id: "ping.Ping".to_string(),
+                    name: "ping".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_falling_behind() {
+        let srg = ServiceRegistry::default();
+        srg.register_service("kyc.Kyc".to_string(), "kyc".to_string(), vec![]);
+        let mut slow = srg.watch();
+        for i in 0..=WATCH_BUFFER {
+            srg.add_endpoint("kyc.Kyc", format!("10.0.0.1:{}", i));
+        }
+        let mut events = 0;
+        while slow.next().await.is_some() {
+            events += 1;
+        }
+        assert_eq!(events, WATCH_BUFFER);
+
+        // watching again catches up from the snapshot
+        let mut watch = srg.watch();
+        assert_eq!(watch.snapshot()[0].endpoints.len(), WATCH_BUFFER + 1);
+        srg.deregister_service("kyc.Kyc");
+        assert!(matches!(
+            watch.next().await,
+            Some(RegistryEvent::EndpointRemoved { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_stage_and_commit() {
+        let srg = ServiceRegistry::default();
+        srg.register_service(
+            "kyc.Kyc".to_string(),
+            "kyc".to_string(),
+            vec!["a:1".to_string()],
+        );
+        srg.register_service("old".to_string(), "old".to_string(), vec![]);
+        let staged = srg.stage();
+        staged.remove_endpoint("kyc.Kyc", "a:1");
+        staged.add_endpoint("kyc.Kyc", "b:1".to_string());
+        staged.deregister_service("old");
+        // nothing shows before the commit
+        assert_eq!(
+            srg.resolve_endpoint("kyc.Kyc".to_string()),
+            Some("a:1".to_string())
+        );
+        assert!(srg.get_service("old").is_some());
+
+        let mut watch = srg.watch();
+        srg.commit(&staged, &["kyc.Kyc"]);
+        assert_eq!(
+            srg.resolve_endpoint("kyc.Kyc".to_string()),
+            Some("b:1".to_string())
+        );
+        assert!(srg.get_service("old").is_some());
+        srg.commit(&staged, &["old"]);
+        assert!(srg.get_service("old").is_none());
+        drop(srg);
+        let mut events = vec![];
+        while let Some(event) = watch.next().await {
+            events.push(event);
+        }
+        assert_eq!(events.len(), 3);
+    }
+
+    #[test]
+    fn test_resolve_round_robin() {
+        let srg = ServiceRegistry::default();
+        srg.register_service(
+            "ping-pong".to_string(),
+            "Ping".to_string(),
+            vec!["b:1".to_string(), "a:1".to_string()],
+        );
+        let picks: Vec<Option<String>> = (0..3)
+            .map(|_| srg.resolve_endpoint("ping-pong".to_string()))
+            .collect();
+        assert_eq!(
+            picks,
+            vec![
+                Some("a:1".to_string()),
+                Some("b:1".to_string()),
+                Some("a:1".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_healthy_only() {
+        let srg = ServiceRegistry::default();
+        srg.register_service(
+            "ping-pong".to_string(),
+            "Ping".to_string(),
+            vec!["a:1".to_string(), "b:1".to_string()],
+        );
+        let check = HealthCheck {
+            fall: 1,
+            ..Default::default()
+        };
+        srg.set_health_check("ping-pong", Some(check.clone()));
+        let service = srg.get_service("ping-pong").unwrap();
+        service
+            .stats()
+            .get("a:1")
+            .record_health_check(false, &check);
+        for _ in 0..3 {
+            assert_eq!(
+                srg.resolve_endpoint("ping-pong".to_string()),
+                Some("b:1".to_string())
+            );
+        }
+
+        service
+            .stats()
+            .get("b:1")
+            .record_health_check(false, &check);
+        assert_eq!(srg.resolve_endpoint("ping-pong".to_string()), None);
+    }
+
+    #[test]
+    fn test_resolve_skips_ejected() {
+        let srg = ServiceRegistry::default();
+        srg.register_service(
+            "ping-pong".to_string(),
+            "Ping".to_string(),
+            vec!["a:1".to_string(), "b:1".to_string()],
+        );
+        srg.set_outlier_detection(
+            "ping-pong",
+            Some(OutlierDetection {
+                consecutive_failures: 2,
+                ..Default::default()
+            }),
+        );
+        let headers = HeaderMap::new();
+        for _ in 0..4 {
+            let upstream = srg.resolve("ping-pong", &headers).unwrap();
+            upstream.report(upstream.endpoint != "a:1");
+        }
+        for _ in 0..3 {
+            assert_eq!(
+                srg.resolve_endpoint("ping-pong".to_string()),
+                Some("b:1".to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn test_resolve_all_breakers_open() {
+        let srg = ServiceRegistry::default();
+        srg.register_service(
+            "ping-pong".to_string(),
+            "Ping".to_string(),
+            vec!["a:1".to_string(), "b:1".to_string()],
+        );
+        srg.set_circuit_breaker(
+            "ping-pong",
+            Some(CircuitBreaker {
+                trip: Trip::ConsecutiveFailures(1),
+                ..Default::default()
+            }),
+        );
+        let headers = HeaderMap::new();
+        for _ in 0..2 {
+            srg.resolve("ping-pong", &headers).unwrap().report(false);
+        }
+        assert!(srg.resolve("ping-pong", &headers).is_none());
+    }
+
+    #[test]
+    fn test_resolve_probes_picked_endpoint_only() {
+        let srg = ServiceRegistry::default();
+        srg.register_service(
+            "ping-pong".to_string(),
+            "Ping".to_string(),
+            vec!["a:1".to_string(), "b:1".to_string()],
+        );
+        srg.set_circuit_breaker(
+            "ping-pong",
+            Some(CircuitBreaker {
+                trip: Trip::ConsecutiveFailures(1),
+                open_for: Duration::ZERO,
+                ..Default::default()
+            }),
+        );
+        let headers = HeaderMap::new();
+        let stats = srg.get_service("ping-pong").unwrap().stats().clone();
+        let config = srg
+            .get_service("ping-pong")
+            .unwrap()
+            .circuit_breaker()
+            .cloned();
+        for endpoint in ["a:1", "b:1"] {
+            stats
+                .get(endpoint)
+                .record_breaker(false, false, config.as_ref().unwrap());
+        }
+        let probe = srg.resolve("ping-pong", &headers).unwrap();
+        assert!(probe.probe);
+        let other = if probe.endpoint == "a:1" {
+            "b:1"
+        } else {
+            "a:1"
+        };
+        assert_eq!(
+            stats.get(&probe.endpoint).breaker_state(),
+            BreakerState::HalfOpen
+        );
+        assert_eq!(stats.get(other).breaker_state(), BreakerState::Open);
+    }
+
+    #[test]
+    fn test_resolve_active_only() {
+        let srg = ServiceRegistry::default();
+        srg.register_service(
+            "ping-pong".to_string(),
+            "Ping".to_string(),
+            vec!["a:1".to_string(), "b:1".to_string()],
+        );
+        srg.set_endpoint_state("ping-pong", "a:1", AdminState::Draining);
+        for _ in 0..3 {
+            assert_eq!(
+                srg.resolve_endpoint("ping-pong".to_string()),
+                Some("b:1".to_string())
+            );
+        }
+
+        srg.set_endpoint_state("ping-pong", "b:1", AdminState::Disabled);
+        assert_eq!(srg.resolve_endpoint("ping-pong".to_string()), None);
+        srg.set_endpoint_state("ping-pong", "a:1", AdminState::Active);
+        assert_eq!(
+            srg.resolve_endpoint("ping-pong".to_string()),
+            Some("a:1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_registered_weights() {
+        let srg = ServiceRegistry::default();
+        srg.register_service_with_strategy(
+            "ping-pong".to_string(),
+            "Ping".to_string(),
+            vec!["a:1".to_string()],
+            Strategy::WeightedRoundRobin(HashMap::new()),
+        );
+        srg.set_endpoint(
+            "ping-pong",
+            Endpoint {
+                weight: 3,
+                zone: Some("eu-west-1a".to_string()),
+                ..Endpoint::new("b:1")
+            },
+        );
+        let endpoint = srg.get_endpoint("ping-pong", "b:1").unwrap();
+        assert_eq!(endpoint.zone.as_deref(), Some("eu-west-1a"));
+
+        let mut counts: HashMap<String, usize> = HashMap::new();
+        for _ in 0..40 {
+            let endpoint = srg.resolve_endpoint("ping-pong".to_string()).unwrap();
+            *counts.entry(endpoint).or_default() += 1;
+        }
+        assert_eq!(counts["a:1"], 10);
+        assert_eq!(counts["b:1"], 30);
+    }
+
+    #[test]
+    fn test_resolve_traffic_split() {
+        let srg = ServiceRegistry::default();
+        srg.register_service("ping-pong".to_string(), "Ping".to_string(), vec![]);
+        for (address, version) in [("a:1", "v1"), ("b:1", "v1"), ("c:1", "v2")] {
+            srg.set_endpoint(
+                "ping-pong",
+                Endpoint {
+                    version: Some(version.to_string()),
+                    ..Endpoint::new(address)
+                },
+            );
+        }
+        srg.set_subset_weight("ping-pong", "v1", 100);
+        for _ in 0..10 {
+            let endpoint = srg.resolve_endpoint("ping-pong".to_string()).unwrap();
+            assert_ne!(endpoint, "c:1");
+        }
+
+        srg.set_subset_weight("ping-pong", "v1", 0);
+        srg.set_subset_weight("ping-pong", "v2", 1);
+        assert_eq!(
+            srg.resolve_endpoint("ping-pong".to_string()),
+            Some("c:1".to_string())
+        );
+        srg.set_endpoint_state("ping-pong", "c:1", AdminState::Draining);
+        assert_eq!(srg.resolve_endpoint("ping-pong".to_string()), None);
+
+        srg.set_traffic_split("ping-pong", None);
+        assert!(srg.resolve_endpoint("ping-pong".to_string()).is_some());
+    }
+
+    #[test]
+    fn test_resolve_subset_rules() {
+        let srg = ServiceRegistry::default();
+        srg.register_service("ping-pong".to_string(), "Ping".to_string(), vec![]);
+        for (address, version) in [("a:1", "v1"), ("b:1", "staging")] {
+            srg.set_endpoint(
+                "ping-pong",
+                Endpoint {
+                    version: Some(version.to_string()),
+                    ..Endpoint::new(address)
+                },
+            );
+        }
+        srg.set_subset_weight("ping-pong", "v1", 1);
+        srg.set_subset_rules(
+            "ping-pong",
+            vec![SubsetRule {
+                header: "x-debug-backend".to_string(),
+                value: None,
+                select: Selector::Address,
+                fallback: false,
+            }],
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert("x-debug-backend", "b:1".parse().unwrap());
+        for _ in 0..3 {
+            assert_eq!(srg.resolve("ping-pong", &headers).unwrap().endpoint, "b:1");
+            assert_eq!(
+                srg.resolve_endpoint("ping-pong".to_string()),
+                Some("a:1".to_string())
+            );
+        }
+
+        headers.insert("x-debug-backend", "c:1".parse().unwrap());
+        assert!(srg.resolve("ping-pong", &headers).is_none());
+    }
+
+    #[test]
+    fn test_resolve_excluding() {
+        let srg = ServiceRegistry::default();
+        srg.register_service(
+            "ping-pong".to_string(),
+            "Ping".to_string(),
+            vec!["a:1".to_string(), "b:1".to_string()],
+        );
+        let headers = HeaderMap::new();
+        let tried = vec!["a:1".to_string()];
+        for _ in 0..3 {
+            let upstream = srg
+                .resolve_excluding("ping-pong", &headers, &tried)
+                .unwrap();
+            assert_eq!(upstream.endpoint, "b:1");
+        }
+        let tried = vec!["a:1".to_string(), "b:1".to_string()];
+        assert!(srg
+            .resolve_excluding("ping-pong", &headers, &tried)
+            .is_some());
+    }
 }
@@ -0,0 +1,420 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use http_body_util::{combinators::BoxBody, BodyExt};
+use hyper::{
+    body::{Body, Bytes, Frame},
+    HeaderMap, StatusCode,
+};
+use serde::Deserialize;
+
+use crate::upstream::GRPC_UNAVAILABLE;
+
+const GRPC_RESOURCE_EXHAUSTED: &str = "8";
+
+/// Time over which a retry budget saves up unused retries.
+const BUDGET_WINDOW: Duration = Duration::from_secs(10);
+
+/// Failure a request can be retried on.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// No connection to the endpoint could be made, nothing was sent.
+    ConnectFailure,
+    /// The connection failed or the stream was reset before a response.
+    Reset,
+    /// `UNAVAILABLE` (or HTTP 503) in the response headers.
+    Unavailable,
+    /// `RESOURCE_EXHAUSTED` in the response headers.
+    ResourceExhausted,
+}
+
+/// Retries of failed requests, on another endpoint of the service when it
+/// has one. Only statuses in the response headers (trailers-only gRPC
+/// responses) are retried, a response that started streaming is final.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Attempts made at most, the first one included.
+    pub max_attempts: u32,
+    pub retry_on: Vec<RetryOn>,
+    /// Backoff before the first retry, doubled for each one after it up to
+    /// `max_backoff`. The wait is picked at random below the backoff.
+    #[serde(with = "crate::config::duration")]
+    pub base_backoff: Duration,
+    #[serde(with = "crate::config::duration")]
+    pub max_backoff: Duration,
+    /// Retries allowed on top of the service's requests, in percent of them.
+    pub budget_percent: f64,
+    /// Retries allowed per second whatever the budget, so a service with
+    /// little traffic can still retry.
+    pub min_retries_per_second: u32,
+    /// Request bodies are kept up to this size to be sent again. A larger
+    /// body is only retried while none of it has been sent.
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            retry_on: vec![RetryOn::ConnectFailure, RetryOn::Unavailable],
+            base_backoff: Duration::from_millis(25),
+            max_backoff: Duration::from_millis(250),
+            budget_percent: 20.0,
+            min_retries_per_second: 10,
+            max_buffered_bytes: 64 * 1024,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Wait before the `retry`th retry, with full jitter.
+    pub(crate) fn backoff(&self, retry: u32) -> Duration {
+        let exponent = retry.saturating_sub(1).min(16);
+        let backoff = self
+            .base_backoff
+            .saturating_mul(1 << exponent)
+            .min(self.max_backoff);
+        backoff.mul_f64(rand::random::<f64>())
+    }
+}
+
+/// Retryable failure a response carries in its headers.
+pub(crate) fn response_failure(status: StatusCode, headers: &HeaderMap) -> Option<RetryOn> {
+    if status == StatusCode::SERVICE_UNAVAILABLE {
+        return Some(RetryOn::Unavailable);
+    }
+    match headers.get("grpc-status")?.to_str().ok()? {
+        GRPC_UNAVAILABLE => Some(RetryOn::Unavailable),
+        GRPC_RESOURCE_EXHAUSTED => Some(RetryOn::ResourceExhausted),
+        _ => None,
+    }
+}
+
+/// Retries a service can still make, earned by its requests so retries
+/// stay a share of the traffic and can't turn into a retry storm.
+#[derive(Debug)]
+pub struct RetryBudget {
+    state: Mutex<BudgetState>,
+    retries: AtomicU64,
+    refused: AtomicU64,
+}
+
+#[derive(Debug)]
+struct BudgetState {
+    /// Retries saved up.
+    tokens: f64,
+    stamp: Instant,
+    /// Requests deposited since `window_start`, and in the window before.
+    requests: f64,
+    previous: f64,
+    window_start: Instant,
+}
+
+impl Default for RetryBudget {
+    fn default() -> Self {
+        RetryBudget {
+            state: Mutex::new(BudgetState {
+                tokens: 0.0,
+                stamp: Instant::now(),
+                requests: 0.0,
+                previous: 0.0,
+                window_start: Instant::now(),
+            }),
+            retries: AtomicU64::default(),
+            refused: AtomicU64::default(),
+        }
+    }
+}
+
+impl RetryBudget {
+    /// Retries made.
+    pub fn retries(&self) -> u64 {
+        self.retries.load(Ordering::Relaxed)
+    }
+
+    /// Retries not made because the budget was spent.
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+
+    /// Earns the budget share of a request.
+    pub(crate) fn deposit(&self, policy: &RetryPolicy) {
+        let mut state = self.state.lock().unwrap();
+        Self::refill(&mut state, policy, 1.0);
+    }
+
+    /// Takes one retry out of the budget, `false` when it is spent.
+    pub(crate) fn withdraw(&self, policy: &RetryPolicy) -> bool {
+        let mut state = self.state.lock().unwrap();
+        Self::refill(&mut state, policy, 0.0);
+        if state.tokens < 1.0 {
+            self.refused.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        state.tokens -= 1.0;
+        self.retries.fetch_add(1, Ordering::Relaxed);
+        true
+    }
+
+    /// Adds the share of `requests` new requests and the minimum rate since
+    /// the last refill. At most the retries earned over one `BUDGET_WINDOW`
+    /// are saved up, so a quiet service can't build up a burst of them.
+    fn refill(state: &mut BudgetState, policy: &RetryPolicy, requests: f64) {
+        let now = Instant::now();
+        let window = BUDGET_WINDOW.as_secs_f64();
+        let windows =
+            now.saturating_duration_since(state.window_start).as_secs() / BUDGET_WINDOW.as_secs();
+        if windows > 0 {
+            state.previous = if windows == 1 { state.requests } else { 0.0 };
+            state.requests = 0.0;
+            state.window_start += BUDGET_WINDOW * windows as u32;
+        }
+        state.requests += requests;
+        // the window before counts for the part of it within the last
+        // BUDGET_WINDOW
+        let into_window = now.saturating_duration_since(state.window_start);
+        let counted = state.requests + state.previous * (1.0 - into_window.as_secs_f64() / window);
+
+        let elapsed = now.saturating_duration_since(state.stamp).as_secs_f64();
+        let share = policy.budget_percent / 100.0;
+        let rate = f64::from(policy.min_retries_per_second);
+        let cap = (share * counted + rate * window).max(1.0);
+        state.tokens = (state.tokens + share * requests + rate * elapsed).min(cap);
+        state.stamp = now;
+    }
+}
+
+#[derive(Debug)]
+enum Recorded {
+    Data(Bytes),
+    Trailers(HeaderMap),
+}
+
+struct ReplayState {
+    inner: BoxBody<Bytes, hyper::Error>,
+    /// Frames read so far, while they fit the limit.
+    frames: Vec<Recorded>,
+    read: usize,
+    buffered: usize,
+    limit: usize,
+    replayable: bool,
+    ended: bool,
+    attempt: u64,
+}
+
+/// Request body shared by the attempts of a request. Frames are kept as
+/// they are read so the next attempt can send them again.
+#[derive(Clone)]
+pub(crate) struct Replay(Arc<Mutex<ReplayState>>);
+
+impl Replay {
+    /// Keeps up to `limit` bytes of `inner`.
+    pub(crate) fn new(inner: BoxBody<Bytes, hyper::Error>, limit: usize) -> Self {
+        Replay(Arc::new(Mutex::new(ReplayState {
+            inner,
+            frames: vec![],
+            read: 0,
+            buffered: 0,
+            limit,
+            replayable: true,
+            ended: false,
+            attempt: 0,
+        })))
+    }
+
+    /// Body of the next attempt, the bodies of earlier attempts end.
+    pub(crate) fn attempt(&self) -> BoxBody<Bytes, hyper::Error> {
+        let mut state = self.0.lock().unwrap();
+        state.attempt += 1;
+        ReplayBody {
+            replay: self.clone(),
+            attempt: state.attempt,
+            position: 0,
+        }
+        .boxed()
+    }
+
+    /// Whether another attempt can send the whole body.
+    pub(crate) fn can_retry(&self) -> bool {
+        self.0.lock().unwrap().replayable
+    }
+
+    /// Body of an attempt made alongside the current one, only once the
+    /// whole body has been read and kept.
+    pub(crate) fn hedge(&self) -> Option<BoxBody<Bytes, hyper::Error>> {
+        let state = self.0.lock().unwrap();
+        // hyper stops polling a body that reports its end with the last frame
+        let complete = state.ended || state.inner.is_end_stream();
+        if !(complete && state.replayable) {
+            return None;
+        }
+        let body = ReplayBody {
+            replay: self.clone(),
+            attempt: state.attempt,
+            position: 0,
+        };
+        Some(body.boxed())
+    }
+}
+
+struct ReplayBody {
+    replay: Replay,
+    attempt: u64,
+    position: usize,
+}
+
+impl Body for ReplayBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.get_mut();
+        let mut state = this.replay.0.lock().unwrap();
+        if this.attempt != state.attempt {
+            return Poll::Ready(None);
+        }
+        if let Some(recorded) = state.frames.get(this.position) {
+            this.position += 1;
+            let frame = match recorded {
+                Recorded::Data(data) => Frame::data(data.clone()),
+                Recorded::Trailers(trailers) => Frame::trailers(trailers.clone()),
+            };
+            return Poll::Ready(Some(Ok(frame)));
+        }
+        if this.position < state.read || state.ended {
+            return Poll::Ready(None);
+        }
+        let state = &mut *state;
+        let polled = Pin::new(&mut state.inner).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => {
+                this.position += 1;
+                state.read += 1;
+                if state.replayable {
+                    let recorded = match (frame.data_ref(), frame.trailers_ref()) {
+                        (Some(data), _) => Some(Recorded::Data(data.clone())),
+                        (_, Some(trailers)) => Some(Recorded::Trailers(trailers.clone())),
+                        _ => None,
+                    };
+                    state.buffered += frame.data_ref().map_or(0, Bytes::len);
+                    state.frames.extend(recorded);
+                    if state.buffered > state.limit {
+                        state.replayable = false;
+                        state.frames = vec![];
+                    }
+                }
+            }
+            Poll::Ready(Some(Err(_))) => state.replayable = false,
+            Poll::Ready(None) => state.ended = true,
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        let state = self.replay.0.lock().unwrap();
+        self.attempt != state.attempt
+            || (self.position >= state.read && (state.ended || state.inner.is_end_stream()))
+    }
+}
+
+#[cfg(test)]
+mod tests_retry {
+    use http_body_util::{Full, StreamBody};
+    use tokio::sync::mpsc;
+    use tokio_stream::wrappers::ReceiverStream;
+
+    use super::*;
+
+    #[test]
+    fn test_budget() {
+        let policy = RetryPolicy {
+            budget_percent: 50.0,
+            min_retries_per_second: 0,
+            ..Default::default()
+        };
+        let budget = RetryBudget::default();
+        assert!(!budget.withdraw(&policy));
+        for _ in 0..4 {
+            budget.deposit(&policy);
+        }
+        // half of the requests deposited can be retried
+        assert!(budget.withdraw(&policy));
+        assert!(budget.withdraw(&policy));
+        assert!(!budget.withdraw(&policy));
+        assert_eq!((budget.retries(), budget.refused()), (2, 2));
+
+        for _ in 0..20 {
+            budget.deposit(&policy);
+        }
+        for _ in 0..10 {
+            assert!(budget.withdraw(&policy));
+        }
+        assert!(!budget.withdraw(&policy));
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy::default();
+        for retry in 1..10 {
+            assert!(policy.backoff(retry) <= policy.max_backoff);
+        }
+        assert!(policy.backoff(1) <= policy.base_backoff);
+    }
+
+    #[test]
+    fn test_response_failure() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(response_failure(StatusCode::OK, &headers), None);
+        assert_eq!(
+            response_failure(StatusCode::SERVICE_UNAVAILABLE, &headers),
+            Some(RetryOn::Unavailable)
+        );
+        headers.insert("grpc-status", "8".parse().unwrap());
+        assert_eq!(
+            response_failure(StatusCode::OK, &headers),
+            Some(RetryOn::ResourceExhausted)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let body = Full::new(Bytes::from("ping"))
+            .map_err(|never| match never {})
+            .boxed();
+        let replay = Replay::new(body, 16);
+        let first = replay.attempt();
+        assert_eq!(first.collect().await.unwrap().to_bytes(), "ping");
+        assert!(replay.can_retry());
+        let second = replay.attempt();
+        let hedge = replay.hedge().unwrap();
+        assert_eq!(second.collect().await.unwrap().to_bytes(), "ping");
+        assert_eq!(hedge.collect().await.unwrap().to_bytes(), "ping");
+
+        // a body over the limit can't be sent again once it has been read
+        let (tx, rx) = mpsc::channel::<Result<Frame<Bytes>, hyper::Error>>(2);
+        let replay = Replay::new(StreamBody::new(ReceiverStream::new(rx)).boxed(), 2);
+        assert!(replay.can_retry());
+        tx.send(Ok(Frame::data(Bytes::from("ping")))).await.unwrap();
+        drop(tx);
+        let mut first = replay.attempt();
+        first.frame().await.unwrap().unwrap();
+        assert!(!replay.can_retry());
+        assert!(replay.hedge().is_none());
+        let mut second = replay.attempt();
+        assert!(first.frame().await.is_none());
+        assert!(second.frame().await.is_none());
+    }
+}
@@ -0,0 +1,166 @@
+use std::sync::{Arc, RwLock};
+
+use regex::Regex;
+
+use crate::deadline::DeadlinePolicy;
+
+/// How a route matches the full `/package.Service/Method` request path.
+#[derive(Clone, Debug)]
+pub enum PathMatch {
+    Exact(String),
+    Prefix(String),
+    Regex(Regex),
+}
+
+impl PathMatch {
+    fn matches(&self, path: &str) -> bool {
+        match self {
+            PathMatch::Exact(p) => p == path,
+            PathMatch::Prefix(p) => path.starts_with(p.as_str()),
+            PathMatch::Regex(re) => re.is_match(path),
+        }
+    }
+}
+
+/// Sends requests whose path matches to the service registered as `service`.
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub matcher: PathMatch,
+    pub service: String,
+    /// Deadline of the route's requests, the router's own when unset.
+    pub deadline: Option<DeadlinePolicy>,
+}
+
+impl Route {
+    pub fn exact(path: &str, service: &str) -> Self {
+        Route {
+            matcher: PathMatch::Exact(with_slash(path)),
+            service: service.to_string(),
+            deadline: None,
+        }
+    }
+
+    pub fn prefix(path: &str, service: &str) -> Self {
+        Route {
+            matcher: PathMatch::Prefix(with_slash(path)),
+            service: service.to_string(),
+            deadline: None,
+        }
+    }
+
+    pub fn regex(pattern: &str, service: &str) -> Result<Self, regex::Error> {
+        Ok(Route {
+            matcher: PathMatch::Regex(Regex::new(pattern)?),
+            service: service.to_string(),
+            deadline: None,
+        })
+    }
+
+    pub fn with_deadline(mut self, deadline: DeadlinePolicy) -> Self {
+        self.deadline = Some(deadline);
+        self
+    }
+}
+
+fn with_slash(path: &str) -> String {
+    if path.starts_with('/') {
+        path.to_string()
+    } else {
+        format!("/{}", path)
+    }
+}
+
+/// Ordered routing rules on top of the service registry, the first matching
+/// route wins. Paths no route matches go to the service named by their first
+/// segment (`/kyc.Kyc/ping` -> `kyc.Kyc`).
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Arc<RwLock<Vec<Route>>>,
+    deadline: Arc<RwLock<DeadlinePolicy>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Appends a route, it only applies to paths no earlier route matched.
+    pub fn add_route(&self, route: Route) {
+        self.routes.write().unwrap().push(route);
+    }
+
+    /// Replaces every route at once.
+    pub fn set_routes(&self, routes: Vec<Route>) {
+        *self.routes.write().unwrap() = routes;
+    }
+
+    pub fn routes(&self) -> Vec<Route> {
+        self.routes.read().unwrap().clone()
+    }
+
+    /// Deadline of the requests of routes without their own.
+    pub fn set_deadline(&self, deadline: DeadlinePolicy) {
+        *self.deadline.write().unwrap() = deadline;
+    }
+
+    /// Id of the service that serves `path`.
+    pub fn route(&self, path: &str) -> String {
+        self.lookup(path).0
+    }
+
+    /// Id of the service that serves `path` and the deadline of its
+    /// requests.
+    pub(crate) fn lookup(&self, path: &str) -> (String, DeadlinePolicy) {
+        let routes = self.routes.read().unwrap();
+        let default = *self.deadline.read().unwrap();
+        if let Some(route) = routes.iter().find(|route| route.matcher.matches(path)) {
+            return (route.service.clone(), route.deadline.unwrap_or(default));
+        }
+        let path = path.strip_prefix('/').unwrap_or(path);
+        let service = path.split('/').next().unwrap_or_default().to_string();
+        (service, default)
+    }
+}
+
+#[cfg(test)]
+mod tests_router {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_first_match() {
+        let router = Router::new();
+        router.add_route(Route::exact("kyc.Kyc/register", "kyc.Kyc.write"));
+        router.add_route(Route::regex(r"^/kyc\.Kyc/(ping|get\w*)$", "kyc.Kyc.read").unwrap());
+        router.add_route(Route::prefix("/kyc.Kyc/", "kyc.Kyc.default"));
+        router.add_route(Route::exact("/kyc.Kyc/ping", "unreachable"));
+
+        assert_eq!(router.route("/kyc.Kyc/register"), "kyc.Kyc.write");
+        assert_eq!(router.route("/kyc.Kyc/ping"), "kyc.Kyc.read");
+        assert_eq!(router.route("/kyc.Kyc/getUser"), "kyc.Kyc.read");
+        assert_eq!(router.route("/kyc.Kyc/delete"), "kyc.Kyc.default");
+        assert_eq!(router.route("/ping.Ping/ping"), "ping.Ping");
+    }
+
+    #[test]
+    fn test_route_deadline() {
+        let router = Router::new();
+        let slow = DeadlinePolicy {
+            max: Some(Duration::from_secs(60)),
+            ..Default::default()
+        };
+        router.add_route(Route::exact("/kyc.Kyc/export", "kyc.Kyc").with_deadline(slow));
+        let default = DeadlinePolicy {
+            default: Some(Duration::from_secs(1)),
+            max: Some(Duration::from_secs(5)),
+        };
+        router.set_deadline(default);
+
+        assert_eq!(router.lookup("/kyc.Kyc/export").1, slow);
+        assert_eq!(
+            router.lookup("/kyc.Kyc/get"),
+            ("kyc.Kyc".to_string(), default)
+        );
+    }
+}
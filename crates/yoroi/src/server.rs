@@ -1,22 +1,36 @@
 use http_body_util::combinators::BoxBody;
+use hyper::service::service_fn;
 use hyper::upgrade::Upgraded;
-use hyper::{server::conn::http2, service::service_fn};
 use std::sync::Arc;
 use std::time::Duration;
 use std::{error::Error, net::SocketAddr, str::FromStr};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::mpsc::{self, Receiver, Sender};
 use tokio::task::JoinHandle;
-use tokio::time::sleep;
+use tokio::time::{sleep, timeout_at, Instant};
 use tracing::{debug, error, info};
 
+use futures::stream::{FuturesUnordered, StreamExt};
 use http_body_util::{BodyExt, Empty, Full};
 use hyper::body::Bytes;
+use hyper::http::request::Parts;
 use hyper::{Method, Request, Response, StatusCode, Uri};
 use hyper_util::rt::TokioIo;
+use hyper_util::server::conn::auto;
 
+use crate::body::TrackedBody;
+use crate::deadline::{self, DeadlineBody};
+use crate::error::{BoxError, ProxyError};
+use crate::hedge::{HedgePolicy, HedgeStats};
+use crate::pool::ConnectionPool;
 use crate::registry::ServiceRegistry;
-use crate::utils;
+use crate::retry::{self, Replay, RetryOn};
+use crate::router::Router;
+use crate::stats::InFlightGuard;
+use crate::tls::CertResolver;
+use crate::upstream::Upstream;
+use crate::{backend, health, mirror, protocol, tls, upstream, utils};
 
 #[derive(Clone)]
 pub struct ShutdownHandler(Sender<Option<usize>>);
@@ -34,7 +48,7 @@ impl ShutdownHandler {
 }
 
 #[derive(Clone)]
-struct TokioExecutor;
+pub(crate) struct TokioExecutor;
 
 impl<F> hyper::rt::Executor<F> for TokioExecutor
 where
@@ -51,6 +65,9 @@ pub struct Server {
     sender: Sender<Option<usize>>,
     receiver: Arc<tokio::sync::Mutex<Receiver<Option<usize>>>>,
     pub(crate) registry: ServiceRegistry,
+    pub(crate) router: Router,
+    pub(crate) pool: ConnectionPool,
+    pub(crate) tls: Option<Arc<CertResolver>>,
 }
 
 impl Default for Server {
@@ -66,6 +83,9 @@ impl Server {
             sender,
             receiver: Arc::new(tokio::sync::Mutex::new(receiver)),
             registry: ServiceRegistry::new(),
+            router: Router::new(),
+            pool: ConnectionPool::default(),
+            tls: None,
         }
     }
 
@@ -96,6 +116,21 @@ impl Server {
             .await;
         });
 
+        let health_checker = tokio::spawn(health::run(self.registry.clone()));
+        let pool_reaper = tokio::spawn(self.pool.clone().run_reaper());
+        let pool_evictor = tokio::spawn(self.pool.clone().run_evictor(self.registry.clone()));
+        let acceptor = self.tls.as_ref().map(|resolver| resolver.acceptor());
+        let tls_reloader = self
+            .tls
+            .clone()
+            .map(|resolver| tokio::spawn(tls::run_reloader(resolver)));
+        let upstream_tls_reloader = tokio::spawn(tls::run_upstream_reloader(self.registry.clone()));
+        let registry_sync = self
+            .registry
+            .backend()
+            .is_shared()
+            .then(|| tokio::spawn(backend::sync(self.registry.clone())));
+
         let mut tasks: Vec<JoinHandle<()>> = vec![];
         info!("Yoroi started on: {}", address);
 
@@ -104,15 +139,19 @@ impl Server {
             tokio::select! {
                 result = listener.accept() => {
                     match result {
-                        Ok((stream, _)) => {
-                            let io = TokioIo::new(stream);
+                        Ok((stream, peer)) => {
                             let sreg = self.registry.clone();
+                            let router = self.router.clone();
+                            let pool = self.pool.clone();
+                            let acceptor = acceptor.clone();
                             let task =  tokio::task::spawn(async move {
-                                if let Err(err) = http2::Builder::new(TokioExecutor)
-                                    .serve_connection(io, service_fn(|req| proxy(sreg.clone(), req)))
-                                    .await
-                                {
-                                    error!("Error serving connection: {}", err);
+                                match acceptor {
+                                    Some(acceptor) => match tokio::time::timeout(tls::HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                                        Ok(Ok(stream)) => serve_connection(stream, sreg, router, pool).await,
+                                        Ok(Err(err)) => debug!("TLS handshake with {} failed: {}", peer, err),
+                                        Err(_) => debug!("TLS handshake with {} timed out", peer),
+                                    },
+                                    None => serve_connection(stream, sreg, router, pool).await,
                                 }
                             });
                             tasks.push(task);
@@ -129,6 +168,16 @@ impl Server {
                 }
             }
         }
+        health_checker.abort();
+        pool_reaper.abort();
+        pool_evictor.abort();
+        if let Some(tls_reloader) = tls_reloader {
+            tls_reloader.abort();
+        }
+        upstream_tls_reloader.abort();
+        if let Some(registry_sync) = registry_sync {
+            registry_sync.abort();
+        }
         // Wait for all tasks to finish before shutting down
         for task in tasks {
             let _ = task.await;
@@ -137,19 +186,61 @@ impl Server {
     }
 }
 
+/// Serves the requests of a client connection, plaintext or TLS, in
+/// HTTP/1.1 or HTTP/2 as the client speaks it.
+async fn serve_connection<S>(stream: S, sreg: ServiceRegistry, router: Router, pool: ConnectionPool)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(|req| proxy(sreg.clone(), router.clone(), pool.clone(), req));
+    if let Err(err) = auto::Builder::new(TokioExecutor)
+        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+        .await
+    {
+        error!("Error serving connection: {}", err);
+    }
+}
+
 async fn proxy(
     sreg: ServiceRegistry,
+    router: Router,
+    pool: ConnectionPool,
     req: Request<hyper::body::Incoming>,
-) -> Result<Response<BoxBody<Bytes, hyper::Error>>, hyper::Error> {
-    let mut id = req.uri().path();
-    if id.starts_with("/") {
-        id = &id[1..];
+) -> Result<Response<BoxBody<Bytes, BoxError>>, hyper::Error> {
+    let grpc = protocol::is_grpc(req.headers());
+    match forward(sreg, router, pool, req, grpc).await {
+        Ok(resp) => Ok(resp),
+        Err(err) => {
+            error!("{}", err);
+            let resp = match grpc {
+                true => err.into_response(),
+                false => err.into_http_response(),
+            };
+            Ok(boxed(resp))
+        }
     }
-    id = id.split("/").collect::<Vec<&str>>()[0];
+}
 
-    let endpoint = match sreg.resolve_endpoint(id.to_string()) {
-        Some(uri) => uri,
-        None => return Ok(Response::new(empty())),
+async fn forward(
+    sreg: ServiceRegistry,
+    router: Router,
+    pool: ConnectionPool,
+    req: Request<hyper::body::Incoming>,
+    grpc: bool,
+) -> Result<Response<BoxBody<Bytes, BoxError>>, ProxyError> {
+    let (id, deadline) = router.lookup(req.uri().path());
+    let id = id.as_str();
+    let deadline = deadline
+        .timeout(req.headers())
+        .map(|timeout| Instant::now() + timeout);
+
+    let upstream = match sreg.resolve(id, req.headers()) {
+        Some(upstream) => upstream,
+        // known service, but every endpoint is down or has its breaker open
+        None if sreg.get_service(id).is_some() => {
+            return Err(ProxyError::NoEndpoint(id.to_string()))
+        }
+        None => return Err(ProxyError::UnknownService(id.to_string())),
     };
 
     if Method::CONNECT == req.method() {
@@ -165,37 +256,324 @@ async fn proxy(
                 }
             });
 
-            Ok(Response::new(empty()))
+            Ok(boxed(Response::new(empty())))
         } else {
             eprintln!("CONNECT host is not socket addr: {:?}", req.uri());
             let mut resp = Response::new(full("CONNECT must be to a socket address"));
             *resp.status_mut() = StatusCode::BAD_REQUEST;
 
-            Ok(resp)
+            Ok(boxed(resp))
         }
     } else {
-        let stream = match TcpStream::connect(endpoint).await {
-            Ok(s) => s,
-            Err(err) => panic!("{}", err),
+        let mut req = req.map(|b| b.boxed());
+        if let Some((mirror, stats)) = sreg.sample_mirror(id) {
+            if let Some(permit) = stats.admit() {
+                let (original, copy, aborted) = mirror::tee(req);
+                let copied = mirror::send(
+                    sreg.clone(),
+                    pool.clone(),
+                    mirror,
+                    stats,
+                    copy,
+                    aborted,
+                    deadline,
+                );
+                tokio::spawn(async move {
+                    let _permit = permit;
+                    copied.await
+                });
+                req = original;
+            }
+        }
+
+        let retry = sreg.retry_policy(id);
+        if let Some((policy, budget)) = &retry {
+            budget.deposit(policy);
+        }
+        let hedge = sreg.hedge_policy(id, req.uri().path());
+        let limit = retry
+            .as_ref()
+            .map_or(0, |(policy, _)| policy.max_buffered_bytes)
+            .max(
+                hedge
+                    .as_ref()
+                    .map_or(0, |(policy, _)| policy.max_buffered_bytes),
+            );
+        let (mut parts, body) = req.into_parts();
+        protocol::adapt(&mut parts, upstream.protocol);
+        let replay = Replay::new(body, limit);
+        let mut next = upstream;
+        let mut tried = vec![];
+        let mut attempts = 1;
+        // dropped at the deadline, which cancels the attempts in flight
+        let exchange = async {
+            loop {
+                let (upstream, guard, result) = match &hedge {
+                    Some((policy, stats)) => {
+                        let hedged = Hedged {
+                            sreg: &sreg,
+                            pool: &pool,
+                            id,
+                            parts: &parts,
+                            replay: &replay,
+                            deadline,
+                            policy,
+                            stats,
+                        };
+                        hedged.send(next, &mut tried).await
+                    }
+                    None => {
+                        let req = request(&parts, replay.attempt(), deadline);
+                        launch(pool.clone(), next, req).await
+                    }
+                };
+                let (failure, result) = match result {
+                    Ok(resp) => match retry::response_failure(resp.status(), resp.headers()) {
+                        Some(failure) => (failure, Ok(resp)),
+                        None => return Ok(respond(resp, upstream, guard)),
+                    },
+                    Err((failure, err)) => (failure, Err(err)),
+                };
+
+                let outcome = result
+                    .as_ref()
+                    .ok()
+                    .and_then(|resp| upstream::response_outcome(resp.status(), resp.headers()));
+                let retried = match &retry {
+                    Some((policy, budget))
+                        if attempts < policy.max_attempts
+                            && policy.retry_on.contains(&failure)
+                            && replay.can_retry() =>
+                    {
+                        tried.push(upstream.endpoint.clone());
+                        sreg.resolve_excluding(id, &parts.headers, &tried)
+                            .filter(|_| budget.withdraw(policy))
+                            .map(|next| (next, policy.backoff(attempts)))
+                    }
+                    _ => None,
+                };
+                let (retried, backoff) = match (retried, result) {
+                    (Some(retried), _) => retried,
+                    (None, Ok(resp)) => return Ok(respond(resp, upstream, guard)),
+                    (None, Err(err)) => return Err(err),
+                };
+                if let Some(success) = outcome {
+                    upstream.report(success);
+                }
+                debug!(
+                    "{}: retrying on {} after {:?} from {}",
+                    id, retried.endpoint, failure, upstream.endpoint
+                );
+                drop(guard);
+                sleep(backoff).await;
+                next = retried;
+                attempts += 1;
+            }
+        };
+        let resp = match deadline {
+            Some(deadline) => timeout_at(deadline, exchange)
+                .await
+                .unwrap_or(Err(ProxyError::DeadlineExceeded))?,
+            None => exchange.await?,
         };
-        let io = TokioIo::new(stream);
-
-        let (mut sender, conn) = hyper::client::conn::http2::Builder::new(TokioExecutor)
-            .handshake(io)
-            .await?;
-        tokio::task::spawn(async move {
-            if let Err(err) = conn.await {
-                println!("Connection failed: {:?}", err);
+        Ok(match deadline {
+            Some(deadline) => resp.map(|body| DeadlineBody::new(body, deadline, grpc).boxed()),
+            None => boxed(resp),
+        })
+    }
+}
+
+/// Outcome of an attempt, with the endpoint it went to.
+type Attempted = (
+    Upstream,
+    InFlightGuard,
+    Result<Response<hyper::body::Incoming>, (RetryOn, ProxyError)>,
+);
+
+/// Copy of the request for an attempt with `body`, telling a gRPC upstream
+/// what is left of the `deadline`.
+fn request(
+    parts: &Parts,
+    body: BoxBody<Bytes, hyper::Error>,
+    deadline: Option<Instant>,
+) -> Request<BoxBody<Bytes, hyper::Error>> {
+    let mut req = Request::new(body);
+    *req.method_mut() = parts.method.clone();
+    *req.uri_mut() = parts.uri.clone();
+    *req.version_mut() = parts.version;
+    *req.headers_mut() = parts.headers.clone();
+    // `grpc-timeout` means nothing to other upstreams
+    if let Some(deadline) = deadline.filter(|_| protocol::is_grpc(&parts.headers)) {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        req.headers_mut()
+            .insert(deadline::GRPC_TIMEOUT, deadline::encode(remaining));
+    }
+    req
+}
+
+/// Sends `req` to `upstream`, counted in flight until the returned guard
+/// is dropped once the response body has been fully streamed back.
+async fn launch(
+    pool: ConnectionPool,
+    upstream: Upstream,
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+) -> Attempted {
+    let guard = upstream.stats.start();
+    let result = attempt(&pool, &upstream, req).await;
+    (upstream, guard, result)
+}
+
+/// Attempt of a request to a hedged method, see `HedgePolicy`.
+struct Hedged<'a> {
+    sreg: &'a ServiceRegistry,
+    pool: &'a ConnectionPool,
+    id: &'a str,
+    parts: &'a Parts,
+    replay: &'a Replay,
+    deadline: Option<Instant>,
+    policy: &'a HedgePolicy,
+    stats: &'a HedgeStats,
+}
+
+impl Hedged<'_> {
+    /// Sends the request to `upstream` then, each time the delay passes
+    /// without an answer, to another endpoint not in `tried`. The first
+    /// answer that isn't a retryable failure wins, the attempts still in
+    /// flight are dropped, which resets their streams.
+    async fn send(&self, upstream: Upstream, tried: &mut Vec<String>) -> Attempted {
+        let first = upstream.endpoint.clone();
+        tried.push(first.clone());
+        let mut pending = FuturesUnordered::new();
+        let req = request(self.parts, self.replay.attempt(), self.deadline);
+        pending.push(launch(self.pool.clone(), upstream, req));
+        let mut sent = 1;
+        let delay = sleep(self.policy.delay);
+        tokio::pin!(delay);
+        loop {
+            tokio::select! {
+                Some((upstream, guard, result)) = pending.next() => {
+                    let failure = match &result {
+                        Ok(resp) => retry::response_failure(resp.status(), resp.headers()),
+                        Err((failure, _)) => Some(*failure),
+                    };
+                    if failure.is_none() || pending.is_empty() {
+                        if upstream.endpoint != first {
+                            self.stats.record_win();
+                        }
+                        return (upstream, guard, result);
+                    }
+                    // another attempt may still answer, this one is done
+                    if let Ok(resp) = &result {
+                        if let Some(success) =
+                            upstream::response_outcome(resp.status(), resp.headers())
+                        {
+                            upstream.report(success);
+                        }
+                    }
+                }
+                () = &mut delay, if sent < self.policy.max_attempts => {
+                    let body = match self.replay.hedge() {
+                        Some(body) => body,
+                        // the body is still streaming, hedge once it is read
+                        None if self.replay.can_retry() => {
+                            delay.as_mut().reset(Instant::now() + self.policy.delay);
+                            continue;
+                        }
+                        // the body is too large to send again, the attempts
+                        // in flight have to do
+                        None => {
+                            sent = self.policy.max_attempts;
+                            continue;
+                        }
+                    };
+                    let next = self
+                        .sreg
+                        .resolve_excluding(self.id, &self.parts.headers, tried)
+                        .filter(|next| !tried.contains(&next.endpoint));
+                    let next = match next {
+                        Some(next) => next,
+                        // no other endpoint is left
+                        None => {
+                            sent = self.policy.max_attempts;
+                            continue;
+                        }
+                    };
+                    debug!(
+                        "{}: hedging on {} after {:?}",
+                        self.id, next.endpoint, self.policy.delay
+                    );
+                    self.stats.record_hedge();
+                    tried.push(next.endpoint.clone());
+                    let req = request(self.parts, body, self.deadline);
+                    pending.push(launch(self.pool.clone(), next, req));
+                    sent += 1;
+                    delay.as_mut().reset(Instant::now() + self.policy.delay);
+                }
             }
-        });
+        }
+    }
+}
 
-        let resp = sender.send_request(req).await?;
-        Ok(resp.map(|b| b.boxed()))
+/// Sends `req` to `upstream`, failures are reported and come with what
+/// they could be retried on.
+async fn attempt(
+    pool: &ConnectionPool,
+    upstream: &Upstream,
+    req: Request<BoxBody<Bytes, hyper::Error>>,
+) -> Result<Response<hyper::body::Incoming>, (RetryOn, ProxyError)> {
+    let mut sender = match pool
+        .get(&upstream.endpoint, upstream.tls.as_ref(), upstream.protocol)
+        .await
+    {
+        Ok(sender) => sender,
+        Err(source) => {
+            upstream.report(false);
+            let err = ProxyError::Connect {
+                endpoint: upstream.endpoint.clone(),
+                source,
+            };
+            return Err((RetryOn::ConnectFailure, err));
+        }
+    };
+    let start = Instant::now();
+    match sender.send_request(req).await {
+        Ok(resp) => {
+            upstream.stats.observe_latency(start.elapsed());
+            Ok(resp)
+        }
+        Err(err) => {
+            upstream.report(false);
+            Err((RetryOn::Reset, err.into()))
+        }
     }
 }
 
+/// Streams `resp` back to the client, reporting its outcome to `upstream`.
+fn respond(
+    mut resp: Response<hyper::body::Incoming>,
+    upstream: Upstream,
+    guard: InFlightGuard,
+) -> Response<BoxBody<Bytes, hyper::Error>> {
+    protocol::strip_hop_by_hop(resp.headers_mut());
+    // the gRPC status of a streamed response only comes with its trailers
+    let pending = match upstream::response_outcome(resp.status(), resp.headers()) {
+        Some(success) => {
+            upstream.report(success);
+            None
+        }
+        None => Some(upstream),
+    };
+    resp.map(|b| TrackedBody::new(b.boxed(), guard, pending).boxed())
+}
+
 fn host_addr(uri: &Uri) -> Option<String> {
-    uri.authority().and_then(|auth| Some(auth.to_string()))
+    uri.authority().map(|auth| auth.to_string())
+}
+
+/// Response to a client, whose body may end in an error of the gateway's
+/// own rather than the upstream's.
+fn boxed(resp: Response<BoxBody<Bytes, hyper::Error>>) -> Response<BoxBody<Bytes, BoxError>> {
+    resp.map(|body| body.map_err(BoxError::from).boxed())
 }
 
 fn empty() -> BoxBody<Bytes, hyper::Error> {
@@ -234,7 +612,14 @@ async fn tunnel(upgraded: Upgraded, addr: String) -> std::io::Result<()> {
 mod tests {
     use std::time::Duration;
 
+    use http_body_util::StreamBody;
+    use hyper::body::{Frame, Incoming};
+    use hyper::client::conn::{http1, http2};
+    use tokio_stream::wrappers::ReceiverStream;
+
     use super::*;
+    use crate::protocol::Protocol;
+
     #[tokio::test]
     async fn test_start_server() {
         let server = Server::new();
@@ -246,4 +631,193 @@ mod tests {
             .graceful_shutdown(Some(Duration::from_secs(3)))
             .await;
     }
+
+    /// HTTP/1.1 endpoint answering with the `Host` and URI it got.
+    async fn serve_http1() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let host = req.headers()["host"].to_str().unwrap().to_string();
+                    Ok::<_, hyper::Error>(Response::new(full(format!("{} {}", host, req.uri()))))
+                });
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        addr.to_string()
+    }
+
+    /// HTTP/2 endpoint reading every request, answering them only when
+    /// `answer`.
+    async fn serve_http2(answer: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service_fn(move |req: Request<Incoming>| async move {
+                    req.into_body().collect().await?;
+                    if !answer {
+                        std::future::pending::<()>().await;
+                    }
+                    Ok::<_, hyper::Error>(Response::new(full("pong")))
+                });
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor)
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        addr.to_string()
+    }
+
+    async fn text(resp: Response<Incoming>) -> Bytes {
+        resp.into_body().collect().await.unwrap().to_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_http1_and_http2_clients() {
+        let sreg = ServiceRegistry::new();
+        sreg.register_service("users".into(), "users".into(), vec![serve_http1().await]);
+        sreg.set_protocol("users", Protocol::Http1);
+        let (router, pool) = (Router::new(), ConnectionPool::default());
+        let connect = || {
+            let (client, server) = tokio::io::duplex(64 * 1024);
+            tokio::spawn(serve_connection(
+                server,
+                sreg.clone(),
+                router.clone(),
+                pool.clone(),
+            ));
+            TokioIo::new(client)
+        };
+
+        let (mut sender, conn) = http1::handshake(connect()).await.unwrap();
+        tokio::spawn(conn);
+        let req = Request::get("/users/1?full=1")
+            .header("host", "api.example.com")
+            .body(empty())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(text(resp).await, "api.example.com /users/1?full=1");
+        // plain HTTP clients get an HTTP status rather than a gRPC one
+        let req = Request::get("/orders/1")
+            .header("host", "api.example.com")
+            .body(empty())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let (mut sender, conn) = http2::handshake(TokioExecutor, connect()).await.unwrap();
+        tokio::spawn(conn);
+        let req = Request::get("http://api.example.com/users/2")
+            .body(empty())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(text(resp).await, "api.example.com /users/2");
+    }
+
+    #[tokio::test]
+    async fn test_hedge_after_streamed_body() {
+        let sreg = ServiceRegistry::new();
+        sreg.register_service(
+            "kyc.Kyc".into(),
+            "kyc".into(),
+            vec![serve_http2(false).await],
+        );
+        let policy = HedgePolicy {
+            methods: vec!["/kyc.Kyc/get".to_string()],
+            delay: Duration::from_millis(20),
+            ..Default::default()
+        };
+        sreg.set_hedge_policy("kyc.Kyc", Some(policy));
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve_connection(
+            server,
+            sreg.clone(),
+            Router::new(),
+            ConnectionPool::default(),
+        ));
+        let (mut sender, conn) = http2::handshake(TokioExecutor, TokioIo::new(client))
+            .await
+            .unwrap();
+        tokio::spawn(conn);
+
+        // the body is still streaming when the first delay passes, the
+        // request is hedged once it is read
+        let (tx, rx) = mpsc::channel::<Result<Frame<Bytes>, hyper::Error>>(1);
+        let req = Request::post("http://kyc/kyc.Kyc/get")
+            .body(StreamBody::new(ReceiverStream::new(rx)))
+            .unwrap();
+        let resp = tokio::spawn(async move { sender.send_request(req).await });
+        sleep(Duration::from_millis(50)).await;
+        sreg.add_endpoint("kyc.Kyc", serve_http2(true).await);
+        tx.send(Ok(Frame::data(Bytes::from("ping")))).await.unwrap();
+        drop(tx);
+        let resp = tokio::time::timeout(Duration::from_secs(2), resp)
+            .await
+            .expect("the request was never hedged")
+            .unwrap()
+            .unwrap();
+        assert_eq!(text(resp).await, "pong");
+        let service = sreg.get_service("kyc.Kyc").unwrap();
+        assert_eq!(service.hedge_stats().wins(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_deadline_without_grpc() {
+        // HTTP/1.1 endpoint telling whether it got a `grpc-timeout`, its
+        // response body never ends
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let service = service_fn(|req: Request<Incoming>| async move {
+                    let got = req.headers().contains_key(deadline::GRPC_TIMEOUT);
+                    let body = futures::stream::pending::<Result<Frame<Bytes>, hyper::Error>>();
+                    let resp = Response::builder()
+                        .header("x-got-grpc-timeout", got.to_string())
+                        .body(StreamBody::new(body))
+                        .unwrap();
+                    Ok::<_, hyper::Error>(resp)
+                });
+                tokio::spawn(
+                    hyper::server::conn::http1::Builder::new()
+                        .serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+        let sreg = ServiceRegistry::new();
+        sreg.register_service("users".into(), "users".into(), vec![addr.to_string()]);
+        sreg.set_protocol("users", Protocol::Http1);
+        let router = Router::new();
+        router.set_deadline(deadline::DeadlinePolicy {
+            default: Some(Duration::from_millis(100)),
+            max: None,
+        });
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        tokio::spawn(serve_connection(
+            server,
+            sreg,
+            router,
+            ConnectionPool::default(),
+        ));
+        let (mut sender, conn) = http1::handshake(TokioIo::new(client)).await.unwrap();
+        tokio::spawn(conn);
+
+        let req = Request::get("/users/1")
+            .header("host", "api.example.com")
+            .body(empty())
+            .unwrap();
+        let resp = sender.send_request(req).await.unwrap();
+        assert_eq!(resp.headers()["x-got-grpc-timeout"], "false");
+        // the response is aborted at the deadline rather than ended
+        let body = tokio::time::timeout(Duration::from_secs(2), resp.into_body().collect());
+        assert!(body.await.unwrap().is_err());
+    }
 }
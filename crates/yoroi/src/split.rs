@@ -0,0 +1,127 @@
+use std::collections::BTreeMap;
+
+use hyper::HeaderMap;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{balancer, endpoint::Endpoint};
+
+/// Split of a service's traffic across subsets of its endpoints by version,
+/// e.g. 95% to `v1` and 5% to a `v2` canary.
+///
+/// Endpoints whose version has no weight get no traffic. A subset without
+/// an endpoint available gets none either, its share goes to the others.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TrafficSplit {
+    /// Weight of each version, relative to the sum of all weights.
+    pub weights: BTreeMap<String, u32>,
+    /// Header (gRPC metadata) whose value keeps a client on one subset,
+    /// e.g. `x-user-id`. Requests without it are split at random.
+    pub sticky: Option<String>,
+}
+
+impl TrafficSplit {
+    /// Version the request with `headers` goes to, out of the versions of
+    /// `endpoints`.
+    pub(crate) fn pick(&self, endpoints: &[&Endpoint], headers: &HeaderMap) -> Option<&str> {
+        let subsets: Vec<(&str, u64)> = self
+            .weights
+            .iter()
+            .filter(|(version, weight)| {
+                **weight > 0
+                    && endpoints
+                        .iter()
+                        .any(|endpoint| endpoint.version.as_ref() == Some(*version))
+            })
+            .map(|(version, weight)| (version.as_str(), u64::from(*weight)))
+            .collect();
+        let total: u64 = subsets.iter().map(|(_, weight)| weight).sum();
+        if total == 0 {
+            return None;
+        }
+        let key = self
+            .sticky
+            .as_ref()
+            .and_then(|header| headers.get(header.as_str()));
+        // a sticky client keeps its place in [0, total) as weights change,
+        // so shifting weight to a subset only moves the clients at the edge
+        let mut point = match key {
+            Some(key) => {
+                ((u128::from(balancer::hash(key.as_bytes())) * u128::from(total)) >> 64) as u64
+            }
+            None => rand::thread_rng().gen_range(0..total),
+        };
+        for (version, weight) in subsets {
+            if point < weight {
+                return Some(version);
+            }
+            point -= weight;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests_split {
+    use std::collections::HashMap;
+
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    fn endpoint(address: &str, version: &str) -> Endpoint {
+        Endpoint {
+            version: Some(version.to_string()),
+            ..Endpoint::new(address)
+        }
+    }
+
+    #[test]
+    fn test_weighted_split() {
+        let split = TrafficSplit {
+            weights: BTreeMap::from([("v1".to_string(), 95), ("v2".to_string(), 5)]),
+            sticky: None,
+        };
+        let (v1, v2) = (endpoint("a:1", "v1"), endpoint("b:1", "v2"));
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for _ in 0..10_000 {
+            let version = split.pick(&[&v1, &v2], &HeaderMap::new()).unwrap();
+            *counts.entry(version).or_default() += 1;
+        }
+        assert!((300..700).contains(&counts["v2"]), "{:?}", counts);
+
+        assert_eq!(split.pick(&[&v1], &HeaderMap::new()), Some("v1"));
+        let v3 = endpoint("c:1", "v3");
+        assert_eq!(split.pick(&[&v3], &HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_sticky_split() {
+        let mut split = TrafficSplit {
+            weights: BTreeMap::from([("v1".to_string(), 50), ("v2".to_string(), 50)]),
+            sticky: Some("x-user-id".to_string()),
+        };
+        let (v1, v2) = (endpoint("a:1", "v1"), endpoint("b:1", "v2"));
+        let pick = |split: &TrafficSplit, user: usize| {
+            let mut headers = HeaderMap::new();
+            headers.insert("x-user-id", HeaderValue::from(user));
+            split.pick(&[&v1, &v2], &headers).unwrap().to_string()
+        };
+        let before: Vec<String> = (0..100).map(|user| pick(&split, user)).collect();
+        assert!(before.iter().any(|version| version == "v1"));
+        assert!(before.iter().any(|version| version == "v2"));
+        assert_eq!(
+            before,
+            (0..100).map(|user| pick(&split, user)).collect::<Vec<_>>()
+        );
+
+        // growing v2 only moves clients from v1 to v2
+        split.weights.insert("v2".to_string(), 80);
+        for (user, version) in before.iter().enumerate() {
+            if version == "v2" {
+                assert_eq!(pick(&split, user), "v2");
+            }
+        }
+    }
+}
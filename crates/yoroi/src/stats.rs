@@ -0,0 +1,253 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    breaker::{Breaker, BreakerState, CircuitBreaker},
+    health::{HealthCheck, HealthState},
+    outlier::{OutlierDetection, OutlierState},
+};
+
+/// Time it takes for a latency sample to lose ~63% of its weight.
+const EWMA_DECAY: Duration = Duration::from_secs(10);
+
+/// Live traffic counters and health of a single endpoint of a service.
+#[derive(Debug)]
+pub struct EndpointStats {
+    in_flight: AtomicUsize,
+    requests: AtomicU64,
+    latency: Mutex<Option<Ewma>>,
+    health: Mutex<HealthState>,
+    outlier: Mutex<OutlierState>,
+    breaker: Mutex<Breaker>,
+    // registered weight, mirrored here for the balancers
+    weight: AtomicU32,
+}
+
+impl Default for EndpointStats {
+    fn default() -> Self {
+        EndpointStats {
+            in_flight: AtomicUsize::default(),
+            requests: AtomicU64::default(),
+            latency: Mutex::default(),
+            health: Mutex::default(),
+            outlier: Mutex::default(),
+            breaker: Mutex::default(),
+            weight: AtomicU32::new(1),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Ewma {
+    nanos: f64,
+    stamp: Instant,
+}
+
+impl Ewma {
+    fn decayed(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.stamp).as_secs_f64();
+        self.nanos * (-elapsed / EWMA_DECAY.as_secs_f64()).exp()
+    }
+}
+
+impl EndpointStats {
+    /// Requests sent to the endpoint whose response has not finished streaming.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// Total number of requests sent to the endpoint.
+    pub fn requests(&self) -> u64 {
+        self.requests.load(Ordering::Relaxed)
+    }
+
+    /// Weight the endpoint is registered with.
+    pub fn weight(&self) -> u32 {
+        self.weight.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_weight(&self, weight: u32) {
+        self.weight.store(weight, Ordering::Relaxed);
+    }
+
+    /// Peak EWMA of the time the endpoint takes to answer with response
+    /// headers, decayed towards zero while no new samples come in so a slow
+    /// endpoint eventually gets probed again.
+    pub fn latency(&self) -> Option<Duration> {
+        let latency = self.latency.lock().unwrap();
+        latency
+            .as_ref()
+            .map(|ewma| Duration::from_nanos(ewma.decayed(Instant::now()) as u64))
+    }
+
+    pub(crate) fn observe_latency(&self, rtt: Duration) {
+        let now = Instant::now();
+        let rtt = rtt.as_nanos() as f64;
+        let mut latency = self.latency.lock().unwrap();
+        let nanos = match latency.as_ref() {
+            // a slower sample than the average is taken as is, so spikes are
+            // reacted to immediately and only recovery is smoothed
+            Some(ewma) if rtt < ewma.nanos => {
+                let elapsed = now.saturating_duration_since(ewma.stamp).as_secs_f64();
+                let w = (-elapsed / EWMA_DECAY.as_secs_f64()).exp();
+                ewma.nanos * w + rtt * (1.0 - w)
+            }
+            _ => rtt,
+        };
+        *latency = Some(Ewma { nanos, stamp: now });
+    }
+
+    /// Whether active health checks consider the endpoint fit for traffic.
+    pub fn is_healthy(&self) -> bool {
+        self.health.lock().unwrap().is_healthy()
+    }
+
+    /// Records an active health check result, returns true when the endpoint
+    /// flipped between healthy and unhealthy.
+    pub(crate) fn record_health_check(&self, passed: bool, check: &HealthCheck) -> bool {
+        self.health.lock().unwrap().record(passed, check)
+    }
+
+    pub(crate) fn reset_health(&self) {
+        *self.health.lock().unwrap() = HealthState::default();
+    }
+
+    /// Whether outlier detection took the endpoint out of rotation.
+    pub fn is_ejected(&self, now: Instant) -> bool {
+        self.outlier.lock().unwrap().is_ejected(now)
+    }
+
+    /// Whether the endpoint can be picked for new requests.
+    pub fn is_available(&self, now: Instant) -> bool {
+        self.is_healthy() && !self.is_ejected(now)
+    }
+
+    pub(crate) fn record_success(&self, detection: &OutlierDetection) {
+        let mut outlier = self.outlier.lock().unwrap();
+        outlier.record_success(detection, Instant::now());
+    }
+
+    /// Records a failed request, returns the ejection time when the endpoint
+    /// got ejected because of it.
+    pub(crate) fn record_failure<F>(
+        &self,
+        detection: &OutlierDetection,
+        can_eject: F,
+    ) -> Option<Duration>
+    where
+        F: FnOnce() -> bool,
+    {
+        let mut outlier = self.outlier.lock().unwrap();
+        outlier.record_failure(detection, Instant::now(), can_eject)
+    }
+
+    pub fn breaker_state(&self) -> BreakerState {
+        self.breaker.lock().unwrap().state()
+    }
+
+    pub(crate) fn breaker_allows(&self, config: &CircuitBreaker, now: Instant) -> bool {
+        self.breaker.lock().unwrap().allows(config, now)
+    }
+
+    /// Admits a request picked for the endpoint, returns whether it took a
+    /// half-open probe slot.
+    pub(crate) fn acquire_probe(&self, config: &CircuitBreaker, now: Instant) -> bool {
+        self.breaker.lock().unwrap().acquire(config, now)
+    }
+
+    pub(crate) fn release_probe(&self) {
+        self.breaker.lock().unwrap().release()
+    }
+
+    /// Records a request outcome with the circuit breaker, returns the new
+    /// breaker state when it changed.
+    pub(crate) fn record_breaker(
+        &self,
+        success: bool,
+        probe: bool,
+        config: &CircuitBreaker,
+    ) -> Option<BreakerState> {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.record(success, probe, config, Instant::now())
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped.
+    pub(crate) fn start(self: &Arc<Self>) -> InFlightGuard {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        InFlightGuard(self.clone())
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct InFlightGuard(Arc<EndpointStats>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Stats of every endpoint of a service, shared between the registry, the
+/// proxy and the balancers that need them.
+#[derive(Clone, Debug, Default)]
+pub struct StatsTable(Arc<Mutex<HashMap<String, Arc<EndpointStats>>>>);
+
+impl StatsTable {
+    pub fn get(&self, endpoint: &str) -> Arc<EndpointStats> {
+        let mut table = self.0.lock().unwrap();
+        if let Some(stats) = table.get(endpoint) {
+            return stats.clone();
+        }
+        let stats = Arc::new(EndpointStats::default());
+        table.insert(endpoint.to_string(), stats.clone());
+        stats
+    }
+
+    pub(crate) fn remove(&self, endpoint: &str) {
+        self.0.lock().unwrap().remove(endpoint);
+    }
+
+    pub(crate) fn all(&self) -> Vec<Arc<EndpointStats>> {
+        self.0.lock().unwrap().values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests_stats {
+    use super::*;
+
+    #[test]
+    fn test_in_flight_guard() {
+        let table = StatsTable::default();
+        let stats = table.get("a:1");
+        let first = stats.start();
+        let second = table.get("a:1").start();
+        assert_eq!(stats.in_flight(), 2);
+        drop(first);
+        drop(second);
+        assert_eq!(stats.in_flight(), 0);
+        assert_eq!(stats.requests(), 2);
+    }
+
+    #[test]
+    fn test_peak_ewma() {
+        let stats = EndpointStats::default();
+        assert_eq!(stats.latency(), None);
+
+        stats.observe_latency(Duration::from_millis(10));
+        stats.observe_latency(Duration::from_millis(100));
+        let peak = stats.latency().unwrap();
+        assert!(peak > Duration::from_millis(99) && peak <= Duration::from_millis(100));
+
+        stats.observe_latency(Duration::from_millis(10));
+        let recovering = stats.latency().unwrap();
+        assert!(recovering > Duration::from_millis(10) && recovering < peak);
+    }
+}
@@ -0,0 +1,167 @@
+use hyper::HeaderMap;
+use serde::{Deserialize, Serialize};
+
+use crate::endpoint::Endpoint;
+
+/// Sends requests carrying a header (gRPC metadata) to a subset of the
+/// service's endpoints, e.g. QA pinning their traffic to a staging instance
+/// with `x-debug-backend: host:port`.
+///
+/// ```toml
+/// subsets = [
+///     { header = "x-version", select = { kind = "version" } },
+///     { header = "x-tenant", value = "acme", select = { kind = "label", key = "tenant" } },
+///     { header = "x-debug-backend", select = { kind = "address" } },
+/// ]
+/// ```
+///
+/// Endpoints only meant for pinned traffic can be kept out of the rest with
+/// a `TrafficSplit` that gives their version no weight.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubsetRule {
+    pub header: String,
+    /// Value the header must have for the rule to apply, any when unset.
+    #[serde(default)]
+    pub value: Option<String>,
+    pub select: Selector,
+    /// Whether requests go to the whole service when the subset has no
+    /// endpoint available, otherwise they fail.
+    #[serde(default)]
+    pub fallback: bool,
+}
+
+/// Endpoints a `SubsetRule` sends requests to.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Selector {
+    /// Endpoints running `version`, the header's value when unset.
+    Version {
+        #[serde(default)]
+        version: Option<String>,
+    },
+    /// Endpoints whose label `key` is `value`, the header's value when unset.
+    Label {
+        key: String,
+        #[serde(default)]
+        value: Option<String>,
+    },
+    /// The endpoint whose address is the header's value.
+    Address,
+}
+
+impl SubsetRule {
+    /// Value of the rule's header when the rule applies to `headers`.
+    fn matches<'h>(&self, headers: &'h HeaderMap) -> Option<&'h str> {
+        let value = headers.get(self.header.as_str())?.to_str().ok()?;
+        match &self.value {
+            Some(expected) if expected != value => None,
+            _ => Some(value),
+        }
+    }
+
+    fn selects(&self, endpoint: &Endpoint, value: &str) -> bool {
+        match &self.select {
+            Selector::Version { version } => {
+                endpoint.version.as_deref() == Some(version.as_deref().unwrap_or(value))
+            }
+            Selector::Label { key, value: wanted } => {
+                endpoint.labels.get(key).map(String::as_str)
+                    == Some(wanted.as_deref().unwrap_or(value))
+            }
+            Selector::Address => endpoint.address == value,
+        }
+    }
+}
+
+/// Endpoints the first of `rules` applying to `headers` selects out of
+/// `endpoints`, `None` when no rule applies or the rule falls back.
+pub(crate) fn select<'e>(
+    rules: &[SubsetRule],
+    endpoints: &[&'e Endpoint],
+    headers: &HeaderMap,
+) -> Option<Vec<&'e Endpoint>> {
+    let (rule, value) = rules
+        .iter()
+        .find_map(|rule| rule.matches(headers).map(|value| (rule, value)))?;
+    let selected: Vec<&Endpoint> = endpoints
+        .iter()
+        .copied()
+        .filter(|endpoint| rule.selects(endpoint, value))
+        .collect();
+    if selected.is_empty() && rule.fallback {
+        return None;
+    }
+    Some(selected)
+}
+
+#[cfg(test)]
+mod tests_subset {
+    use hyper::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn test_select() {
+        let stable = Endpoint {
+            version: Some("v1".to_string()),
+            ..Endpoint::new("a:1")
+        };
+        let mut staging = Endpoint {
+            version: Some("v2".to_string()),
+            ..Endpoint::new("b:1")
+        };
+        staging
+            .labels
+            .insert("tenant".to_string(), "acme".to_string());
+        let endpoints = [&stable, &staging];
+        let rules = vec![
+            SubsetRule {
+                header: "x-version".to_string(),
+                value: None,
+                select: Selector::Version { version: None },
+                fallback: true,
+            },
+            SubsetRule {
+                header: "x-tenant".to_string(),
+                value: Some("acme".to_string()),
+                select: Selector::Label {
+                    key: "tenant".to_string(),
+                    value: None,
+                },
+                fallback: false,
+            },
+            SubsetRule {
+                header: "x-debug-backend".to_string(),
+                value: None,
+                select: Selector::Address,
+                fallback: false,
+            },
+        ];
+        let addresses = |headers: HeaderMap| {
+            select(&rules, &endpoints, &headers).map(|selected| {
+                selected
+                    .iter()
+                    .map(|endpoint| endpoint.address.as_str())
+                    .collect::<Vec<_>>()
+            })
+        };
+
+        assert_eq!(addresses(headers("x-version", "v2")), Some(vec!["b:1"]));
+        assert_eq!(addresses(headers("x-version", "v3")), None);
+        assert_eq!(addresses(headers("x-tenant", "acme")), Some(vec!["b:1"]));
+        assert_eq!(addresses(headers("x-tenant", "other")), None);
+        assert_eq!(
+            addresses(headers("x-debug-backend", "a:1")),
+            Some(vec!["a:1"])
+        );
+        assert_eq!(addresses(headers("x-debug-backend", "c:1")), Some(vec![]));
+        assert_eq!(addresses(HeaderMap::new()), None);
+    }
+}
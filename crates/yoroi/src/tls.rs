@@ -0,0 +1,749 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    fs::File,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        verify_server_cert_signed_by_trust_anchor,
+    },
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime},
+    server::{ClientHello, ParsedCertificate, ResolvesServerCert},
+    sign::CertifiedKey,
+    ClientConfig, DigitallySignedStruct, RootCertStore, ServerConfig, SignatureScheme,
+};
+use serde::Deserialize;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+    time::interval,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{error, info};
+
+use crate::{
+    config::{self, ConfigError},
+    protocol::Protocol,
+    registry::ServiceRegistry,
+};
+
+/// How often certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Time a client has to complete the TLS handshake, so a stalled one
+/// doesn't hold on to its connection.
+pub(crate) const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// TLS on the gateway listener, clients pick a certificate by SNI.
+///
+/// ```toml
+/// [tls]
+/// certificates = [
+///     { cert = "certs/api.pem", key = "certs/api.key", server_names = ["api.example.com"] },
+///     { cert = "certs/wildcard.pem", key = "certs/wildcard.key", server_names = ["*.example.com"] },
+/// ]
+/// ```
+///
+/// The files are reloaded when they change, so renewed certificates are
+/// served without a restart.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// The first certificate also serves clients whose SNI no certificate
+    /// matches, or that send none.
+    pub certificates: Vec<CertificateConfig>,
+}
+
+/// PEM certificate chain and private key served for some server names.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateConfig {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// Names the certificate is served for, `*.example.com` matches a
+    /// single label.
+    #[serde(default)]
+    pub server_names: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+struct Loaded {
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+    default: Option<Arc<CertifiedKey>>,
+    modified: Vec<Option<SystemTime>>,
+}
+
+/// Certificates of the listener, picked by the SNI of each handshake.
+#[derive(Debug)]
+pub struct CertResolver {
+    config: TlsConfig,
+    provider: Arc<CryptoProvider>,
+    loaded: RwLock<Loaded>,
+}
+
+impl CertResolver {
+    /// Loads every certificate of `config`.
+    pub fn new(config: TlsConfig) -> Result<Self, ConfigError> {
+        let resolver = CertResolver {
+            config,
+            provider: Arc::new(ring::default_provider()),
+            loaded: RwLock::default(),
+        };
+        let loaded = resolver.load()?;
+        *resolver.loaded.write().unwrap() = loaded;
+        Ok(resolver)
+    }
+
+    /// Reloads the certificates when one of their files changed. On error
+    /// the certificates loaded before are kept.
+    pub(crate) fn reload(&self) -> Result<bool, ConfigError> {
+        if self.modified() == self.loaded.read().unwrap().modified {
+            return Ok(false);
+        }
+        let loaded = self.load()?;
+        *self.loaded.write().unwrap() = loaded;
+        Ok(true)
+    }
+
+    /// Acceptor of TLS connections negotiating HTTP/2 or HTTP/1.1 with
+    /// ALPN, HTTP/2 preferred.
+    pub(crate) fn acceptor(self: &Arc<Self>) -> TlsAcceptor {
+        let mut config = ServerConfig::builder_with_provider(self.provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("the ring provider supports the default versions")
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone());
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.config
+            .certificates
+            .iter()
+            .flat_map(|cert| [&cert.cert, &cert.key])
+            .map(|path| config::modified_at(path))
+            .collect()
+    }
+
+    fn load(&self) -> Result<Loaded, ConfigError> {
+        let mut loaded = Loaded {
+            modified: self.modified(),
+            ..Default::default()
+        };
+        for cert in &self.config.certificates {
+            let key = Arc::new(self.certified_key(cert)?);
+            loaded.default.get_or_insert_with(|| key.clone());
+            for name in &cert.server_names {
+                loaded
+                    .by_name
+                    .entry(name.to_ascii_lowercase())
+                    .or_insert_with(|| key.clone());
+            }
+        }
+        Ok(loaded)
+    }
+
+    fn certified_key(&self, config: &CertificateConfig) -> Result<CertifiedKey, ConfigError> {
+        let certs = certs(&config.cert)?;
+        let key = self
+            .provider
+            .key_provider
+            .load_private_key(private_key(&config.key)?)
+            .map_err(|err| invalid(&config.key, &err.to_string()))?;
+        let certified = CertifiedKey::new(certs, key);
+        certified
+            .keys_match()
+            .map_err(|err| invalid(&config.cert, &err.to_string()))?;
+        Ok(certified)
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let loaded = self.loaded.read().unwrap();
+        let name = match hello.server_name() {
+            Some(name) => name.to_ascii_lowercase(),
+            None => return loaded.default.clone(),
+        };
+        let wildcard = name
+            .split_once('.')
+            .map(|(_, parent)| format!("*.{}", parent));
+        loaded
+            .by_name
+            .get(&name)
+            .or_else(|| wildcard.and_then(|wildcard| loaded.by_name.get(&wildcard)))
+            .or(loaded.default.as_ref())
+            .cloned()
+    }
+}
+
+/// TLS to the endpoints of a service, e.g. mTLS inside the mesh. The pool
+/// and the health checks connect with it alike.
+///
+/// ```toml
+/// tls = { ca = "certs/mesh-ca.pem", cert = "certs/gateway.pem", key = "certs/gateway.key" }
+/// ```
+///
+/// Like the listener's, the files are reloaded when they change.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct UpstreamTlsConfig {
+    /// PEM bundle of the CAs endpoint certificates are verified against, the
+    /// system's roots when unset.
+    pub ca: Option<PathBuf>,
+    /// Client certificate chain and key presented to endpoints that ask for
+    /// one, both or neither are set.
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+    /// Name sent as SNI and verified, the endpoint's host when unset.
+    pub server_name: Option<String>,
+    /// Whether the endpoint's certificate must be valid for the server name,
+    /// its chain is verified either way.
+    pub verify_hostname: bool,
+}
+
+impl Default for UpstreamTlsConfig {
+    fn default() -> Self {
+        UpstreamTlsConfig {
+            ca: None,
+            cert: None,
+            key: None,
+            server_name: None,
+            verify_hostname: true,
+        }
+    }
+}
+
+impl UpstreamTlsConfig {
+    /// Checks the config without loading its files, they only have to
+    /// exist. `build` reads them.
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        if self.cert.is_some() != self.key.is_some() {
+            return Err(ConfigError::Invalid(
+                "cert and key must be set together".to_string(),
+            ));
+        }
+        if let Some(path) = self.files().find(|path| !path.is_file()) {
+            return Err(invalid(path, "no such file"));
+        }
+        if let Some(name) = &self.server_name {
+            ServerName::try_from(name.as_str())
+                .map_err(|err| ConfigError::Invalid(format!("server name `{}`: {}", name, err)))?;
+        }
+        Ok(())
+    }
+
+    fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        self.ca.iter().chain(&self.cert).chain(&self.key)
+    }
+
+    fn modified(&self) -> Vec<Option<SystemTime>> {
+        self.files().map(|path| config::modified_at(path)).collect()
+    }
+
+    /// Loads the files of the config into a client TLS setup.
+    pub(crate) fn build(&self) -> Result<ClientTls, ConfigError> {
+        // taken first, a file changed while it is read is loaded again
+        let modified = self.modified();
+        let provider = Arc::new(ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        match &self.ca {
+            Some(ca) => {
+                for cert in certs(ca)? {
+                    roots
+                        .add(cert)
+                        .map_err(|err| invalid(ca, &err.to_string()))?;
+                }
+            }
+            None => {
+                let native = rustls_native_certs::load_native_certs();
+                roots.add_parsable_certificates(native.certs);
+            }
+        }
+        let server_name = self
+            .server_name
+            .as_ref()
+            .map(|name| {
+                ServerName::try_from(name.clone())
+                    .map_err(|err| ConfigError::Invalid(format!("server name `{}`: {}", name, err)))
+            })
+            .transpose()?;
+
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("the ring provider supports the default versions");
+        let builder = if self.verify_hostname {
+            builder.with_root_certificates(roots)
+        } else {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(ChainVerifier {
+                    roots: Arc::new(roots),
+                    provider: provider.clone(),
+                }))
+        };
+        let mut config = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => builder
+                .with_client_auth_cert(certs(cert)?, private_key(key)?)
+                .map_err(|err| invalid(cert, &err.to_string()))?,
+            (None, None) => builder.with_no_client_auth(),
+            _ => {
+                return Err(ConfigError::Invalid(
+                    "cert and key must be set together".to_string(),
+                ))
+            }
+        };
+        // ALPN has to agree with the protocol the connection is then used for
+        let mut http1 = config.clone();
+        http1.alpn_protocols = vec![b"http/1.1".to_vec()];
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        Ok(ClientTls {
+            http2: TlsConnector::from(Arc::new(config)),
+            http1: TlsConnector::from(Arc::new(http1)),
+            server_name,
+            config: self.clone(),
+            modified,
+        })
+    }
+}
+
+/// Client side of the TLS to a service's endpoints, built from its
+/// `UpstreamTlsConfig`.
+#[derive(Clone)]
+pub(crate) struct ClientTls {
+    http2: TlsConnector,
+    http1: TlsConnector,
+    server_name: Option<ServerName<'static>>,
+    config: UpstreamTlsConfig,
+    modified: Vec<Option<SystemTime>>,
+}
+
+impl ClientTls {
+    /// The TLS built again from its files when they changed since, e.g. a
+    /// rotated client certificate.
+    pub(crate) fn reload(&self) -> Result<Option<ClientTls>, ConfigError> {
+        if self.config.modified() == self.modified {
+            return Ok(None);
+        }
+        self.config.build().map(Some)
+    }
+}
+
+impl fmt::Debug for ClientTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientTls")
+            .field("server_name", &self.server_name)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Connection to an endpoint, plaintext or TLS.
+pub(crate) trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// Opens a connection to `endpoint` for `protocol`, over TLS with `tls`.
+pub(crate) async fn connect(
+    endpoint: &str,
+    tls: Option<&ClientTls>,
+    protocol: Protocol,
+) -> Result<Box<dyn Io>, Box<dyn Error + Send + Sync>> {
+    let stream = TcpStream::connect(endpoint).await?;
+    stream.set_nodelay(true)?;
+    let tls = match tls {
+        Some(tls) => tls,
+        None => return Ok(Box::new(stream)),
+    };
+    let server_name = match &tls.server_name {
+        Some(name) => name.clone(),
+        None => {
+            let host = endpoint
+                .rsplit_once(':')
+                .map_or(endpoint, |(host, _)| host)
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            ServerName::try_from(host.to_string())?
+        }
+    };
+    let connector = match protocol {
+        Protocol::Http2 => &tls.http2,
+        Protocol::Http1 => &tls.http1,
+    };
+    Ok(Box::new(connector.connect(server_name, stream).await?))
+}
+
+/// Verifies the chain of an endpoint's certificate but not the name it is
+/// valid for, when `verify_hostname` is off.
+#[derive(Debug)]
+struct ChainVerifier {
+    roots: Arc<RootCertStore>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for ChainVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let cert = ParsedCertificate::try_from(end_entity)?;
+        verify_server_cert_signed_by_trust_anchor(
+            &cert,
+            &self.roots,
+            intermediates,
+            now,
+            self.provider.signature_verification_algorithms.all,
+        )?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+fn certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, ConfigError> {
+    let certs = rustls_pemfile::certs(&mut reader(path)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificate found"));
+    }
+    Ok(certs)
+}
+
+fn private_key(path: &Path) -> Result<PrivateKeyDer<'static>, ConfigError> {
+    rustls_pemfile::private_key(&mut reader(path)?)
+        .map_err(|err| ConfigError::Io(path.to_path_buf(), err))?
+        .ok_or_else(|| invalid(path, "no private key found"))
+}
+
+fn reader(path: &Path) -> Result<BufReader<File>, ConfigError> {
+    let file = File::open(path).map_err(|err| ConfigError::Io(path.to_path_buf(), err))?;
+    Ok(BufReader::new(file))
+}
+
+fn invalid(path: &Path, msg: &str) -> ConfigError {
+    ConfigError::Invalid(format!("{}: {}", path.display(), msg))
+}
+
+/// Reloads the certificates of `resolver` as their files change, until the
+/// task is aborted.
+pub(crate) async fn run_reloader(resolver: Arc<CertResolver>) {
+    let mut ticker = interval(RELOAD_INTERVAL);
+    loop {
+        ticker.tick().await;
+        match resolver.reload() {
+            Ok(true) => info!("tls: certificates reloaded"),
+            Ok(false) => {}
+            Err(err) => error!("tls: keeping the current certificates: {}", err),
+        }
+    }
+}
+
+/// Reloads the upstream TLS of the services of `registry` as their files
+/// change, until the task is aborted.
+pub(crate) async fn run_upstream_reloader(registry: ServiceRegistry) {
+    let mut ticker = interval(RELOAD_INTERVAL);
+    loop {
+        ticker.tick().await;
+        registry.reload_upstream_tls();
+    }
+}
+
+#[cfg(test)]
+mod tests_tls {
+    use std::fs;
+
+    use http_body_util::{BodyExt, Empty, Full};
+    use hyper::{body::Bytes, server::conn::http2, service::service_fn, Request, Response};
+    use hyper_util::rt::TokioIo;
+
+    use super::*;
+    use crate::{pool::ConnectionPool, server::TokioExecutor};
+
+    /// Writes a self-signed certificate for `name` into `dir`, returns its
+    /// paths and DER.
+    fn self_signed(dir: &Path, name: &str) -> (PathBuf, PathBuf, Vec<u8>) {
+        let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert = dir.join(format!("{}.pem", name));
+        let key = dir.join(format!("{}.key", name));
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+        (cert, key, generated.cert.der().to_vec())
+    }
+
+    /// Certificate the resolver serves for `name`, with ALPN `h2` agreed.
+    async fn handshake(resolver: &Arc<CertResolver>, roots: &[&[u8]], name: &str) -> Vec<u8> {
+        let mut store = RootCertStore::empty();
+        for root in roots {
+            store.add(root.to_vec().into()).unwrap();
+        }
+        let mut config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let acceptor = resolver.acceptor();
+        let accepted = tokio::spawn(async move { acceptor.accept(server).await.unwrap() });
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        let stream = TlsConnector::from(Arc::new(config))
+            .connect(name, client)
+            .await
+            .unwrap();
+        accepted.await.unwrap();
+        let (_, conn) = stream.get_ref();
+        assert_eq!(conn.alpn_protocol(), Some(&b"h2"[..]));
+        conn.peer_certificates().unwrap()[0].to_vec()
+    }
+
+    #[tokio::test]
+    async fn test_sni_and_reload() {
+        let dir = std::env::temp_dir().join(format!("yoroi-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (api_cert, api_key, api) = self_signed(&dir, "api.example.com");
+        let (kyc_cert, kyc_key, kyc) = self_signed(&dir, "kyc.internal");
+        let resolver = Arc::new(
+            CertResolver::new(TlsConfig {
+                certificates: vec![
+                    CertificateConfig {
+                        cert: api_cert.clone(),
+                        key: api_key.clone(),
+                        server_names: vec!["*.example.com".to_string()],
+                    },
+                    CertificateConfig {
+                        cert: kyc_cert,
+                        key: kyc_key,
+                        server_names: vec!["kyc.internal".to_string()],
+                    },
+                ],
+            })
+            .unwrap(),
+        );
+        let roots = [api.as_slice(), kyc.as_slice()];
+        assert_eq!(handshake(&resolver, &roots, "kyc.internal").await, kyc);
+        assert_eq!(handshake(&resolver, &roots, "api.example.com").await, api);
+
+        assert!(!resolver.reload().unwrap());
+        // a renewed certificate is served once reloaded
+        let generated =
+            rcgen::generate_simple_self_signed(vec!["api.example.com".to_string()]).unwrap();
+        rewrite(&api_cert, generated.cert.pem());
+        rewrite(&api_key, generated.key_pair.serialize_pem());
+        assert!(resolver.reload().unwrap());
+        let renewed = generated.cert.der().to_vec();
+        let roots = [renewed.as_slice(), kyc.as_slice()];
+        assert_eq!(
+            handshake(&resolver, &roots, "api.example.com").await,
+            renewed
+        );
+
+        // a broken file keeps the certificates loaded before
+        rewrite(&api_key, "not a key");
+        assert!(resolver.reload().is_err());
+        assert_eq!(
+            handshake(&resolver, &roots, "api.example.com").await,
+            renewed
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Writes `contents` to `path` and moves its modification time on, a
+    /// reload sees the change however coarse the file system's times are.
+    fn rewrite(path: &Path, contents: impl AsRef<[u8]>) {
+        let before = fs::metadata(path).unwrap().modified().unwrap();
+        fs::write(path, contents).unwrap();
+        fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(before + Duration::from_secs(1))
+            .unwrap();
+    }
+
+    /// Writes a certificate for `names` signed by `ca` into `dir`.
+    fn signed(
+        dir: &Path,
+        file: &str,
+        names: &[&str],
+        ca: &(rcgen::Certificate, rcgen::KeyPair),
+    ) -> (PathBuf, PathBuf) {
+        let key_pair = rcgen::KeyPair::generate().unwrap();
+        let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+        let cert = rcgen::CertificateParams::new(names)
+            .unwrap()
+            .signed_by(&key_pair, &ca.0, &ca.1)
+            .unwrap();
+        let (cert_path, key_path) = (
+            dir.join(format!("{}.pem", file)),
+            dir.join(format!("{}.key", file)),
+        );
+        fs::write(&cert_path, cert.pem()).unwrap();
+        fs::write(&key_path, key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    /// HTTP/2 over TLS server that requires a client certificate from `ca`.
+    async fn serve_mtls(cert: &Path, key: &Path, ca: &Path) -> String {
+        let provider = Arc::new(ring::default_provider());
+        let mut roots = RootCertStore::empty();
+        roots.add_parsable_certificates(certs(ca).unwrap());
+        let verifier = rustls::server::WebPkiClientVerifier::builder_with_provider(
+            Arc::new(roots),
+            provider.clone(),
+        )
+        .build()
+        .unwrap();
+        let mut config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_client_cert_verifier(verifier)
+            .with_single_cert(certs(cert).unwrap(), private_key(key).unwrap())
+            .unwrap();
+        config.alpn_protocols = vec![b"h2".to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let acceptor = acceptor.clone();
+                tokio::spawn(async move {
+                    let Ok(stream) = acceptor.accept(stream).await else {
+                        return;
+                    };
+                    let service = service_fn(|_| async {
+                        Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::from("ok"))))
+                    });
+                    let _ = http2::Builder::new(TokioExecutor)
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        addr.to_string()
+    }
+
+    async fn call(endpoint: &str, config: &UpstreamTlsConfig) -> bool {
+        let tls = Arc::new(config.build().unwrap());
+        let req = Request::new(Empty::new().map_err(|never| match never {}).boxed());
+        match ConnectionPool::default()
+            .get(endpoint, Some(&tls), Protocol::Http2)
+            .await
+        {
+            Ok(mut sender) => sender.send_request(req).await.is_ok(),
+            Err(_) => false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_upstream_mtls() {
+        let dir = std::env::temp_dir().join(format!("yoroi-mtls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let ca_key = rcgen::KeyPair::generate().unwrap();
+        let mut params = rcgen::CertificateParams::new(vec![]).unwrap();
+        params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = (params.self_signed(&ca_key).unwrap(), ca_key);
+        let ca_path = dir.join("ca.pem");
+        fs::write(&ca_path, ca.0.pem()).unwrap();
+        let (server_cert, server_key) = signed(&dir, "kyc", &["kyc.internal"], &ca);
+        let (client_cert, client_key) = signed(&dir, "gateway", &["gateway"], &ca);
+        let endpoint = serve_mtls(&server_cert, &server_key, &ca_path).await;
+
+        let config = UpstreamTlsConfig {
+            ca: Some(ca_path),
+            cert: Some(client_cert),
+            key: Some(client_key),
+            server_name: Some("kyc.internal".to_string()),
+            verify_hostname: true,
+        };
+        assert!(call(&endpoint, &config).await);
+        // the endpoint's certificate is not valid for its IP
+        let by_address = UpstreamTlsConfig {
+            server_name: None,
+            ..config.clone()
+        };
+        assert!(!call(&endpoint, &by_address).await);
+        let unverified = UpstreamTlsConfig {
+            verify_hostname: false,
+            ..by_address
+        };
+        assert!(call(&endpoint, &unverified).await);
+
+        // only missing files fail validation, their contents are read once
+        // the config is applied
+        assert!(config.validate().is_ok());
+        let missing = UpstreamTlsConfig {
+            ca: Some(dir.join("missing.pem")),
+            ..config.clone()
+        };
+        assert!(missing.validate().is_err());
+        // a rotated client certificate is picked up by a reload
+        let tls = config.build().unwrap();
+        assert!(tls.reload().unwrap().is_none());
+        let (rotated_cert, rotated_key) = signed(&dir, "rotated", &["gateway"], &ca);
+        let client_cert = config.cert.as_ref().unwrap();
+        rewrite(client_cert, fs::read(rotated_cert).unwrap());
+        rewrite(config.key.as_ref().unwrap(), fs::read(rotated_key).unwrap());
+        let reloaded = Arc::new(tls.reload().unwrap().unwrap());
+        assert!(reloaded.reload().unwrap().is_none());
+        let req = Request::new(Empty::new().map_err(|never| match never {}).boxed());
+        let mut sender = ConnectionPool::default()
+            .get(&endpoint, Some(&reloaded), Protocol::Http2)
+            .await
+            .unwrap();
+        assert!(sender.send_request(req).await.is_ok());
+
+        // the endpoint turns away a gateway without a client certificate
+        let anonymous = UpstreamTlsConfig {
+            cert: None,
+            key: None,
+            ..config
+        };
+        assert!(!call(&endpoint, &anonymous).await);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
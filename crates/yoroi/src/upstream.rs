@@ -0,0 +1,101 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use hyper::{HeaderMap, StatusCode};
+use tracing::warn;
+
+use crate::{
+    breaker::CircuitBreaker,
+    outlier::{self, OutlierDetection},
+    protocol::Protocol,
+    stats::{EndpointStats, StatsTable},
+    tls::ClientTls,
+};
+
+/// gRPC status codes that point at a broken endpoint rather than a bad call.
+const GRPC_INTERNAL: &str = "13";
+pub(crate) const GRPC_UNAVAILABLE: &str = "14";
+
+/// Endpoint resolved for a request, the proxy reports the request's outcome
+/// back through it.
+pub(crate) struct Upstream {
+    pub(crate) id: String,
+    pub(crate) endpoint: String,
+    pub(crate) stats: Arc<EndpointStats>,
+    pub(crate) service_stats: StatsTable,
+    /// Number of endpoints the service has, healthy or not.
+    pub(crate) endpoints: usize,
+    pub(crate) outlier: Option<OutlierDetection>,
+    pub(crate) breaker: Option<CircuitBreaker>,
+    /// Whether the request holds a half-open probe slot of the breaker.
+    pub(crate) probe: bool,
+    pub(crate) reported: AtomicBool,
+    /// TLS the endpoint is connected to with.
+    pub(crate) tls: Option<Arc<ClientTls>>,
+    /// Protocol the endpoint speaks.
+    pub(crate) protocol: Protocol,
+}
+
+impl Upstream {
+    /// Reports the request outcome, only the first report counts.
+    pub(crate) fn report(&self, success: bool) {
+        if self.reported.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        if let Some(config) = &self.breaker {
+            if let Some(state) = self.stats.record_breaker(success, self.probe, config) {
+                warn!(
+                    "{}: circuit breaker of {} is {:?}",
+                    self.id, self.endpoint, state
+                );
+            }
+        }
+        let detection = match &self.outlier {
+            Some(detection) => detection,
+            None => return,
+        };
+        if success {
+            self.stats.record_success(detection);
+            return;
+        }
+        let ejected = self.stats.record_failure(detection, || {
+            outlier::can_eject(detection, &self.service_stats, self.endpoints, &self.stats)
+        });
+        if let Some(ejection) = ejected {
+            warn!(
+                "{}: ejecting endpoint {} for {:?}",
+                self.id, self.endpoint, ejection
+            );
+        }
+    }
+}
+
+impl Drop for Upstream {
+    fn drop(&mut self) {
+        // a cancelled probe has no outcome, let another request probe instead
+        if self.probe && !self.reported.load(Ordering::Relaxed) {
+            self.stats.release_probe();
+        }
+    }
+}
+
+/// Outcome carried by response headers, `None` when the gRPC status only
+/// comes with the trailers.
+pub(crate) fn response_outcome(status: StatusCode, headers: &HeaderMap) -> Option<bool> {
+    if status.is_server_error() {
+        return Some(false);
+    }
+    if headers.contains_key("grpc-status") {
+        return Some(trailers_outcome(headers));
+    }
+    None
+}
+
+pub(crate) fn trailers_outcome(trailers: &HeaderMap) -> bool {
+    !matches!(
+        trailers.get("grpc-status").and_then(|s| s.to_str().ok()),
+        Some(GRPC_INTERNAL) | Some(GRPC_UNAVAILABLE)
+    )
+}
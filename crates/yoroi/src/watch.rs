@@ -0,0 +1,159 @@
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+use futures::Stream;
+use serde::Serialize;
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tracing::warn;
+
+use crate::backend::ServiceRecord;
+
+/// Events a subscriber may fall behind by before it is dropped.
+pub const WATCH_BUFFER: usize = 1024;
+
+/// Membership change of the service registry. A service comes with the
+/// `EndpointAdded` events of its endpoints after `ServiceAdded`, and with
+/// their `EndpointRemoved` events before `ServiceRemoved`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum RegistryEvent {
+    ServiceAdded {
+        id: String,
+        name: String,
+    },
+    EndpointAdded {
+        id: String,
+        endpoint: String,
+    },
+    /// Metadata of the endpoint changed, e.g. its weight or admin state.
+    EndpointUpdated {
+        id: String,
+        endpoint: String,
+    },
+    EndpointRemoved {
+        id: String,
+        endpoint: String,
+    },
+    ServiceRemoved {
+        id: String,
+    },
+}
+
+impl RegistryEvent {
+    /// Events that turn `before` into `after`, both being the same service.
+    pub(crate) fn diff(
+        before: Option<&ServiceRecord>,
+        after: Option<&ServiceRecord>,
+    ) -> Vec<RegistryEvent> {
+        let mut events = vec![];
+        let id = match before.or(after) {
+            Some(record) => record.id.clone(),
+            None => return events,
+        };
+        if let (None, Some(after)) = (before, after) {
+            events.push(RegistryEvent::ServiceAdded {
+                id: id.clone(),
+                name: after.name.clone(),
+            });
+        }
+        let empty = Default::default();
+        let old = before.map_or(&empty, |record| &record.endpoints);
+        let new = after.map_or(&empty, |record| &record.endpoints);
+        for endpoint in old.keys().filter(|endpoint| !new.contains_key(*endpoint)) {
+            events.push(RegistryEvent::EndpointRemoved {
+                id: id.clone(),
+                endpoint: endpoint.clone(),
+            });
+        }
+        for (endpoint, metadata) in new {
+            let event = match old.get(endpoint) {
+                None => RegistryEvent::EndpointAdded {
+                    id: id.clone(),
+                    endpoint: endpoint.clone(),
+                },
+                Some(previous) if previous != metadata => RegistryEvent::EndpointUpdated {
+                    id: id.clone(),
+                    endpoint: endpoint.clone(),
+                },
+                Some(_) => continue,
+            };
+            events.push(event);
+        }
+        if after.is_none() {
+            events.push(RegistryEvent::ServiceRemoved { id });
+        }
+        events
+    }
+}
+
+/// Subscription to the registry, see `ServiceRegistry::watch`.
+pub struct Watch {
+    snapshot: Vec<ServiceRecord>,
+    events: Receiver<RegistryEvent>,
+}
+
+impl Watch {
+    /// Every service as of the subscription, sorted by id. The events that
+    /// follow are the changes made after it.
+    pub fn snapshot(&self) -> &[ServiceRecord] {
+        &self.snapshot
+    }
+
+    /// Next change, `None` once the registry is gone or once the watch fell
+    /// more than `WATCH_BUFFER` events behind. Watch again to catch up from
+    /// a new snapshot.
+    pub async fn next(&mut self) -> Option<RegistryEvent> {
+        self.events.recv().await
+    }
+}
+
+impl Stream for Watch {
+    type Item = RegistryEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+/// Senders of every live subscription.
+#[derive(Clone, Default)]
+pub(crate) struct Watchers(Arc<Mutex<Vec<Sender<RegistryEvent>>>>);
+
+impl Watchers {
+    /// Must be called under the registry lock `snapshot` was taken with, so
+    /// no change is missed or seen twice.
+    pub(crate) fn subscribe(&self, mut snapshot: Vec<ServiceRecord>) -> Watch {
+        let (tx, rx) = mpsc::channel(WATCH_BUFFER);
+        self.0.lock().unwrap().push(tx);
+        snapshot.sort_by(|a, b| a.id.cmp(&b.id));
+        Watch {
+            snapshot,
+            events: rx,
+        }
+    }
+
+    /// Sends `events` to every subscriber, dropping the ones that went away
+    /// or fell behind.
+    pub(crate) fn notify(&self, events: Vec<RegistryEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        let mut watchers = self.0.lock().unwrap();
+        watchers.retain(|tx| {
+            events.iter().all(|event| match tx.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    warn!(
+                        "watch: dropping a subscriber {} events behind",
+                        WATCH_BUFFER
+                    );
+                    false
+                }
+                Err(TrySendError::Closed(_)) => false,
+            })
+        });
+    }
+}
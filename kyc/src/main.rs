@@ -1,13 +1,33 @@
 mod service;
 mod transport;
 
-use std::net::SocketAddr;
+use std::{net::SocketAddr, time::Duration};
 use transport::grpc::serve;
+use yoroi::registration::Registration;
 
 #[tokio::main]
 async fn main() {
     let addr = "[::1]:50051".parse::<SocketAddr>().unwrap();
-    match serve(addr).await.err() {
+    // join the gateway's rotation when it exposes the registration API
+    let registration = match std::env::var("YOROI_REGISTRY") {
+        Ok(gateway) => Some(
+            Registration::register(
+                gateway,
+                "kyc.Kyc",
+                "kyc",
+                &addr.to_string(),
+                Duration::from_secs(10),
+            )
+            .await
+            .unwrap_or_else(|err| panic!("{}", err)),
+        ),
+        Err(_) => None,
+    };
+    let result = serve(addr).await;
+    if let Some(registration) = registration {
+        let _ = registration.deregister().await;
+    }
+    match result.err() {
         Some(err) => panic!("{}", err),
         _ => (),
     };
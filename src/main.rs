@@ -3,12 +3,13 @@ use yoroi;
 
 #[tokio::main]
 async fn main() {
-    let mut yr = yoroi::Yoroi::new("[::1]:8080".to_string());
-    yr.registry().register_service(
-        "kyc.Kyc".to_string(),
-        "kyc".to_string(),
-        vec!["localhost:50051".to_string()],
-    );
+    let path = std::env::args()
+        .nth(1)
+        .unwrap_or_else(|| "yoroi.toml".to_string());
+    let yr = match yoroi::Yoroi::from_config_file(path) {
+        Ok(yr) => yr,
+        Err(err) => panic!("{}", err),
+    };
     let err = yr.start_daemon().await.err();
     if let Some(err) = err {
         panic!("{}", err);